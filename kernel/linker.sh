@@ -0,0 +1,53 @@
+#!/bin/bash
+# Links the kernel with rust-lld, then fills the `.symbols` section reserved by `kernel::backtrace`
+# with the address and demangled name of every function, one per line.
+# rustc runs the linker with rust-lld and the other LLVM tools on the PATH.
+set -eu
+set -o pipefail
+
+for tool in rust-lld llvm-objdump llvm-nm llvm-objcopy; do
+    if ! command -v "$tool" >/dev/null; then
+        echo "linker.sh: $tool was not found, install the llvm-tools-preview component" >&2
+        exit 1
+    fi
+done
+
+rust-lld "$@"
+
+output=
+while [ $# -gt 0 ]; do
+    if [ "$1" = "-o" ]; then
+        output=$2
+        shift
+    fi
+    shift
+done
+
+size=$(llvm-objdump --section-headers "$output" | awk '$2 == ".symbols" { print $3 }')
+if [ -z "$size" ]; then
+    exit 0
+fi
+size=$((0x$size))
+
+table="$output.symbols"
+llvm-nm --defined-only --numeric-sort --demangle "$output" |
+    sed -n 's/^0*\([0-9a-f][0-9a-f]*\) [tT] /\1 /p' |
+    sed -e 's/::h[0-9a-f]\{16\}$//' -e 's/\.\./::/g' -e 's/ _\$/ $/' -e 's/::_\$/::$/g' \
+        -e 's/\$LT\$/</g' -e 's/\$GT\$/>/g' -e 's/\$RF\$/\&/g' -e 's/\$BP\$/*/g' \
+        -e 's/\$C\$/,/g' -e 's/\$SP\$/@/g' -e 's/\$LP\$/(/g' -e 's/\$RP\$/)/g' \
+        -e 's/\$u20\$/ /g' -e "s/\\\$u27\\\$/'/g" \
+        -e 's/\$u5b\$/[/g' -e 's/\$u5d\$/]/g' -e 's/\$u7b\$/{/g' -e 's/\$u7d\$/}/g' \
+        -e 's/\$u7e\$/~/g' -e 's/\$u3b\$/;/g' -e 's/\$u2b\$/+/g' -e 's/\$u22\$/"/g' -e 's/\$u3d\$/=/g' \
+        >"$table"
+
+needed=$(wc -c <"$table")
+if [ "$needed" -ge "$size" ]; then
+    echo "linker.sh: the symbol table needs $needed bytes, but only $size are reserved" >&2
+    rm "$table"
+    exit 1
+fi
+# Pad the table with zeros to the size of the section.
+cp "$table" "$table.padded"
+truncate -s "$size" "$table.padded"
+llvm-objcopy --update-section .symbols="$table.padded" "$output"
+rm "$table" "$table.padded"
@@ -0,0 +1,12 @@
+#!/bin/sh
+# Rebuilds the user programs embedded in the kernel, see `kernel::user::programs`.
+# Requires GNU binutils. Programs are linked into the user region of the address space.
+set -e
+cd "$(dirname "$0")"
+for src in *.s; do
+    name="${src%.s}"
+    as --64 -o "$name.o" "$src"
+    ld -static -nostdlib -s --build-id=none -z noexecstack -z max-page-size=0x1000 \
+        -Ttext-segment=0x100000400000 -e _start -o "$name.elf" "$name.o"
+    rm "$name.o"
+done
@@ -0,0 +1,48 @@
+# Prints a greeting followed by its arguments, one per line.
+    .intel_syntax noprefix
+    .text
+    .global _start
+_start:
+    mov rbx, rsp
+    lea rsi, [rip + greeting]
+    mov edx, greeting_end - greeting
+    call write
+    # Skip argv[0], the name of the program.
+    mov r12, 1
+next_arg:
+    cmp r12, [rbx]
+    jae done
+    mov rsi, [rbx + r12 * 8 + 8]
+    # Find the length of the argument.
+    xor edx, edx
+strlen:
+    cmp byte ptr [rsi + rdx], 0
+    je print_arg
+    inc rdx
+    jmp strlen
+print_arg:
+    call write
+    lea rsi, [rip + newline]
+    mov edx, 1
+    call write
+    inc r12
+    jmp next_arg
+done:
+    mov eax, 2
+    xor edi, edi
+    syscall
+    ud2
+
+# write(1, rsi, rdx)
+write:
+    mov eax, 0
+    mov edi, 1
+    syscall
+    ret
+
+    .section .rodata
+greeting:
+    .ascii "Hello from an ELF program!\n"
+greeting_end:
+newline:
+    .ascii "\n"
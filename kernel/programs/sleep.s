@@ -0,0 +1,34 @@
+# Sleeps for the number of seconds given as its argument, or forever without one, then exits 0.
+    .intel_syntax noprefix
+    .text
+    .global _start
+_start:
+    mov r12, -1
+    cmp qword ptr [rsp], 2
+    jb sleep
+    # Parse the decimal number in argv[1].
+    mov rsi, [rsp + 16]
+    xor r12d, r12d
+parse:
+    movzx eax, byte ptr [rsi]
+    sub eax, '0'
+    cmp eax, 9
+    ja sleep
+    imul r12, r12, 10
+    add r12, rax
+    inc rsi
+    jmp parse
+sleep:
+    test r12, r12
+    jz done
+    # sleep(1000)
+    mov eax, 3
+    mov edi, 1000
+    syscall
+    dec r12
+    jmp sleep
+done:
+    mov eax, 2
+    xor edi, edi
+    syscall
+    ud2
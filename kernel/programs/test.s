@@ -0,0 +1,86 @@
+# Exercises the ELF loader. The first character of argv[1] selects what the program exits with:
+#   a: argc
+#   e: the number of environment variables
+#   p: the AT_PAGESZ auxiliary vector entry
+#   d: the sum of a .data value initialized to 42 and a .bss value, after incrementing both
+# Any other argument exits with -1.
+    .intel_syntax noprefix
+    .text
+    .global _start
+_start:
+    mov rbx, rsp
+    mov rdi, [rbx]
+    cmp rdi, 2
+    jb exit
+    mov rsi, [rbx + 16]
+    movzx eax, byte ptr [rsi]
+    cmp al, 'a'
+    je argc
+    cmp al, 'e'
+    je envc
+    cmp al, 'p'
+    je pagesz
+    cmp al, 'd'
+    je data
+    mov rdi, -1
+    jmp exit
+
+argc:
+    mov rdi, [rbx]
+    jmp exit
+
+envc:
+    call envp
+    xor edi, edi
+envc_loop:
+    cmp qword ptr [rsi + rdi * 8], 0
+    je exit
+    inc rdi
+    jmp envc_loop
+
+pagesz:
+    call envp
+    # The auxiliary vector follows the null pointer ending envp.
+skip_env:
+    add rsi, 8
+    cmp qword ptr [rsi - 8], 0
+    jne skip_env
+auxv_loop:
+    mov rax, [rsi]
+    test rax, rax
+    jz not_found
+    cmp rax, 6
+    je found
+    add rsi, 16
+    jmp auxv_loop
+found:
+    mov rdi, [rsi + 8]
+    jmp exit
+not_found:
+    mov rdi, -1
+    jmp exit
+
+data:
+    inc qword ptr [rip + value]
+    inc qword ptr [rip + zeroed]
+    mov rdi, [rip + value]
+    add rdi, [rip + zeroed]
+
+exit:
+    mov eax, 2
+    syscall
+    ud2
+
+# Returns the address of envp in rsi, which follows argv and its null pointer.
+envp:
+    mov rcx, [rbx]
+    lea rsi, [rbx + rcx * 8 + 16]
+    ret
+
+    .data
+value:
+    .quad 42
+
+    .bss
+zeroed:
+    .quad 0
@@ -0,0 +1,188 @@
+//! ACPI table discovery.
+//!
+//! The RSDP is found by scanning the BIOS memory areas through the physical memory mapping, and
+//! the tables are read in place. Typed views are provided for the [Madt], [Fadt], [HpetTable] and
+//! [Mcfg].
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use crate::memory::phys_to_virt;
+use core::mem::size_of;
+pub use fadt::Fadt;
+pub use hpet::HpetTable;
+pub use madt::{Madt, MadtEntry};
+pub use mcfg::{Mcfg, McfgEntry};
+use spinlock::LazyStatic;
+use x86_64::PhysAddr;
+
+/// The root table, found on first use.
+static ROOT: LazyStatic<Option<RootTable>> = LazyStatic::new(RootTable::find);
+
+/// The header shared by every ACPI system description table.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Returns the whole table, including the header.
+    pub fn bytes(&self) -> &[u8] {
+        let len = self.length as usize;
+        unsafe { core::slice::from_raw_parts((self as *const Self).cast(), len) }
+    }
+    /// Returns the table contents following the header.
+    pub fn data(&self) -> &[u8] {
+        &self.bytes()[size_of::<Self>()..]
+    }
+    /// Returns the signature as a string, or `"????"` if it's not valid UTF-8.
+    pub fn signature_str(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+    /// # Safety
+    /// `phys` must point to a readable table.
+    unsafe fn at(phys: PhysAddr) -> Option<&'static Self> {
+        let header = unsafe { &*phys_to_virt(phys).as_ptr::<Self>() };
+        let valid = header.length as usize >= size_of::<Self>() && checksum(header.bytes());
+        valid.then_some(header)
+    }
+}
+
+/// The address space a [GenericAddress] refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// An ACPI Generic Address Structure, describing the location of a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Parse the structure at `offset`, returning `None` if it is out of bounds or its address is
+    /// 0.
+    fn parse(bytes: &[u8], offset: usize) -> Option<Self> {
+        let space = match read::<u8>(bytes, offset)? {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        let address = read(bytes, offset + 4)?;
+        (address != 0).then_some(GenericAddress {
+            space,
+            bit_width: read(bytes, offset + 1)?,
+            bit_offset: read(bytes, offset + 2)?,
+            access_size: read(bytes, offset + 3)?,
+            address,
+        })
+    }
+}
+
+/// Returns whether the bytes sum to 0, as required of every ACPI structure.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Read a `T` at `offset`, returning `None` if it would be out of bounds.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    let bytes = bytes.get(offset..offset + size_of::<T>())?;
+    Some(unsafe { bytes.as_ptr().cast::<T>().read_unaligned() })
+}
+
+/// The RSDT or XSDT, listing the physical addresses of all other tables.
+struct RootTable {
+    header: &'static SdtHeader,
+    /// 4 for the RSDT, 8 for the XSDT.
+    entry_size: usize,
+}
+
+impl RootTable {
+    fn find() -> Option<Self> {
+        let rsdp = find_rsdp()?;
+        let revision: u8 = read(rsdp, 15)?;
+        if revision >= 2 {
+            let xsdt: u64 = read(rsdp, 24)?;
+            if let Some(header) = unsafe { SdtHeader::at(PhysAddr::new(xsdt)) } {
+                return Some(RootTable {
+                    header,
+                    entry_size: 8,
+                });
+            }
+        }
+        let rsdt: u32 = read(rsdp, 16)?;
+        let header = unsafe { SdtHeader::at(PhysAddr::new(rsdt as u64)) }?;
+        Some(RootTable {
+            header,
+            entry_size: 4,
+        })
+    }
+    fn entries(&self) -> impl Iterator<Item = PhysAddr> {
+        let entry_size = self.entry_size;
+        self.header
+            .data()
+            .chunks_exact(entry_size)
+            .map(move |entry| match entry_size {
+                4 => read::<u32>(entry, 0).unwrap_or(0) as u64,
+                _ => read::<u64>(entry, 0).unwrap_or(0),
+            })
+            .map(PhysAddr::new)
+    }
+}
+
+/// Locate the Root System Description Pointer, returning its bytes.
+fn find_rsdp() -> Option<&'static [u8]> {
+    const SIGNATURE: &[u8] = b"RSD PTR ";
+    let ebda = unsafe { phys_to_virt(PhysAddr::new(0x40e)).as_ptr::<u16>().read() } as u64;
+    let areas = [(ebda << 4, 1024), (0xe_0000, 0x2_0000)];
+    areas
+        .into_iter()
+        .filter(|&(start, _)| start != 0)
+        .flat_map(|(start, len)| (start..start + len).step_by(16))
+        .map(|addr| unsafe {
+            core::slice::from_raw_parts(phys_to_virt(PhysAddr::new(addr)).as_ptr(), 36)
+        })
+        .find(|rsdp: &&[u8]| {
+            if !rsdp.starts_with(SIGNATURE) || !checksum(&rsdp[..20]) {
+                return false;
+            }
+            // ACPI 2.0+ RSDPs have an extended checksum over the whole structure.
+            rsdp[15] < 2 || checksum(rsdp)
+        })
+}
+
+/// Returns whether ACPI tables were found.
+pub fn is_available() -> bool {
+    ROOT.is_some()
+}
+
+/// Iterate over every valid table listed by the root table.
+pub fn tables() -> impl Iterator<Item = &'static SdtHeader> {
+    ROOT.iter()
+        .flat_map(RootTable::entries)
+        .filter_map(|phys| unsafe { SdtHeader::at(phys) })
+}
+
+/// Find the first valid table with the given signature.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    tables().find(|table| &table.signature == signature)
+}
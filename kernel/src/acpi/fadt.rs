@@ -0,0 +1,76 @@
+//! The Fixed ACPI Description Table, describing the power management hardware.
+use super::{GenericAddress, SdtHeader, find_table, read};
+use x86_64::PhysAddr;
+
+/// The FADT flag indicating that the reset register is supported.
+pub const RESET_REG_SUP: u32 = 1 << 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    header: &'static SdtHeader,
+}
+
+impl Fadt {
+    /// Find the FADT, if the system has one.
+    pub fn get() -> Option<Self> {
+        find_table(b"FACP").map(|header| Fadt { header })
+    }
+    pub fn header(&self) -> &'static SdtHeader {
+        self.header
+    }
+    fn read<T: Copy + Default>(&self, offset: usize) -> T {
+        read(self.header.bytes(), offset).unwrap_or_default()
+    }
+    /// The physical address of the DSDT, preferring the 64-bit field when present.
+    pub fn dsdt(&self) -> PhysAddr {
+        match self.read::<u64>(140) {
+            0 => PhysAddr::new(self.read::<u32>(40) as u64),
+            addr => PhysAddr::new(addr),
+        }
+    }
+    /// The DSDT, which is referenced by the FADT rather than the root table.
+    pub fn dsdt_table(&self) -> Option<&'static SdtHeader> {
+        unsafe { SdtHeader::at(self.dsdt()) }
+    }
+    /// The interrupt the System Control Interrupt is wired to.
+    pub fn sci_interrupt(&self) -> u16 {
+        self.read(46)
+    }
+    /// The I/O port ACPI mode is enabled through, 0 if the system is always in ACPI mode.
+    pub fn smi_command_port(&self) -> u32 {
+        self.read(48)
+    }
+    /// The value to write to [Self::smi_command_port] to enable ACPI mode.
+    pub fn acpi_enable(&self) -> u8 {
+        self.read(52)
+    }
+    /// The I/O port of the PM1a control register block.
+    pub fn pm1a_control_block(&self) -> u32 {
+        self.read(64)
+    }
+    /// The I/O port of the PM1b control register block, 0 if not supported.
+    pub fn pm1b_control_block(&self) -> u32 {
+        self.read(68)
+    }
+    /// The I/O port of the power management timer, 0 if not supported.
+    pub fn pm_timer_block(&self) -> u32 {
+        self.read(76)
+    }
+    /// The CMOS RTC register holding the century, 0 if not supported.
+    pub fn century_register(&self) -> u8 {
+        self.read(108)
+    }
+    pub fn flags(&self) -> u32 {
+        self.read(112)
+    }
+    /// The register to write [Self::reset_value] to in order to reset the system, if supported.
+    pub fn reset_register(&self) -> Option<GenericAddress> {
+        if self.flags() & RESET_REG_SUP == 0 {
+            return None;
+        }
+        GenericAddress::parse(self.header.bytes(), 116)
+    }
+    pub fn reset_value(&self) -> u8 {
+        self.read(128)
+    }
+}
@@ -0,0 +1,32 @@
+//! The HPET description table, locating the High Precision Event Timer.
+use super::{GenericAddress, SdtHeader, find_table, read};
+
+#[derive(Debug, Clone, Copy)]
+pub struct HpetTable {
+    header: &'static SdtHeader,
+}
+
+impl HpetTable {
+    /// Find the HPET table, if the system has an HPET.
+    pub fn get() -> Option<Self> {
+        find_table(b"HPET").map(|header| HpetTable { header })
+    }
+    pub fn header(&self) -> &'static SdtHeader {
+        self.header
+    }
+    /// The hardware ID of the event timer block.
+    pub fn event_timer_block_id(&self) -> u32 {
+        read(self.header.bytes(), 36).unwrap_or(0)
+    }
+    /// The address of the HPET registers.
+    pub fn base_address(&self) -> Option<GenericAddress> {
+        GenericAddress::parse(self.header.bytes(), 40)
+    }
+    pub fn hpet_number(&self) -> u8 {
+        read(self.header.bytes(), 52).unwrap_or(0)
+    }
+    /// The minimum tick count of periodic timers without lost interrupts.
+    pub fn minimum_tick(&self) -> u16 {
+        read(self.header.bytes(), 53).unwrap_or(0)
+    }
+}
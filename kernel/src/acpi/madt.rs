@@ -0,0 +1,104 @@
+//! The Multiple APIC Description Table, describing the interrupt controllers in the system.
+use super::{SdtHeader, find_table, read};
+use x86_64::PhysAddr;
+
+/// The MADT flag indicating that legacy 8259 PICs are installed.
+pub const PCAT_COMPAT: u32 = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    header: &'static SdtHeader,
+}
+
+/// An interrupt controller structure in the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: PhysAddr,
+        gsi_base: u32,
+    },
+    /// Maps an ISA IRQ to a different global system interrupt.
+    InterruptOverride {
+        bus: u8,
+        irq: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    LocalApicAddressOverride(PhysAddr),
+    /// An entry type that isn't parsed.
+    Other(u8),
+}
+
+impl Madt {
+    /// Find the MADT, if the system has one.
+    pub fn get() -> Option<Self> {
+        find_table(b"APIC").map(|header| Madt { header })
+    }
+    pub fn header(&self) -> &'static SdtHeader {
+        self.header
+    }
+    pub fn flags(&self) -> u32 {
+        read(self.header.data(), 4).unwrap_or(0)
+    }
+    /// The physical address of the local APIC registers.
+    pub fn local_apic_address(&self) -> PhysAddr {
+        let address: u32 = read(self.header.data(), 0).unwrap_or(0);
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride(address) => Some(address),
+                _ => None,
+            })
+            .unwrap_or(PhysAddr::new(address as u64))
+    }
+    pub fn entries(&self) -> impl Iterator<Item = MadtEntry> + use<> {
+        let data = self.header.data();
+        let mut offset = 8;
+        core::iter::from_fn(move || {
+            let kind: u8 = read(data, offset)?;
+            let len: u8 = read(data, offset + 1)?;
+            let entry = data.get(offset..offset + len as usize)?;
+            if len < 2 {
+                return None;
+            }
+            offset += len as usize;
+            Some(parse_entry(kind, entry))
+        })
+    }
+}
+
+fn parse_entry(kind: u8, entry: &[u8]) -> MadtEntry {
+    let parsed = match kind {
+        0 => (|| {
+            Some(MadtEntry::LocalApic {
+                processor_id: read(entry, 2)?,
+                apic_id: read(entry, 3)?,
+                flags: read(entry, 4)?,
+            })
+        })(),
+        1 => (|| {
+            Some(MadtEntry::IoApic {
+                id: read(entry, 2)?,
+                address: PhysAddr::new(read::<u32>(entry, 4)? as u64),
+                gsi_base: read(entry, 8)?,
+            })
+        })(),
+        2 => (|| {
+            Some(MadtEntry::InterruptOverride {
+                bus: read(entry, 2)?,
+                irq: read(entry, 3)?,
+                gsi: read(entry, 4)?,
+                flags: read(entry, 8)?,
+            })
+        })(),
+        5 => read::<u64>(entry, 4)
+            .map(|addr| MadtEntry::LocalApicAddressOverride(PhysAddr::new(addr))),
+        _ => None,
+    };
+    parsed.unwrap_or(MadtEntry::Other(kind))
+}
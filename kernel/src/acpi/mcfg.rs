@@ -0,0 +1,38 @@
+//! The PCI Express memory mapped configuration space table.
+use super::{SdtHeader, find_table, read};
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+    header: &'static SdtHeader,
+}
+
+/// The configuration space of a range of buses in a PCI segment group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base_address: PhysAddr,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    /// Find the MCFG, if the system supports memory mapped PCI configuration.
+    pub fn get() -> Option<Self> {
+        find_table(b"MCFG").map(|header| Mcfg { header })
+    }
+    pub fn header(&self) -> &'static SdtHeader {
+        self.header
+    }
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> + use<> {
+        let data = self.header.data().get(8..).unwrap_or_default();
+        data.chunks_exact(16).filter_map(|entry| {
+            Some(McfgEntry {
+                base_address: PhysAddr::new(read(entry, 0)?),
+                segment_group: read(entry, 8)?,
+                start_bus: read(entry, 10)?,
+                end_bus: read(entry, 11)?,
+            })
+        })
+    }
+}
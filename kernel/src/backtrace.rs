@@ -0,0 +1,164 @@
+//! Symbolized backtraces, walked through the frame pointer chain.
+//!
+//! The kernel is built with frame pointers, so every frame starts with the caller's `rbp`
+//! followed by the return address. The names of functions come from the `.symbols` section,
+//! which `kernel/linker.sh` fills with the sorted addresses and names of all functions after
+//! linking.
+use crate::memory;
+use core::fmt;
+use x86_64::VirtAddr;
+
+/// The space reserved for the symbol table. Optimized builds inline most functions.
+const SYMBOLS_SIZE: usize = if cfg!(debug_assertions) {
+    2 * 1024 * 1024
+} else {
+    256 * 1024
+};
+/// The most frames a [Backtrace] records.
+pub const MAX_FRAMES: usize = 32;
+
+core::arch::global_asm!(
+    r#".pushsection .symbols, "a""#,
+    ".global symbol_table",
+    "symbol_table:",
+    ".skip {size}",
+    ".popsection",
+    size = const SYMBOLS_SIZE,
+);
+
+/// Returns the symbol table, lines of a hexadecimal address and a name sorted by address, or an
+/// empty table if the kernel wasn't linked by `kernel/linker.sh`.
+fn symbol_table() -> &'static [u8] {
+    unsafe extern "C" {
+        static symbol_table: [u8; SYMBOLS_SIZE];
+    }
+    let table = unsafe { &symbol_table };
+    let len = table
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(SYMBOLS_SIZE);
+    &table[..len]
+}
+
+/// A function and an offset into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// Returns the function containing `addr`.
+pub fn symbolize(addr: u64) -> Option<Symbol> {
+    let mut symbol = None;
+    for line in symbol_table().split(|&byte| byte == b'\n') {
+        let Some((start, name)) = core::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.split_once(' '))
+        else {
+            break;
+        };
+        let Ok(start) = u64::from_str_radix(start, 16) else {
+            break;
+        };
+        if start > addr {
+            break;
+        }
+        symbol = Some(Symbol {
+            name,
+            offset: addr - start,
+        });
+    }
+    symbol
+}
+
+/// The return addresses of a chain of frames, innermost first.
+#[derive(Clone)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+    /// Whether the first frame is the address of an interrupted instruction rather than a return
+    /// address.
+    exact_first: bool,
+}
+
+impl Backtrace {
+    /// Capture the frames of the caller and the functions that called it.
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        // The first frame is this function's own, returning into the caller.
+        Self::walk(None, rbp)
+    }
+    /// Capture the frames of code interrupted at `rip` with the frame pointer `rbp`.
+    pub fn from_frame(rip: u64, rbp: u64) -> Self {
+        Self::walk(Some(rip), rbp)
+    }
+    fn walk(first: Option<u64>, mut rbp: u64) -> Self {
+        let mut trace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            exact_first: first.is_some(),
+        };
+        if let Some(rip) = first {
+            trace.push(rip);
+        }
+        while trace.len < MAX_FRAMES && rbp != 0 && rbp % 8 == 0 {
+            let Ok(frame) = VirtAddr::try_new(rbp) else {
+                break;
+            };
+            if !memory::is_mapped(frame) || !memory::is_mapped(frame + 8u64) {
+                break;
+            }
+            let [next, ret] = unsafe { frame.as_ptr::<[u64; 2]>().read() };
+            if ret == 0 {
+                break;
+            }
+            trace.push(ret);
+            // The frames of callers are further up the stack, anything else is garbage.
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+        trace
+    }
+    fn push(&mut self, addr: u64) {
+        self.frames[self.len] = addr;
+        self.len += 1;
+    }
+    /// The recorded addresses, innermost first.
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, &addr) in self.frames().iter().enumerate() {
+            // Return addresses may point past the end of the calling function.
+            let exact = i == 0 && self.exact_first;
+            let symbol = if exact {
+                symbolize(addr)
+            } else {
+                symbolize(addr - 1).map(|symbol| Symbol {
+                    offset: symbol.offset + 1,
+                    ..symbol
+                })
+            };
+            write!(f, "{i:>3}: {addr:#018x}")?;
+            match symbol {
+                Some(symbol) => writeln!(f, " {symbol}")?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,24 +1,165 @@
+pub mod hpet;
+pub mod rtc;
+pub mod tickless;
+pub mod tsc;
+mod wall;
+
 use core::{
+    ops::{Add, Sub},
     sync::atomic::{AtomicU64, Ordering::*},
     time::Duration,
 };
+use hpet::Hpet;
+use spinlock::LazyStatic;
+pub use wall::{DateTime, WallClock};
+
+const NANOS_PER_MS: u64 = 1_000_000;
 
 /// A monotonically-nondecreasing millisecond-granular clock
 ///
-/// Gets incremented every millisecond by the Programmable Interval Timer
-pub(crate) static MS_CLOCK: AtomicU64 = AtomicU64::new(0);
+/// Gets incremented every millisecond by the Programmable Interval Timer, until the [tickless]
+/// timer takes over. Read through [load_ms], which switches to the clock source from then on.
+static MS_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+/// The high-resolution time source, selected by [init].
+static SOURCE: LazyStatic<ClockSource> =
+    LazyStatic::new(|| panic!("Attempted to use the clock source before clock::init"));
 
-/// Advances the global clock by 1 ms
+/// Advances the global clock by 1 ms, deferring waking the tasks whose deadline has passed, if
+/// any.
 pub fn tick_ms() {
     let timer = MS_CLOCK.fetch_add(1, Relaxed);
-    crate::task::timer::wake_tasks(timer);
+    if crate::task::timer::next_deadline().is_some_and(|deadline| deadline <= timer) {
+        crate::interrupts::deferred::defer(crate::task::timer::wake_tasks, timer);
+    }
+}
+
+/// Handle a timer interrupt, either the periodic tick or a [tickless] one-shot timer.
+pub fn timer_interrupt() {
+    if tickless::is_enabled() {
+        tickless::interrupt();
+    } else {
+        tick_ms();
+    }
+}
+
+/// Returns the number of milliseconds since boot, the timebase of
+/// [task::timer](crate::task::timer).
+pub fn now_ms() -> u64 {
+    load_ms()
+}
+
+/// A time source [Instant]s can be read from with nanosecond precision.
+#[derive(Debug)]
+pub enum ClockSource {
+    /// The invariant TSC, with its frequency in Hz.
+    Tsc {
+        frequency: u64,
+        offset: Offset,
+    },
+    Hpet {
+        hpet: Hpet,
+        offset: Offset,
+    },
+    /// The millisecond clock driven by the PIT, used when nothing better is available.
+    Pit,
+}
+
+/// The counter value and time at which a [ClockSource] was started, to keep it in sync with the
+/// [MS_CLOCK].
+#[derive(Debug)]
+pub struct Offset {
+    counter: u64,
+    nanos: u64,
+}
+
+impl ClockSource {
+    /// Returns the time since boot in nanoseconds.
+    fn nanos(&self) -> u64 {
+        match self {
+            ClockSource::Tsc { frequency, offset } => {
+                let ticks = tsc::read().wrapping_sub(offset.counter);
+                offset.nanos + (ticks as u128 * 1_000_000_000 / *frequency as u128) as u64
+            }
+            ClockSource::Hpet { hpet, offset } => {
+                offset.nanos + hpet.ticks_to_ns(hpet.counter().wrapping_sub(offset.counter))
+            }
+            ClockSource::Pit => load_ms() * NANOS_PER_MS,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClockSource::Tsc { .. } => "TSC",
+            ClockSource::Hpet { .. } => "HPET",
+            ClockSource::Pit => "PIT",
+        }
+    }
+}
+
+/// Select the high-resolution clock source: the invariant TSC calibrated against the HPET or
+/// PIT, the HPET, or the PIT millisecond clock, and switch to the [tickless] timer if possible.
+/// The [WallClock] is started from the RTC. Requires memory and interrupts to be initialized.
+pub fn init() {
+    let hpet = Hpet::new();
+    let source = if tsc::is_invariant() {
+        let frequency = tsc::calibrate(hpet.as_ref());
+        let offset = Offset {
+            nanos: load_ms() * NANOS_PER_MS,
+            counter: tsc::read(),
+        };
+        ClockSource::Tsc { frequency, offset }
+    } else if let Some(hpet) = hpet {
+        let offset = Offset {
+            nanos: load_ms() * NANOS_PER_MS,
+            counter: hpet.counter(),
+        };
+        ClockSource::Hpet { hpet, offset }
+    } else {
+        ClockSource::Pit
+    };
+    if SOURCE.insert_if_uninit(source).is_ok() {
+        WallClock::init();
+        tickless::init();
+    }
+}
+
+/// Returns the clock source in use, if [init] has been called.
+pub fn source() -> Option<&'static ClockSource> {
+    SOURCE.get_if_init()
+}
+
+/// Returns the TSC frequency in Hz, if the TSC is used as the clock source.
+pub fn tsc_frequency() -> Option<u64> {
+    match source()? {
+        ClockSource::Tsc { frequency, .. } => Some(*frequency),
+        _ => None,
+    }
+}
+
+/// Returns the number of milliseconds since boot. The [MS_CLOCK] stops once the [tickless] timer
+/// takes over, so the free-running clock source is read instead from then on.
+fn load_ms() -> u64 {
+    if tickless::is_enabled() {
+        load_now() / NANOS_PER_MS
+    } else {
+        MS_CLOCK.load(Relaxed)
+    }
 }
 
 fn load_now() -> u64 {
-    MS_CLOCK.load(Relaxed)
+    match source() {
+        Some(source) => source.nanos(),
+        None => load_ms() * NANOS_PER_MS,
+    }
 }
 
-/// Represents a measurement of the global millisecond-granular clock.
+/// Returns the time elapsed since boot.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(load_now())
+}
+
+/// Represents a measurement of the global clock, with nanosecond precision when a
+/// high-resolution [ClockSource] is available.
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Instant(u64);
@@ -27,39 +168,71 @@ impl Instant {
     pub fn now() -> Self {
         Self(load_now())
     }
+    /// Returns the number of milliseconds between boot and this instant.
     pub fn since_epoch(&self) -> u64 {
+        self.0 / NANOS_PER_MS
+    }
+    /// Returns the number of nanoseconds between boot and this instant.
+    pub fn since_epoch_ns(&self) -> u64 {
         self.0
     }
     pub fn elapsed_ms(&self) -> u64 {
-        load_now().wrapping_sub(self.0)
+        self.elapsed_ns() / NANOS_PER_MS
+    }
+    pub fn elapsed_ns(&self) -> u64 {
+        load_now().saturating_sub(self.0)
     }
     pub fn elapsed(&self) -> Duration {
-        let ms = self.elapsed_ms();
-        Duration::from_millis(ms)
+        Duration::from_nanos(self.elapsed_ns())
     }
     pub fn duration_since(&self, Self(earlier): Self) -> Duration {
-        let later = self.since_epoch();
-        let difference = later.saturating_sub(earlier);
-        Duration::from_millis(difference)
+        Duration::from_nanos(self.0.saturating_sub(earlier))
     }
     pub fn checked_add_ms(&self, ms: u64) -> Option<Self> {
-        self.0.checked_add(ms).map(Self::from)
+        self.0.checked_add(ms.checked_mul(NANOS_PER_MS)?).map(Self)
     }
     pub fn checked_sub_ms(&self, ms: u64) -> Option<Self> {
-        self.0.checked_sub(ms).map(Self::from)
+        self.0.checked_sub(ms.checked_mul(NANOS_PER_MS)?).map(Self)
     }
     pub fn checked_add(&self, dur: Duration) -> Option<Self> {
-        let ms: u64 = dur.as_millis().try_into().ok()?;
-        self.checked_add_ms(ms)
+        let ns: u64 = dur.as_nanos().try_into().ok()?;
+        self.0.checked_add(ns).map(Self)
     }
     pub fn checked_sub(&self, dur: Duration) -> Option<Self> {
-        let ms: u64 = dur.as_millis().try_into().ok()?;
-        self.checked_sub_ms(ms)
+        let ns: u64 = dur.as_nanos().try_into().ok()?;
+        self.0.checked_sub(ns).map(Self)
     }
 }
 
+/// Creates an instant from a number of milliseconds since boot.
 impl From<u64> for Instant {
-    fn from(value: u64) -> Self {
-        Self(value)
+    fn from(ms: u64) -> Self {
+        Self(ms * NANOS_PER_MS)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, dur: Duration) -> Instant {
+        self.checked_add(dur)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, dur: Duration) -> Instant {
+        self.checked_sub(dur)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
     }
 }
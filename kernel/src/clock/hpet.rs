@@ -0,0 +1,64 @@
+//! High Precision Event Timer driver.
+use crate::{acpi::HpetTable, memory::vmm::VMM};
+use x86_64::VirtAddr;
+
+/// The size of the HPET's register window.
+const REGS_SIZE: u64 = 0x400;
+
+// HPET registers
+const CAPABILITIES: u64 = 0x00;
+const CONFIG: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+
+const CONFIG_ENABLE: u64 = 1;
+const CAPABILITY_64BIT: u64 = 1 << 13;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+/// The HPET's main counter, used as a free-running clock.
+#[derive(Debug)]
+pub struct Hpet {
+    regs: VirtAddr,
+    /// The counter period in femtoseconds.
+    period_fs: u64,
+}
+
+impl Hpet {
+    /// Map and enable the HPET described by ACPI, if there is one with a 64-bit counter.
+    pub fn new() -> Option<Self> {
+        let base = HpetTable::get()?.base_address()?;
+        let regs = unsafe {
+            VMM.lock()
+                .map_mmio(x86_64::PhysAddr::new(base.address), REGS_SIZE)
+        };
+        let mut hpet = Hpet {
+            regs: regs.ok()?,
+            period_fs: 0,
+        };
+        let capabilities = hpet.read(CAPABILITIES);
+        hpet.period_fs = capabilities >> 32;
+        if capabilities & CAPABILITY_64BIT == 0 || hpet.period_fs == 0 {
+            VMM.lock().unmap_mmio(hpet.regs, REGS_SIZE);
+            return None;
+        }
+        hpet.write(CONFIG, hpet.read(CONFIG) | CONFIG_ENABLE);
+        Some(hpet)
+    }
+    fn read(&self, reg: u64) -> u64 {
+        unsafe { (self.regs + reg).as_ptr::<u64>().read_volatile() }
+    }
+    fn write(&mut self, reg: u64, value: u64) {
+        unsafe { (self.regs + reg).as_mut_ptr::<u64>().write_volatile(value) }
+    }
+    /// Returns the current value of the main counter.
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+    /// The counter frequency in Hz.
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+    /// Convert a number of counter ticks to nanoseconds.
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / FEMTOS_PER_NANO as u128) as u64
+    }
+}
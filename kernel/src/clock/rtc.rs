@@ -0,0 +1,206 @@
+//! CMOS real-time clock driver.
+//!
+//! Reads the calendar time kept by the battery-backed RTC, and drives its periodic and alarm
+//! interrupts on IRQ 8.
+use super::DateTime;
+use crate::{acpi::Fadt, interrupts};
+use core::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use spinlock::{DisableInterrupts, SpinLock};
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+/// Setting this bit in the register index disables NMIs while the RTC is accessed.
+const NMI_DISABLE: u8 = 0x80;
+
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const HOUR_PM: u8 = 1 << 7;
+const BINARY_MODE: u8 = 1 << 2;
+const HOUR_24: u8 = 1 << 1;
+
+/// The ISA IRQ the RTC interrupts on.
+pub const RTC_IRQ: u8 = 8;
+
+struct Handlers {
+    periodic: Option<fn()>,
+    alarm: Option<fn()>,
+}
+
+static HANDLERS: SpinLock<Handlers, DisableInterrupts> = SpinLock::disable_interrupts(Handlers {
+    periodic: None,
+    alarm: None,
+});
+
+fn read(reg: u8) -> u8 {
+    unsafe {
+        Port::new(0x70).write(reg | NMI_DISABLE);
+        Port::new(0x71).read()
+    }
+}
+
+fn write(reg: u8, value: u8) {
+    unsafe {
+        Port::new(0x70).write(reg | NMI_DISABLE);
+        Port::new(0x71).write(value);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | (value % 10)
+}
+
+/// The raw time registers.
+#[derive(PartialEq, Eq)]
+struct Registers {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_registers(century_reg: Option<u8>) -> Registers {
+    while read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    Registers {
+        seconds: read(SECONDS),
+        minutes: read(MINUTES),
+        hours: read(HOURS),
+        day: read(DAY),
+        month: read(MONTH),
+        year: read(YEAR),
+        century: century_reg.map(read).unwrap_or(0),
+    }
+}
+
+/// Read the current date and time from the RTC, which is assumed to be in UTC.
+pub fn read_datetime() -> DateTime {
+    let century_reg = Fadt::get()
+        .map(|fadt| fadt.century_register())
+        .filter(|&reg| reg != 0);
+    // The registers may change in the middle of being read, so read until they are stable.
+    let mut regs = without_interrupts(|| read_registers(century_reg));
+    loop {
+        let again = without_interrupts(|| read_registers(century_reg));
+        if again == regs {
+            break;
+        }
+        regs = again;
+    }
+    let status = without_interrupts(|| read(STATUS_B));
+    let binary = status & BINARY_MODE != 0;
+    let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+    let pm = regs.hours & HOUR_PM != 0;
+    let mut hour = decode(regs.hours & !HOUR_PM);
+    if status & HOUR_24 == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = match century_reg {
+        Some(_) => decode(regs.century) as u16,
+        None => 20,
+    };
+    DateTime {
+        year: century * 100 + decode(regs.year) as u16,
+        month: decode(regs.month),
+        day: decode(regs.day),
+        hour,
+        minute: decode(regs.minutes),
+        second: decode(regs.seconds),
+        nanosecond: 0,
+    }
+}
+
+/// Register the RTC interrupt handler on IRQ 8, once.
+fn register_irq() {
+    static REGISTERED: AtomicBool = AtomicBool::new(false);
+    if !REGISTERED.swap(true, Relaxed) {
+        interrupts::register_irq(RTC_IRQ, handle_interrupt);
+    }
+}
+
+/// Fire `handler` periodically at `32768 >> (rate - 1)` Hz, with `rate` between 3 (8192 Hz) and
+/// 15 (2 Hz). Passing `None` disables the periodic interrupt.
+pub fn set_periodic(rate: u8, handler: Option<fn()>) {
+    assert!((3..=15).contains(&rate), "Invalid RTC periodic rate {rate}");
+    without_interrupts(|| {
+        HANDLERS.lock().periodic = handler;
+        write(STATUS_A, (read(STATUS_A) & 0xf0) | rate);
+        let status = read(STATUS_B);
+        match handler {
+            Some(_) => write(STATUS_B, status | PERIODIC_INTERRUPT),
+            None => write(STATUS_B, status & !PERIODIC_INTERRUPT),
+        }
+        // Clear any pending interrupt
+        read(STATUS_C);
+    });
+    register_irq();
+}
+
+/// Fire `handler` every day when the RTC reaches `hour:minute:second` UTC. Passing `None`
+/// disables the alarm.
+pub fn set_alarm(hour: u8, minute: u8, second: u8, handler: Option<fn()>) {
+    without_interrupts(|| {
+        HANDLERS.lock().alarm = handler;
+        let status = read(STATUS_B);
+        let encode = |value: u8| {
+            if status & BINARY_MODE != 0 {
+                value
+            } else {
+                to_bcd(value)
+            }
+        };
+        let hour = match status & HOUR_24 {
+            0 if hour >= 12 => encode(if hour == 12 { 12 } else { hour - 12 }) | HOUR_PM,
+            0 if hour == 0 => encode(12),
+            _ => encode(hour),
+        };
+        write(SECONDS_ALARM, encode(second));
+        write(MINUTES_ALARM, encode(minute));
+        write(HOURS_ALARM, hour);
+        match handler {
+            Some(_) => write(STATUS_B, status | ALARM_INTERRUPT),
+            None => write(STATUS_B, status & !ALARM_INTERRUPT),
+        }
+        read(STATUS_C);
+    });
+    register_irq();
+}
+
+/// Acknowledge an RTC interrupt and run the registered handlers.
+pub fn handle_interrupt() {
+    let flags = read(STATUS_C);
+    let (periodic, alarm) = {
+        let handlers = HANDLERS.lock();
+        (handlers.periodic, handlers.alarm)
+    };
+    if let Some(handler) = periodic.filter(|_| flags & PERIODIC_INTERRUPT != 0) {
+        handler();
+    }
+    if let Some(handler) = alarm.filter(|_| flags & ALARM_INTERRUPT != 0) {
+        handler();
+    }
+}
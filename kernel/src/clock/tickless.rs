@@ -0,0 +1,142 @@
+//! Tickless timer scheduling.
+//!
+//! Instead of interrupting every millisecond, the timer hardware is programmed to fire once at
+//! the earliest deadline registered with [task::timer](crate::task::timer), so an idle CPU stays
+//! halted until something needs to run. The local APIC timer is used when the APIC is enabled,
+//! the PIT in one-shot mode otherwise. Every CPU has its own local APIC timer, armed for its own
+//! earliest deadline.
+//!
+//! Requires a high-resolution [ClockSource](super::ClockSource), which the time is read from once
+//! the millisecond clock is no longer advanced by the periodic tick.
+use super::{ClockSource, NANOS_PER_MS, load_now};
+use crate::interrupts::{self, PIT_FREQUENCY, TIMER_IRQ, apic, irq_vector};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering::*};
+use spinlock::LazyStatic;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The deadline in milliseconds the timer of the bootstrap processor is armed for before its
+/// [PerCpu](crate::smp::PerCpu) block exists, `u64::MAX` if none.
+static BOOT_ARMED: AtomicU64 = AtomicU64::new(u64::MAX);
+/// The number of one-shot timer interrupts handled, for diagnostics.
+static FIRED: AtomicU64 = AtomicU64::new(0);
+
+static TIMER: LazyStatic<OneshotTimer> =
+    LazyStatic::new(|| panic!("Attempted to use the tickless timer before it was initialized"));
+
+/// The hardware used to fire a single timer interrupt.
+#[derive(Debug, Clone, Copy)]
+enum OneshotTimer {
+    /// The local APIC timer, with its calibrated number of ticks per millisecond.
+    Apic {
+        ticks_per_ms: u64,
+    },
+    Pit,
+}
+
+impl OneshotTimer {
+    /// Arm the timer to fire after `ns` nanoseconds, or as late as it can if that's sooner.
+    fn arm(self, ns: u64) {
+        match self {
+            OneshotTimer::Apic { ticks_per_ms } => {
+                let ticks = (ns as u128 * ticks_per_ms as u128 / NANOS_PER_MS as u128)
+                    .min(u32::MAX as u128);
+                apic::arm_timer_oneshot(irq_vector(TIMER_IRQ), ticks as u32);
+            }
+            OneshotTimer::Pit => {
+                let counts = (ns as f64 * PIT_FREQUENCY / 1e9) as u64;
+                interrupts::arm_pit_oneshot(counts.clamp(1, u16::MAX as u64) as u16);
+            }
+        }
+    }
+}
+
+/// Returns whether the periodic tick has been replaced by one-shot timers.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Relaxed)
+}
+
+/// Switch from the periodic 1ms tick to one-shot timers. Does nothing without a high-resolution
+/// clock source.
+pub(super) fn init() {
+    if matches!(super::source(), None | Some(ClockSource::Pit)) {
+        return;
+    }
+    let timer = match apic::calibrate_timer() {
+        Some(ticks_per_ms) => OneshotTimer::Apic { ticks_per_ms },
+        None => OneshotTimer::Pit,
+    };
+    if TIMER.insert_if_uninit(timer).is_err() {
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        match timer {
+            // The local APIC timer uses the same vector, so the PIT must be silenced while the
+            // line's handlers keep running.
+            OneshotTimer::Apic { .. } => interrupts::silence_irq(TIMER_IRQ),
+            // Reprogramming the PIT to one-shot mode stops its periodic interrupts.
+            OneshotTimer::Pit => interrupts::arm_pit_oneshot(u16::MAX),
+        }
+        ENABLED.store(true, Relaxed);
+    });
+    if let Some(deadline) = crate::task::timer::next_deadline() {
+        schedule(deadline);
+    }
+}
+
+/// The deadline the timer of the current CPU is armed for.
+fn armed() -> &'static AtomicU64 {
+    match crate::smp::current() {
+        Some(cpu) => &cpu.timer_deadline,
+        None => &BOOT_ARMED,
+    }
+}
+
+/// Move the deadline armed before SMP was initialized over to the bootstrap processor's
+/// [PerCpu](crate::smp::PerCpu) block, once it is reachable.
+pub(crate) fn smp_ready() {
+    let deadline = BOOT_ARMED.swap(u64::MAX, Relaxed);
+    if deadline != u64::MAX {
+        schedule(deadline);
+    }
+}
+
+/// Returns the number of one-shot timer interrupts handled so far.
+pub fn fired() -> u64 {
+    FIRED.load(Relaxed)
+}
+
+/// Make sure a timer interrupt fires on the current CPU at the deadline, given in milliseconds
+/// since boot.
+pub fn schedule(deadline: u64) {
+    if !is_enabled() {
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let armed = armed();
+        if deadline >= armed.load(Relaxed) {
+            return;
+        }
+        armed.store(deadline, Relaxed);
+        let ns = (deadline * NANOS_PER_MS).saturating_sub(load_now());
+        TIMER.arm(ns);
+    })
+}
+
+/// Handle a one-shot timer interrupt by deferring waking the tasks whose deadline has passed.
+pub(super) fn interrupt() {
+    armed().store(u64::MAX, Relaxed);
+    FIRED.fetch_add(1, Relaxed);
+    crate::interrupts::deferred::defer(expire, super::now_ms());
+    // Keep preempting threads while others are waiting to run.
+    if let Some(deadline) = crate::thread::preemption_deadline() {
+        schedule(deadline);
+    }
+}
+
+/// Wake the tasks whose deadline passed by `now` and arm the timer for the next one.
+fn expire(now: u64) {
+    crate::task::timer::wake_tasks(now);
+    if let Some(deadline) = crate::task::timer::next_deadline() {
+        schedule(deadline);
+    }
+}
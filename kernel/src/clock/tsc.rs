@@ -0,0 +1,64 @@
+//! Time Stamp Counter calibration.
+use super::hpet::Hpet;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use x86_64::instructions::port::Port;
+
+/// The frequency of the PIT oscillator in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// How long to calibrate the TSC for, in milliseconds.
+const CALIBRATION_MS: u64 = 10;
+
+/// Returns whether the TSC runs at a constant rate regardless of power states.
+pub fn is_invariant() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Measure the TSC frequency in Hz against the HPET if available, or the PIT otherwise.
+pub fn calibrate(hpet: Option<&Hpet>) -> u64 {
+    match hpet {
+        Some(hpet) => calibrate_hpet(hpet),
+        None => calibrate_pit(),
+    }
+}
+
+fn calibrate_hpet(hpet: &Hpet) -> u64 {
+    let ticks = hpet.frequency() * CALIBRATION_MS / 1000;
+    let start = hpet.counter();
+    let tsc_start = read();
+    while hpet.counter().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+    let tsc_end = read();
+    let elapsed_ns = hpet.ticks_to_ns(hpet.counter().wrapping_sub(start));
+    ((tsc_end - tsc_start) as u128 * 1_000_000_000 / elapsed_ns as u128) as u64
+}
+
+/// Count TSC ticks during a one-shot countdown of PIT channel 2, which is not used for
+/// interrupts.
+fn calibrate_pit() -> u64 {
+    let count = (PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    unsafe {
+        // Enable the channel 2 gate, with the speaker disconnected
+        let value = gate.read();
+        gate.write((value & !0b10) | 0b1);
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+    }
+    let start = read();
+    // Bit 5 is set once the counter reaches 0
+    while unsafe { gate.read() } & 0x20 == 0 {
+        core::hint::spin_loop();
+    }
+    let end = read();
+    (end - start) * 1000 / CALIBRATION_MS
+}
@@ -0,0 +1,112 @@
+//! Calendar time, derived from the RTC reading taken at boot and the monotonic [Instant] clock.
+use super::{Instant, rtc};
+use core::{fmt, time::Duration};
+use spinlock::LazyStatic;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The wall clock time at a known [Instant], read from the RTC by [super::init].
+static WALL_CLOCK: LazyStatic<WallClock> =
+    LazyStatic::new(|| panic!("Attempted to use the wall clock before clock::init"));
+
+/// A UTC date and time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl DateTime {
+    /// Convert a duration since the Unix epoch to a date and time.
+    pub fn from_unix(since_epoch: Duration) -> Self {
+        let secs = since_epoch.as_secs();
+        let (days, time) = (secs / SECONDS_PER_DAY, secs % SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days as i64);
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+            nanosecond: since_epoch.subsec_nanos(),
+        }
+    }
+    /// Returns the duration since the Unix epoch, saturating at 0 for earlier dates.
+    pub fn to_unix(&self) -> Duration {
+        let days = days_from_civil(self.year as i64, self.month, self.day).max(0) as u64;
+        let secs = days * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64;
+        Duration::new(secs, self.nanosecond)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Returns the number of days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of [days_from_civil].
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Tracks calendar time by pairing an RTC reading with the [Instant] it was taken at.
+#[derive(Debug, Clone, Copy)]
+pub struct WallClock {
+    unix: Duration,
+    at: Instant,
+}
+
+impl WallClock {
+    /// Read the RTC and start tracking wall clock time from it.
+    pub(super) fn init() {
+        let clock = WallClock {
+            unix: rtc::read_datetime().to_unix(),
+            at: Instant::now(),
+        };
+        _ = WALL_CLOCK.insert_if_uninit(clock);
+    }
+    /// Returns the time since the Unix epoch, if the wall clock has been initialized.
+    pub fn unix_time() -> Option<Duration> {
+        let clock = WALL_CLOCK.get_if_init()?;
+        Some(clock.unix + clock.at.elapsed())
+    }
+    /// Returns the current date and time, if the wall clock has been initialized.
+    pub fn now() -> Option<DateTime> {
+        Self::unix_time().map(DateTime::from_unix)
+    }
+}
@@ -1,49 +1,133 @@
-use spinlock::LazyStatic;
+use crate::memory::{stack::KernelStack, vmm::VmmError};
+use alloc::boxed::Box;
+use spinlock::{DisableInterrupts, LazyStatic, SpinLock};
 use x86_64::VirtAddr;
 use x86_64::structures::{
     gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
     tss::TaskStateSegment,
 };
 
-static GDT: LazyStatic<Gdt> = LazyStatic::new(|| {
-    let mut gdt = GlobalDescriptorTable::new();
-    let code_selector = gdt.append(Descriptor::kernel_code_segment());
-    let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-    Gdt {
-        gdt,
-        code_selector,
-        tss_selector,
-    }
-});
+static GDT: LazyStatic<Gdt> = LazyStatic::new(|| Gdt::new(&TSS));
 
 struct Gdt {
     gdt: GlobalDescriptorTable,
-    code_selector: SegmentSelector,
+    selectors: Selectors,
     tss_selector: SegmentSelector,
 }
 
-/// Initialize the GlobalDescriptorTable
-pub fn init() {
-    use x86_64::instructions::segmentation::{CS, Segment};
-    use x86_64::instructions::tables::load_tss;
+/// The segment selectors of the GDT, which are the same on every CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+}
+
+impl Gdt {
+    fn new(tss: &'static SpinLock<TaskStateSegment, DisableInterrupts>) -> Self {
+        let mut gdt = GlobalDescriptorTable::new();
+        // `sysret` expects the user data segment to come right before the user code segment.
+        let selectors = Selectors {
+            kernel_code: gdt.append(Descriptor::kernel_code_segment()),
+            kernel_data: gdt.append(Descriptor::kernel_data_segment()),
+            user_data: gdt.append(Descriptor::user_data_segment()),
+            user_code: gdt.append(Descriptor::user_code_segment()),
+        };
+        // SAFETY: The TSS is never freed, and is only ever modified through its lock.
+        let tss_selector =
+            gdt.append(unsafe { Descriptor::tss_segment_unchecked(tss.get_inner_mut()) });
+        Gdt {
+            gdt,
+            selectors,
+            tss_selector,
+        }
+    }
+    fn load(&'static self) {
+        use x86_64::instructions::segmentation::{CS, SS, Segment};
+        use x86_64::instructions::tables::load_tss;
 
-    GDT.gdt.load();
-    unsafe {
-        CS::set_reg(GDT.code_selector);
-        load_tss(GDT.tss_selector);
+        self.gdt.load();
+        unsafe {
+            CS::set_reg(self.selectors.kernel_code);
+            SS::set_reg(self.selectors.kernel_data);
+            load_tss(self.tss_selector);
+        }
     }
 }
 
-pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Returns the segment selectors of the GDT.
+pub fn selectors() -> Selectors {
+    GDT.selectors
+}
+
+/// The GDT and TSS used by a CPU.
+#[derive(Clone, Copy)]
+pub struct CpuTables {
+    gdt: &'static Gdt,
+    pub tss: &'static SpinLock<TaskStateSegment, DisableInterrupts>,
+}
+
+impl CpuTables {
+    /// The tables of the bootstrap processor, loaded by [init].
+    pub fn bsp() -> Self {
+        CpuTables {
+            gdt: &GDT,
+            tss: &TSS,
+        }
+    }
+    /// Allocate a GDT and a TSS with its own interrupt stacks for an application processor.
+    /// Requires memory to be initialized.
+    pub fn new() -> Result<Self, VmmError> {
+        let mut tss = TaskStateSegment::new();
+        for index in 0..IST_STACK_COUNT {
+            let stack = KernelStack::new(IST_STACK_PAGES)?;
+            tss.interrupt_stack_table[index] = stack.top();
+            // Application processors are never shut down, so their stacks live forever.
+            core::mem::forget(stack);
+        }
+        let tss = Box::leak(Box::new(SpinLock::disable_interrupts(tss)));
+        let gdt = Box::leak(Box::new(Gdt::new(tss)));
+        Ok(CpuTables { gdt, tss })
+    }
+    /// Load the GDT and TSS on the current CPU.
+    pub fn load(&self) {
+        self.gdt.load();
+    }
+}
 
-static TSS: LazyStatic<TaskStateSegment> = LazyStatic::new(|| {
-    let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Initialize the GlobalDescriptorTable
+pub fn init() {
+    TSS.lock().interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
         let stack_start = VirtAddr::from_ptr(&raw const STACK);
-        stack_start + STACK_SIZE as u64
+        stack_start + BOOT_STACK_SIZE as u64
     };
-    tss
+    GDT.load();
+}
+
+/// Replace the static boot-time interrupt stacks with guard-paged stacks.
+/// Requires memory to be initialized.
+pub fn init_stacks() {
+    let stacks = IST_STACKS.force();
+    let mut tss = TSS.lock();
+    for (index, stack) in stacks.iter().enumerate() {
+        tss.interrupt_stack_table[index] = stack.top();
+    }
+}
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// The number of interrupt stacks in use.
+const IST_STACK_COUNT: usize = 1;
+/// The number of pages in every interrupt stack.
+const IST_STACK_PAGES: usize = 5;
+/// The size of the interrupt stack used before memory is initialized.
+const BOOT_STACK_SIZE: usize = 4096 * 5;
+
+static IST_STACKS: LazyStatic<[KernelStack; IST_STACK_COUNT]> = LazyStatic::new(|| {
+    [KernelStack::new(IST_STACK_PAGES).expect("Failed to allocate the double fault stack")]
 });
+
+static TSS: SpinLock<TaskStateSegment, DisableInterrupts> =
+    SpinLock::disable_interrupts(TaskStateSegment::new());
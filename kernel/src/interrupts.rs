@@ -1,4 +1,8 @@
-use core::time::Duration;
+use alloc::boxed::Box;
+use core::{
+    sync::atomic::{AtomicBool, Ordering::*},
+    time::Duration,
+};
 
 use pic8259::ChainedPics;
 use spinlock::{LazyStatic, SpinLock};
@@ -6,7 +10,16 @@ use x86_64::{
     instructions::{interrupts, port::Port},
     structures::idt::InterruptDescriptorTable,
 };
+pub mod apic;
+pub mod deferred;
+pub mod exceptions;
 mod handlers;
+mod irq;
+
+pub use irq::{
+    HandlerId, IRQ_COUNT, IrqStats, KEYBOARD_IRQ, TIMER_IRQ, irq_stats, irq_vector, mask_irq,
+    register_irq, silence_irq, unmask_irq, unregister_irq,
+};
 
 pub static PICS: SpinLock<ChainedPics> =
     SpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
@@ -16,22 +29,21 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 /// The global InterruptDescriptorTable.
 static IDT: LazyStatic<InterruptDescriptorTable> = LazyStatic::new(|| {
     let mut idt = InterruptDescriptorTable::new();
-    idt.breakpoint.set_handler_fn(handlers::breakpoint);
-    unsafe {
-        idt.double_fault
-            .set_handler_fn(handlers::double_fault)
-            .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX)
-    };
-    idt.page_fault.set_handler_fn(handlers::page_fault);
-    idt[InterruptIndex::Timer as u8].set_handler_fn(handlers::timer_interrupt);
-    idt[InterruptIndex::Keyboard as u8].set_handler_fn(handlers::keyboard_interrupt);
+    exceptions::install(&mut idt);
+    irq::install(&mut idt);
+    idt[apic::SPURIOUS_VECTOR].set_handler_fn(handlers::spurious_interrupt);
+    idt[apic::TLB_SHOOTDOWN_VECTOR].set_handler_fn(handlers::tlb_shootdown);
+    idt[apic::WAKEUP_VECTOR].set_handler_fn(handlers::wakeup);
+    idt[apic::RESCHEDULE_VECTOR].set_handler_fn(handlers::reschedule);
     idt
 });
 
+/// The frequency of the Programmable Interval Timer's oscillator in Hz.
+pub const PIT_FREQUENCY: f64 = 3579545. / 3.;
+
 /// Set the frequency of the Programmable Interval Timer.
 fn set_timer_freq(tick_every: Duration) {
-    const OSCILLATOR_FREQ: f64 = 3579545. / 3.;
-    let oscillator_interval = Duration::from_secs(1).div_f64(OSCILLATOR_FREQ);
+    let oscillator_interval = Duration::from_secs(1).div_f64(PIT_FREQUENCY);
     let counter = tick_every.div_duration_f32(oscillator_interval) as u16;
     interrupts::without_interrupts(|| unsafe {
         let mut command = Port::new(0x43);
@@ -42,6 +54,18 @@ fn set_timer_freq(tick_every: Duration) {
     })
 }
 
+/// Fire the Programmable Interval Timer once after `counts` oscillator periods, stopping its
+/// periodic interrupts.
+pub fn arm_pit_oneshot(counts: u16) {
+    interrupts::without_interrupts(|| unsafe {
+        let mut command = Port::new(0x43);
+        command.write(0b0011_0000u8);
+        let mut timer = Port::new(0x40);
+        timer.write((counts & 0xFF) as u8);
+        timer.write((counts >> 8) as u8);
+    })
+}
+
 /// Initialize interrupt handlers and the Programmable Interval Timer.
 pub fn init() {
     IDT.load();
@@ -49,12 +73,40 @@ pub fn init() {
         set_timer_freq(Duration::from_millis(1));
         PICS.lock().initialize();
     };
+    irq::write_pic_masks();
+    register_irq(TIMER_IRQ, handlers::timer);
+    register_irq(KEYBOARD_IRQ, handlers::keyboard);
     x86_64::instructions::interrupts::enable();
 }
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+/// The IDT of the bootstrap processor.
+pub fn idt() -> &'static InterruptDescriptorTable {
+    &IDT
+}
+
+/// Allocate a copy of the IDT for an application processor.
+pub fn new_idt() -> &'static InterruptDescriptorTable {
+    Box::leak(Box::new(IDT.clone()))
+}
+
+/// Whether to keep interrupts on the legacy PIC instead of switching to the APIC.
+static LEGACY_PIC: AtomicBool = AtomicBool::new(false);
+
+/// Keep delivering interrupts through the legacy PIC instead of switching to the APIC. Must be
+/// called before [crate::init].
+pub fn keep_legacy_pic() {
+    LEGACY_PIC.store(true, Relaxed);
+}
+
+/// Switch interrupt delivery from the legacy PIC over to the APIC, routing the unmasked IRQ lines
+/// through it. The legacy PIC is kept if [keep_legacy_pic] was called, the CPU has no local APIC
+/// or ACPI describes no I/O APIC. Requires memory to be initialized.
+pub fn init_apic() {
+    if LEGACY_PIC.load(Relaxed) {
+        crate::serial_println!("Keeping interrupts on the legacy PIC");
+        return;
+    }
+    if !interrupts::without_interrupts(|| apic::init(&irq::unmasked())) {
+        crate::serial_println!("No usable APIC found, falling back to the legacy PIC");
+    }
 }
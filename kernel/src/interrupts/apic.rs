@@ -0,0 +1,339 @@
+//! Local APIC and I/O APIC support.
+//!
+//! Once enabled, the legacy PIC is masked and ISA IRQs are routed through the I/O APIC to the
+//! bootstrap processor's local APIC, using the same vectors the PIC delivered them on.
+use crate::{
+    acpi::{Madt, MadtEntry},
+    memory::vmm::VMM,
+};
+use alloc::vec::Vec;
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicU64, Ordering::*},
+};
+use spinlock::{DisableInterrupts, SpinLock};
+use x86_64::{PhysAddr, VirtAddr, registers::model_specific::Msr};
+
+const APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// The size of the local APIC's register window.
+const LAPIC_SIZE: u64 = 0x400;
+/// The size of an I/O APIC's register window.
+const IOAPIC_SIZE: u64 = 0x20;
+
+// Local APIC registers
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+const LVT_MASKED: u32 = 1 << 16;
+/// Divide the timer input clock by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+// Interrupt command register fields
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_ALL_INCLUDING_SELF: u32 = 0b10 << 18;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// I/O APIC registers
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// The vector spurious interrupts from the local APIC are delivered on.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+/// The vector of the inter-processor interrupt asking a CPU to flush its TLB.
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xfe;
+/// The vector of the inter-processor interrupt waking a halted CPU.
+pub const WAKEUP_VECTOR: u8 = 0xfd;
+/// The vector of the inter-processor interrupt asking a CPU to switch to another thread.
+pub const RESCHEDULE_VECTOR: u8 = 0xfc;
+
+/// The virtual address of the local APIC registers, 0 while the APIC is not in use.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+static IO_APICS: SpinLock<Vec<IoApic>, DisableInterrupts> =
+    SpinLock::disable_interrupts(Vec::new());
+
+/// An I/O APIC, routing a range of global system interrupts to local APICs.
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    /// The number of redirection entries.
+    entries: u32,
+}
+
+impl IoApic {
+    /// # Safety
+    /// `phys` must be the address of an I/O APIC's registers.
+    unsafe fn new(phys: PhysAddr, gsi_base: u32) -> Option<Self> {
+        let base = unsafe { VMM.lock().map_mmio(phys, IOAPIC_SIZE) }.ok()?;
+        let mut io_apic = IoApic {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        Some(io_apic)
+    }
+    fn read(&mut self, reg: u32) -> u32 {
+        unsafe {
+            self.base.as_mut_ptr::<u32>().write_volatile(reg);
+            (self.base + 0x10u64).as_ptr::<u32>().read_volatile()
+        }
+    }
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            self.base.as_mut_ptr::<u32>().write_volatile(reg);
+            (self.base + 0x10u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        }
+    }
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+    fn set_redirection(&mut self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION + (gsi - self.gsi_base) * 2;
+        // Mask the entry while it's half-written
+        self.write(reg, REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+fn local_apic_read(base: u64, reg: usize) -> u32 {
+    unsafe { ((base as usize + reg) as *const u32).read_volatile() }
+}
+fn local_apic_write(base: u64, reg: usize, value: u32) {
+    unsafe { ((base as usize + reg) as *mut u32).write_volatile(value) }
+}
+
+/// Returns whether the CPU has a local APIC.
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1) }.edx & (1 << 9) != 0
+}
+
+/// Returns whether interrupts are delivered through the APIC rather than the legacy PIC.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Relaxed) != 0
+}
+
+/// Returns the ID of the local APIC of the current CPU.
+pub fn local_apic_id() -> Option<u8> {
+    let base = LOCAL_APIC.load(Relaxed);
+    (base != 0).then(|| (local_apic_read(base, LAPIC_ID) >> 24) as u8)
+}
+
+/// Signal the end of an interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    let base = LOCAL_APIC.load(Relaxed);
+    if base != 0 {
+        local_apic_write(base, LAPIC_EOI, 0);
+    }
+}
+
+/// Enable the local APIC and the I/O APICs described by the MADT, and switch the given ISA IRQs
+/// over to them. Returns false, leaving the legacy PIC in use, if there is no usable APIC.
+///
+/// Must be called with interrupts disabled, after memory is initialized.
+pub(super) fn init(isa_irqs: &[(u8, u8)]) -> bool {
+    if !is_supported() {
+        return false;
+    }
+    let Some(madt) = Madt::get() else {
+        return false;
+    };
+    let mut io_apics = IO_APICS.lock();
+    for entry in madt.entries() {
+        if let MadtEntry::IoApic {
+            address, gsi_base, ..
+        } = entry
+        {
+            io_apics.extend(unsafe { IoApic::new(address, gsi_base) });
+        }
+    }
+    if io_apics.is_empty() {
+        return false;
+    }
+    let Ok(base) = (unsafe { VMM.lock().map_mmio(madt.local_apic_address(), LAPIC_SIZE) }) else {
+        for io_apic in io_apics.drain(..) {
+            VMM.lock().unmap_mmio(io_apic.base, IOAPIC_SIZE);
+        }
+        return false;
+    };
+    let base = base.as_u64();
+    enable_local_apic(base);
+    LOCAL_APIC.store(base, Relaxed);
+    core::mem::drop(io_apics);
+
+    unsafe { super::PICS.lock().disable() };
+    for &(irq, vector) in isa_irqs {
+        route_isa_irq(irq, vector);
+    }
+    true
+}
+
+fn enable_local_apic(base: u64) {
+    unsafe {
+        let mut msr = Msr::new(APIC_BASE_MSR);
+        msr.write(msr.read() | APIC_BASE_ENABLE);
+    }
+    local_apic_write(base, LAPIC_TPR, 0);
+    local_apic_write(
+        base,
+        LAPIC_SPURIOUS,
+        LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+}
+
+/// Enable the local APIC of an application processor. The bootstrap processor's is enabled by
+/// [init], which must have succeeded.
+pub fn init_ap() {
+    let base = LOCAL_APIC.load(Relaxed);
+    assert_ne!(
+        base, 0,
+        "The APIC of the bootstrap processor is not enabled"
+    );
+    enable_local_apic(base);
+}
+
+/// Send an inter-processor interrupt, waiting for the local APIC to accept it.
+fn send_ipi_raw(destination: u8, command: u32) {
+    let base = LOCAL_APIC.load(Relaxed);
+    if base == 0 {
+        return;
+    }
+    // An interrupt handler sending an IPI between the two writes would clobber the destination.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        local_apic_write(base, LAPIC_ICR_HIGH, (destination as u32) << 24);
+        local_apic_write(base, LAPIC_ICR_LOW, command);
+        while local_apic_read(base, LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    })
+}
+
+/// Send an interrupt on `vector` to the CPU with the given local APIC ID.
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send_ipi_raw(apic_id, ICR_ASSERT | vector as u32);
+}
+
+/// Send an interrupt on `vector` to every CPU except the current one.
+pub fn broadcast_ipi(vector: u8) {
+    send_ipi_raw(0, ICR_ALL_EXCLUDING_SELF | ICR_ASSERT | vector as u32);
+}
+
+/// Send an interrupt on `vector` to every CPU, including the current one.
+pub fn broadcast_ipi_including_self(vector: u8) {
+    send_ipi_raw(0, ICR_ALL_INCLUDING_SELF | ICR_ASSERT | vector as u32);
+}
+
+/// Send an INIT IPI, resetting the CPU into its wait-for-startup state.
+pub fn send_init(apic_id: u8) {
+    send_ipi_raw(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Send a startup IPI, making a CPU waiting for startup execute real mode code at the physical
+/// address `page * 4096`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi_raw(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+/// Returns the global system interrupt and MPS INTI flags of an ISA IRQ, applying any interrupt
+/// source override from the MADT.
+fn isa_gsi(irq: u8) -> (u32, u16) {
+    Madt::get()
+        .into_iter()
+        .flat_map(|madt| madt.entries())
+        .find_map(|entry| match entry {
+            MadtEntry::InterruptOverride {
+                bus: 0,
+                irq: source,
+                gsi,
+                flags,
+            } if source == irq => Some((gsi, flags)),
+            _ => None,
+        })
+        .unwrap_or((irq as u32, 0))
+}
+
+/// Route an ISA IRQ to `vector` on the current CPU. Returns false if no I/O APIC handles the
+/// IRQ.
+pub fn route_isa_irq(irq: u8, vector: u8) -> bool {
+    let Some(apic_id) = local_apic_id() else {
+        return false;
+    };
+    let (gsi, flags) = isa_gsi(irq);
+    // ISA interrupts are active high and edge triggered unless overridden.
+    let mut entry = vector as u64 | ((apic_id as u64) << 56);
+    if flags & 0b11 == 0b11 {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        entry |= REDIRECTION_LEVEL;
+    }
+    set_redirection(gsi, entry)
+}
+
+/// Stop delivering an ISA IRQ. Returns false if no I/O APIC handles the IRQ.
+pub fn mask_isa_irq(irq: u8) -> bool {
+    set_redirection(isa_gsi(irq).0, REDIRECTION_MASKED)
+}
+
+fn set_redirection(gsi: u32, entry: u64) -> bool {
+    let mut io_apics = IO_APICS.lock();
+    let Some(io_apic) = io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) else {
+        return false;
+    };
+    io_apic.set_redirection(gsi, entry);
+    true
+}
+
+/// Measure how many local APIC timer ticks elapse per millisecond, using the [clock
+/// source](crate::clock::source).
+pub fn calibrate_timer() -> Option<u64> {
+    use crate::clock::Instant;
+
+    let base = LOCAL_APIC.load(Relaxed);
+    if base == 0 {
+        return None;
+    }
+    const CALIBRATION_MS: u64 = 10;
+    local_apic_write(base, LAPIC_LVT_TIMER, LVT_MASKED);
+    local_apic_write(base, LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    let start = Instant::now();
+    local_apic_write(base, LAPIC_TIMER_INITIAL, u32::MAX);
+    while start.elapsed_ms() < CALIBRATION_MS {
+        core::hint::spin_loop();
+    }
+    let remaining = local_apic_read(base, LAPIC_TIMER_CURRENT);
+    local_apic_write(base, LAPIC_TIMER_INITIAL, 0);
+    let ticks = (u32::MAX - remaining) as u64 * 1_000_000 / start.elapsed_ns().max(1);
+    (ticks != 0).then_some(ticks)
+}
+
+/// Fire the local APIC timer once on `vector` after `ticks` timer ticks, replacing any pending
+/// timer.
+pub fn arm_timer_oneshot(vector: u8, ticks: u32) {
+    let base = LOCAL_APIC.load(Relaxed);
+    if base == 0 {
+        return;
+    }
+    local_apic_write(base, LAPIC_LVT_TIMER, vector as u32);
+    local_apic_write(base, LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    local_apic_write(base, LAPIC_TIMER_INITIAL, ticks.max(1));
+}
@@ -0,0 +1,81 @@
+//! Work deferred out of interrupt handlers.
+//!
+//! IRQ handlers only acknowledge their device and [defer] the rest, like waking tasks or printing,
+//! to a queue drained by a task running on its own thread. Until that task is started, the queue
+//! is drained at the end of every IRQ instead, after the end of interrupt was signalled. Work that
+//! doesn't fit in the queue, or is deferred before the heap is initialized, runs right away.
+use crate::thread;
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering::*},
+    task::Poll,
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use spinlock::LazyStatic;
+
+/// The number of work items that can be pending.
+const QUEUE_SIZE: usize = 256;
+
+/// A function to run later, with its argument.
+struct Work {
+    run: fn(u64),
+    arg: u64,
+}
+
+static QUEUE: LazyStatic<ArrayQueue<Work>> = LazyStatic::new(|| ArrayQueue::new(QUEUE_SIZE));
+static WORKER_WAKER: AtomicWaker = AtomicWaker::new();
+static WORKER_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Run `run(arg)` outside of interrupt context, soon.
+pub fn defer(run: fn(u64), arg: u64) {
+    let Some(queue) = QUEUE.get_if_init() else {
+        return run(arg);
+    };
+    if let Err(work) = queue.push(Work { run, arg }) {
+        return (work.run)(work.arg);
+    }
+    WORKER_WAKER.wake();
+}
+
+/// Run all pending work, returning how many items ran.
+pub fn run_pending() -> usize {
+    let Some(queue) = QUEUE.get_if_init() else {
+        return 0;
+    };
+    let mut ran = 0;
+    while let Some(work) = queue.pop() {
+        (work.run)(work.arg);
+        ran += 1;
+    }
+    ran
+}
+
+/// Drain the queue at the end of an IRQ, unless the worker takes care of it.
+pub(super) fn interrupt_exit() {
+    if !WORKER_STARTED.load(Relaxed) {
+        run_pending();
+    }
+}
+
+/// Drain the queue whenever work is deferred.
+async fn worker() {
+    poll_fn(|cx| {
+        run_pending();
+        WORKER_WAKER.register(cx.waker());
+        // Work deferred before the waker was registered didn't wake us.
+        run_pending();
+        Poll::<()>::Pending
+    })
+    .await
+}
+
+/// Allocate the queue and start the task draining it on its own thread. Requires threads to be
+/// initialized.
+pub fn init() {
+    QUEUE.force();
+    if WORKER_STARTED.swap(true, Relaxed) {
+        return;
+    }
+    thread::spawn(|| thread::block_on(worker()));
+}
@@ -0,0 +1,406 @@
+//! Handlers for the CPU exceptions.
+//!
+//! Every exception vector has an assembly stub that pushes a zero in place of the error code when
+//! the CPU doesn't push one, then the vector and the general purpose registers, and calls
+//! [handle_exception] with the resulting [ExceptionFrame]. Page faults the VMM can't resolve and
+//! all other faults print a register dump to VGA and serial. Faults in user mode then end the
+//! process, while faults in the kernel print a backtrace starting at the faulting instruction
+//! and halt.
+use crate::{
+    backtrace::{Backtrace, symbolize},
+    prelude::{vga_color::*, *},
+};
+use core::fmt;
+use x86_64::{
+    PrivilegeLevel, VirtAddr,
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::{
+        DescriptorTable, ExceptionVector, InterruptDescriptorTable, InterruptStackFrame,
+        PageFaultErrorCode, SelectorErrorCode,
+    },
+};
+
+/// Generate a stub for each exception, the `exception_common` routine they jump to, and
+/// `install_stubs` to point their IDT entries at them.
+macro_rules! exception_stubs {
+    (
+        without_error_code: $($field:ident = $vector:literal),*;
+        with_error_code: $($code_field:ident = $code_vector:literal),*;
+    ) => {
+        core::arch::global_asm!(
+            $(
+                concat!(".global exception_", stringify!($field)),
+                concat!("exception_", stringify!($field), ":"),
+                "push 0",
+                concat!("push ", $vector),
+                "jmp exception_common",
+            )*
+            $(
+                concat!(".global exception_", stringify!($code_field)),
+                concat!("exception_", stringify!($code_field), ":"),
+                concat!("push ", $code_vector),
+                "jmp exception_common",
+            )*
+            "",
+            "exception_common:",
+            "cld",
+            "push r15",
+            "push r14",
+            "push r13",
+            "push r12",
+            "push r11",
+            "push r10",
+            "push r9",
+            "push r8",
+            "push rbp",
+            "push rdi",
+            "push rsi",
+            "push rdx",
+            "push rcx",
+            "push rbx",
+            "push rax",
+            // 22 words were pushed onto the 16 byte aligned stack the CPU switched to, so it is
+            // still aligned for the call.
+            "mov rdi, rsp",
+            "call {handle}",
+            "pop rax",
+            "pop rbx",
+            "pop rcx",
+            "pop rdx",
+            "pop rsi",
+            "pop rdi",
+            "pop rbp",
+            "pop r8",
+            "pop r9",
+            "pop r10",
+            "pop r11",
+            "pop r12",
+            "pop r13",
+            "pop r14",
+            "pop r15",
+            // Skip the vector and error code.
+            "add rsp, 16",
+            "iretq",
+            handle = sym handle_exception,
+        );
+
+        mod stubs {
+            unsafe extern "C" {
+                $(
+                    #[link_name = concat!("exception_", stringify!($field))]
+                    pub fn $field();
+                )*
+                $(
+                    #[link_name = concat!("exception_", stringify!($code_field))]
+                    pub fn $code_field();
+                )*
+            }
+        }
+
+        /// Point the IDT entry of every exception at its stub.
+        fn install_stubs(idt: &mut InterruptDescriptorTable) {
+            // SAFETY: The stubs save every register they touch and return with `iretq`, after
+            // popping the error code if the CPU pushed one.
+            unsafe {
+                $(idt.$field.set_handler_addr(VirtAddr::from_ptr(stubs::$field as *const ()));)*
+                $(
+                    idt.$code_field
+                        .set_handler_addr(VirtAddr::from_ptr(stubs::$code_field as *const ()));
+                )*
+            }
+        }
+    };
+}
+
+exception_stubs! {
+    without_error_code:
+        divide_error = 0,
+        debug = 1,
+        non_maskable_interrupt = 2,
+        breakpoint = 3,
+        overflow = 4,
+        bound_range_exceeded = 5,
+        invalid_opcode = 6,
+        device_not_available = 7,
+        x87_floating_point = 16,
+        machine_check = 18,
+        simd_floating_point = 19,
+        virtualization = 20,
+        hv_injection_exception = 28;
+    with_error_code:
+        double_fault = 8,
+        invalid_tss = 10,
+        segment_not_present = 11,
+        stack_segment_fault = 12,
+        general_protection_fault = 13,
+        page_fault = 14,
+        alignment_check = 17,
+        cp_protection_exception = 21,
+        vmm_communication_exception = 29,
+        security_exception = 30;
+}
+
+/// Install the handlers of all exceptions in `idt`. Double faults run on their own stack, so that
+/// a kernel stack overflow can still be reported.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    install_stubs(idt);
+    unsafe {
+        idt.double_fault
+            .set_handler_addr(VirtAddr::from_ptr(stubs::double_fault as *const ()))
+            .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+}
+
+/// The general purpose registers at the time of an exception.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// The state saved by an exception stub, as laid out on the stack.
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    /// The error code pushed by the CPU, or zero.
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrame,
+}
+
+impl ExceptionFrame {
+    /// Returns whether the exception happened in user mode.
+    pub fn from_user(&self) -> bool {
+        self.stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+    }
+}
+
+/// The decoded error code of an exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The exception doesn't push an error code.
+    None,
+    /// The segment selector that caused the fault.
+    Selector(SelectorErrorCode),
+    /// The kind of access that caused a page fault.
+    PageFault(PageFaultErrorCode),
+    /// An error code without further decoding.
+    Raw(u64),
+}
+
+impl ErrorCode {
+    /// Decode the error code `code` of exception `vector`.
+    pub fn decode(vector: u8, code: u64) -> Self {
+        use ExceptionVector::*;
+        match ExceptionVector::try_from(vector) {
+            Ok(InvalidTss | SegmentNotPresent | Stack | GeneralProtection) => {
+                ErrorCode::Selector(SelectorErrorCode::new_truncate(code))
+            }
+            Ok(Page) => ErrorCode::PageFault(PageFaultErrorCode::from_bits_retain(code)),
+            Ok(Double | AlignmentCheck | ControlProtection | VmmCommunication | Security) => {
+                ErrorCode::Raw(code)
+            }
+            _ => ErrorCode::None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::None => write!(f, "none"),
+            // Faults unrelated to a segment push a zero.
+            ErrorCode::Selector(selector) if selector.is_null() => {
+                write!(f, "no selector")
+            }
+            ErrorCode::Selector(selector) => {
+                let table = match selector.descriptor_table() {
+                    DescriptorTable::Gdt => "GDT",
+                    DescriptorTable::Idt => "IDT",
+                    DescriptorTable::Ldt => "LDT",
+                };
+                write!(f, "{table} index {}", selector.index())?;
+                if selector.external() {
+                    write!(f, ", external")?;
+                }
+                Ok(())
+            }
+            ErrorCode::PageFault(code) => write!(f, "{code:?}"),
+            ErrorCode::Raw(code) => write!(f, "{code:#x}"),
+        }
+    }
+}
+
+/// Returns the mnemonic and name of exception `vector`.
+pub fn name(vector: u8) -> (&'static str, &'static str) {
+    use ExceptionVector::*;
+    match ExceptionVector::try_from(vector) {
+        Ok(Division) => ("#DE", "Divide Error"),
+        Ok(Debug) => ("#DB", "Debug"),
+        Ok(NonMaskableInterrupt) => ("NMI", "Non-Maskable Interrupt"),
+        Ok(Breakpoint) => ("#BP", "Breakpoint"),
+        Ok(Overflow) => ("#OF", "Overflow"),
+        Ok(BoundRange) => ("#BR", "Bound Range Exceeded"),
+        Ok(InvalidOpcode) => ("#UD", "Invalid Opcode"),
+        Ok(DeviceNotAvailable) => ("#NM", "Device Not Available"),
+        Ok(Double) => ("#DF", "Double Fault"),
+        Ok(InvalidTss) => ("#TS", "Invalid TSS"),
+        Ok(SegmentNotPresent) => ("#NP", "Segment Not Present"),
+        Ok(Stack) => ("#SS", "Stack-Segment Fault"),
+        Ok(GeneralProtection) => ("#GP", "General Protection Fault"),
+        Ok(Page) => ("#PF", "Page Fault"),
+        Ok(X87FloatingPoint) => ("#MF", "x87 Floating-Point Exception"),
+        Ok(AlignmentCheck) => ("#AC", "Alignment Check"),
+        Ok(MachineCheck) => ("#MC", "Machine Check"),
+        Ok(SimdFloatingPoint) => ("#XM", "SIMD Floating-Point Exception"),
+        Ok(Virtualization) => ("#VE", "Virtualization Exception"),
+        Ok(ControlProtection) => ("#CP", "Control Protection Exception"),
+        Ok(HypervisorInjection) => ("#HV", "Hypervisor Injection Exception"),
+        Ok(VmmCommunication) => ("#VC", "VMM Communication Exception"),
+        Ok(Security) => ("#SX", "Security Exception"),
+        _ => ("#??", "Unknown Exception"),
+    }
+}
+
+/// Returns the exit code of a user program killed by exception `vector`, the one shells report
+/// for the matching signal.
+pub fn exit_code(vector: u8) -> i64 {
+    use ExceptionVector::*;
+    const SIGILL: i64 = 4;
+    const SIGTRAP: i64 = 5;
+    const SIGBUS: i64 = 7;
+    const SIGFPE: i64 = 8;
+    const SIGSEGV: i64 = 11;
+    let signal = match ExceptionVector::try_from(vector) {
+        Ok(InvalidOpcode) => SIGILL,
+        Ok(Debug | Breakpoint) => SIGTRAP,
+        Ok(AlignmentCheck) => SIGBUS,
+        Ok(Division | X87FloatingPoint | SimdFloatingPoint) => SIGFPE,
+        _ => SIGSEGV,
+    };
+    128 + signal
+}
+
+/// The register dump printed for an exception.
+struct Dump<'a>(&'a ExceptionFrame);
+
+impl fmt::Display for Dump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ExceptionFrame {
+            registers: r,
+            vector,
+            error_code,
+            stack_frame: frame,
+        } = self.0;
+        let vector = *vector as u8;
+        let (mnemonic, name) = name(vector);
+        let mode = if self.0.from_user() { "user" } else { "kernel" };
+        writeln!(
+            f,
+            "EXCEPTION: {mnemonic} {name} (vector {vector}) in {mode} mode"
+        )?;
+        let decoded = ErrorCode::decode(vector, *error_code);
+        if decoded != ErrorCode::None {
+            writeln!(f, "Error code: {error_code:#x} ({decoded})")?;
+        }
+        let rip = frame.instruction_pointer.as_u64();
+        if let Some(symbol) = symbolize(rip).filter(|_| !self.0.from_user()) {
+            writeln!(f, "In {symbol}")?;
+        }
+        let (cr3_frame, cr3_flags) = Cr3::read_raw();
+        let rows = [
+            [
+                ("RIP", rip),
+                ("RSP", frame.stack_pointer.as_u64()),
+                ("RFL", frame.cpu_flags.bits()),
+            ],
+            [
+                ("CS", frame.code_segment.0.into()),
+                ("SS", frame.stack_segment.0.into()),
+                ("RBP", r.rbp),
+            ],
+            [("RAX", r.rax), ("RBX", r.rbx), ("RCX", r.rcx)],
+            [("RDX", r.rdx), ("RSI", r.rsi), ("RDI", r.rdi)],
+            [("R8", r.r8), ("R9", r.r9), ("R10", r.r10)],
+            [("R11", r.r11), ("R12", r.r12), ("R13", r.r13)],
+            [("R14", r.r14), ("R15", r.r15), ("CR0", Cr0::read_raw())],
+            [
+                ("CR2", Cr2::read_raw()),
+                (
+                    "CR3",
+                    cr3_frame.start_address().as_u64() | u64::from(cr3_flags),
+                ),
+                ("CR4", Cr4::read_raw()),
+            ],
+        ];
+        for row in rows {
+            for (i, (register, value)) in row.into_iter().enumerate() {
+                let separator = if i == 0 { "" } else { "  " };
+                write!(f, "{separator}{register:>3} {value:#018x}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Called by the exception stubs with the saved state.
+extern "C" fn handle_exception(frame: &mut ExceptionFrame) {
+    let vector = frame.vector as u8;
+    if vector == ExceptionVector::Page as u8 {
+        let error_code = PageFaultErrorCode::from_bits_retain(frame.error_code);
+        if Cr2::read().is_ok_and(|addr| crate::memory::vmm::handle_page_fault(addr, error_code)) {
+            return;
+        }
+    }
+
+    let dump = Dump(frame);
+    let color = VGA_OUT.lock().color;
+    println!(fgcolor = LightRed, bgcolor = Black, "{dump}");
+    VGA_OUT.lock().color = color;
+    serial_println!("{dump}");
+
+    let vector = ExceptionVector::try_from(vector);
+    if let Ok(ExceptionVector::Debug | ExceptionVector::Breakpoint) = vector {
+        // Traps point past the instruction that raised them, so execution can just continue.
+        return;
+    }
+    let fatal = matches!(
+        vector,
+        Ok(ExceptionVector::Double | ExceptionVector::MachineCheck)
+    );
+    if frame.from_user() && !fatal {
+        crate::user::exit(exit_code(frame.vector as u8));
+    }
+    // Panicking would capture a backtrace of the panic handler instead of the faulting code.
+    let backtrace = Backtrace::from_frame(
+        frame.stack_frame.instruction_pointer.as_u64(),
+        frame.registers.rbp,
+    );
+    println!("{backtrace}");
+    serial_println!("{backtrace}");
+    if crate::panic::is_qemu_test() {
+        use crate::qemu::{QemuExitCode, exit_qemu};
+        serial_println!("[failed]\n");
+        serial_println!(
+            "Error: Unrecoverable exception {}\n",
+            name(frame.vector as u8).0
+        );
+        exit_qemu(QemuExitCode::Failed);
+    }
+    crate::hlt_loop()
+}
@@ -1,57 +1,39 @@
-use super::InterruptIndex;
-use crate::{
-    interrupts::PICS,
-    prelude::{vga_color::*, *},
-};
 use x86_64::structures::idt::InterruptStackFrame;
-use x86_64::structures::idt::PageFaultErrorCode;
 
-pub extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
-    println!(
-        fgcolor = LightRed,
-        "EXCEPTION: BREAKPOINT\n{:#?}", stack_frame
-    );
-}
-pub extern "x86-interrupt" fn double_fault(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-pub extern "x86-interrupt" fn timer_interrupt(_stack_frame: InterruptStackFrame) {
-    crate::clock::tick_ms();
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
-    }
+/// The handler of the timer IRQ.
+pub fn timer() {
+    crate::clock::timer_interrupt();
+    crate::thread::tick();
 }
 
-pub extern "x86-interrupt" fn page_fault(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    let color = VGA_OUT.lock().color;
-    println!(fgcolor = LightRed, bgcolor = Black, "EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    VGA_OUT.lock().color = color;
-    crate::hlt_loop();
-}
-
-pub extern "x86-interrupt" fn keyboard_interrupt(_stack_frame: InterruptStackFrame) {
+/// The handler of the keyboard IRQ.
+pub fn keyboard() {
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
-    }
+/// Sent by another CPU after it changed the page tables.
+pub extern "x86-interrupt" fn tlb_shootdown(_stack_frame: InterruptStackFrame) {
+    crate::smp::handle_tlb_shootdown();
+    super::apic::end_of_interrupt();
 }
+
+/// Sent to wake a halted CPU, the interrupt itself does nothing.
+pub extern "x86-interrupt" fn wakeup(_stack_frame: InterruptStackFrame) {
+    super::apic::end_of_interrupt();
+}
+
+/// Sent by another CPU when the time slice of the current thread is up, or when its process was
+/// killed.
+pub extern "x86-interrupt" fn reschedule(stack_frame: InterruptStackFrame) {
+    super::apic::end_of_interrupt();
+    crate::thread::reschedule_interrupt();
+    crate::process::exit_if_killed(&stack_frame);
+}
+
+/// Spurious interrupts must not be acknowledged, so they are simply ignored.
+pub extern "x86-interrupt" fn spurious_interrupt(_stack_frame: InterruptStackFrame) {}
@@ -0,0 +1,262 @@
+//! Handlers for the 16 ISA IRQ lines.
+//!
+//! IRQ `n` is delivered on vector `PIC_1_OFFSET + n` by both the legacy PIC and the I/O APIC, to a
+//! stub that counts it, runs the handlers registered for the line and signals the end of interrupt
+//! to whichever controller is in use. Lines can be shared by several handlers, and are masked
+//! while they have none.
+use super::{PIC_1_OFFSET, PICS, apic};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering::*};
+use spinlock::{DisableInterrupts, SpinLock};
+use x86_64::{
+    instructions::{interrupts::without_interrupts, port::Port},
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+/// The number of ISA IRQ lines.
+pub const IRQ_COUNT: u8 = 16;
+/// The IRQ of the Programmable Interval Timer.
+pub const TIMER_IRQ: u8 = 0;
+/// The IRQ of the PS/2 keyboard.
+pub const KEYBOARD_IRQ: u8 = 1;
+/// The line of the master PIC the slave PIC cascades through.
+const CASCADE_IRQ: u8 = 2;
+/// The command ports of the master and slave PIC.
+const PIC_COMMAND_PORTS: [u16; 2] = [0x20, 0xa0];
+/// The OCW3 command making the next read of the command port return the in-service register.
+const PIC_READ_ISR: u8 = 0x0b;
+
+/// Identifies a registered handler, to unregister it with [unregister_irq].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId(u64);
+
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Handler {
+    id: HandlerId,
+    run: fn(),
+}
+
+struct Line {
+    handlers: SpinLock<Vec<Handler>, DisableInterrupts>,
+    count: AtomicU64,
+    masked: AtomicBool,
+    /// Whether the line's device is masked at the controller, see [silence_irq].
+    silenced: AtomicBool,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Line {
+            handlers: SpinLock::disable_interrupts(Vec::new()),
+            count: AtomicU64::new(0),
+            masked: AtomicBool::new(true),
+            silenced: AtomicBool::new(false),
+        }
+    }
+}
+
+static LINES: [Line; IRQ_COUNT as usize] = [const { Line::new() }; IRQ_COUNT as usize];
+
+fn line(irq: u8) -> &'static Line {
+    assert!(irq < IRQ_COUNT, "Invalid IRQ {irq}");
+    &LINES[irq as usize]
+}
+
+/// Returns the vector IRQ `irq` is delivered on.
+pub const fn irq_vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+extern "x86-interrupt" fn stub<const IRQ: u8>(stack_frame: InterruptStackFrame) {
+    dispatch(IRQ, &stack_frame);
+}
+
+/// The stub of every line, by IRQ.
+const STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT as usize] = [
+    stub::<0>, stub::<1>, stub::<2>, stub::<3>, stub::<4>, stub::<5>, stub::<6>, stub::<7>,
+    stub::<8>, stub::<9>, stub::<10>, stub::<11>, stub::<12>, stub::<13>, stub::<14>, stub::<15>,
+];
+
+/// Point the vectors of all lines at their stubs.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    for (irq, &stub) in (0..).zip(STUBS.iter()) {
+        idt[irq_vector(irq)].set_handler_fn(stub);
+    }
+}
+
+fn dispatch(irq: u8, stack_frame: &InterruptStackFrame) {
+    if !apic::is_enabled() && spurious_pic_interrupt(irq) {
+        return;
+    }
+    let line = &LINES[irq as usize];
+    line.count.fetch_add(1, Relaxed);
+    for handler in line.handlers.lock().iter() {
+        (handler.run)();
+    }
+    end_of_interrupt(irq);
+    super::deferred::interrupt_exit();
+    crate::thread::preempt();
+    crate::process::exit_if_killed(stack_frame);
+}
+
+/// Signal the end of IRQ `irq` to the active interrupt controller.
+fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(irq_vector(irq)) };
+    }
+}
+
+/// Returns whether IRQ `irq` is a spurious interrupt of the legacy PIC, which must be neither
+/// handled nor acknowledged. The master still gets the end of interrupt of the cascade line if it
+/// came from the slave.
+fn spurious_pic_interrupt(irq: u8) -> bool {
+    let mut pics = PICS.lock();
+    if !is_spurious(irq) {
+        return false;
+    }
+    if irq >= 8 {
+        unsafe { pics.notify_end_of_interrupt(irq_vector(CASCADE_IRQ)) };
+    }
+    true
+}
+
+/// Returns whether IRQ `irq` is a spurious interrupt of the legacy PIC. Those are raised on the
+/// lowest priority line of each PIC, 7 and 15, without setting its bit in the in-service register.
+/// The PIC lock must be held.
+fn is_spurious(irq: u8) -> bool {
+    if irq % 8 != 7 {
+        return false;
+    }
+    let mut command = Port::<u8>::new(PIC_COMMAND_PORTS[irq as usize / 8]);
+    unsafe {
+        command.write(PIC_READ_ISR);
+        command.read() & 1 << 7 == 0
+    }
+}
+
+/// Run `run` whenever IRQ `irq` fires, unmasking the line if it had no handlers yet. Handlers
+/// run in interrupt context, before the end of interrupt is signalled, and must not register or
+/// unregister handlers of their own line. Anything more than acknowledging the device should be
+/// [deferred](super::deferred::defer).
+pub fn register_irq(irq: u8, run: fn()) -> HandlerId {
+    let line = line(irq);
+    let id = HandlerId(NEXT_HANDLER_ID.fetch_add(1, Relaxed));
+    let first = {
+        let mut handlers = line.handlers.lock();
+        handlers.push(Handler { id, run });
+        handlers.len() == 1
+    };
+    if first {
+        unmask_irq(irq);
+    }
+    id
+}
+
+/// Remove a handler added by [register_irq], masking the line if it was the last one. Returns
+/// false if the handler isn't registered for `irq`.
+pub fn unregister_irq(irq: u8, id: HandlerId) -> bool {
+    let line = line(irq);
+    let (removed, empty) = {
+        let mut handlers = line.handlers.lock();
+        let len = handlers.len();
+        handlers.retain(|handler| handler.id != id);
+        (handlers.len() != len, handlers.is_empty())
+    };
+    if removed && empty {
+        mask_irq(irq);
+    }
+    removed
+}
+
+/// Stop delivering IRQ `irq` until it's unmasked.
+pub fn mask_irq(irq: u8) {
+    line(irq).masked.store(true, Relaxed);
+    if apic::is_enabled() {
+        apic::mask_isa_irq(irq);
+    } else {
+        write_pic_masks();
+    }
+}
+
+/// Deliver IRQ `irq` again. A [silenced](silence_irq) line stays masked at the controller.
+pub fn unmask_irq(irq: u8) {
+    let line = line(irq);
+    line.masked.store(false, Relaxed);
+    if line.silenced.load(Relaxed) {
+        return;
+    }
+    if apic::is_enabled() {
+        apic::route_isa_irq(irq, irq_vector(irq));
+    } else {
+        write_pic_masks();
+    }
+}
+
+/// Mask the device on IRQ `irq` at the interrupt controller for good, while its handlers keep
+/// running for another source of the line's vector, like the local APIC timer on [TIMER_IRQ].
+pub fn silence_irq(irq: u8) {
+    line(irq).silenced.store(true, Relaxed);
+    if apic::is_enabled() {
+        apic::mask_isa_irq(irq);
+    } else {
+        write_pic_masks();
+    }
+}
+
+/// Returns whether the device on `line` can raise interrupts.
+fn is_open(line: &Line) -> bool {
+    !line.masked.load(Relaxed) && !line.silenced.load(Relaxed)
+}
+
+/// Update the masks of the legacy PIC to match the masked lines.
+pub(super) fn write_pic_masks() {
+    let masks = (0..IRQ_COUNT)
+        .filter(|&irq| !is_open(&LINES[irq as usize]))
+        .fold(0u16, |masks, irq| masks | (1 << irq));
+    // The slave PIC needs the cascade line to get through.
+    let masks = if masks >> 8 != 0xff {
+        masks & !(1 << CASCADE_IRQ)
+    } else {
+        masks
+    };
+    // The PIC lock is taken by interrupt handlers.
+    without_interrupts(|| unsafe { PICS.lock().write_masks(masks as u8, (masks >> 8) as u8) });
+}
+
+/// Returns the unmasked lines, with the vectors they are delivered on.
+pub(super) fn unmasked() -> Vec<(u8, u8)> {
+    (0..IRQ_COUNT)
+        .filter(|&irq| is_open(&LINES[irq as usize]))
+        .map(|irq| (irq, irq_vector(irq)))
+        .collect()
+}
+
+/// The state of an IRQ line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqStats {
+    pub irq: u8,
+    /// How many times the line fired.
+    pub count: u64,
+    /// The number of registered handlers.
+    pub handlers: usize,
+    pub masked: bool,
+    /// Whether the line's device is masked at the controller, see [silence_irq].
+    pub silenced: bool,
+}
+
+/// Returns the state of every line.
+pub fn irq_stats() -> impl Iterator<Item = IrqStats> {
+    (0..IRQ_COUNT).map(|irq| {
+        let line = &LINES[irq as usize];
+        IrqStats {
+            irq,
+            count: line.count.load(Relaxed),
+            handlers: line.handlers.lock().len(),
+            masked: line.masked.load(Relaxed),
+            silenced: line.silenced.load(Relaxed),
+        }
+    })
+}
@@ -5,15 +5,22 @@
 #![test_runner(crate::test::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 extern crate alloc;
+pub mod acpi;
+pub mod backtrace;
 pub mod clock;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
 pub mod panic;
+pub mod power;
+pub mod process;
 pub mod qemu;
 pub mod serial;
+pub mod smp;
 pub mod task;
 pub mod test;
+pub mod thread;
+pub mod user;
 pub mod vga;
 pub mod prelude {
     pub use crate::serial::SERIAL1;
@@ -35,7 +42,14 @@ pub fn init(boot_info: &'static BootInfo) {
     vga::init();
     interrupts::init();
     memory::init(boot_info);
+    gdt::init_stacks();
+    interrupts::init_apic();
+    clock::init();
     task::init();
+    smp::init();
+    thread::init();
+    interrupts::deferred::init();
+    user::init();
 }
 
 /// Redirect panic output to QEMU's serial/stdout.
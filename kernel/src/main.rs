@@ -7,8 +7,9 @@ extern crate alloc;
 mod flappy;
 mod snek;
 
-use alloc::{format, string::String};
+use alloc::{format, string::String, vec::Vec};
 use bootloader::{BootInfo, entry_point};
+use core::fmt::Debug;
 use futures::{FutureExt, select_biased};
 use futures_util::StreamExt;
 use kernel::{
@@ -19,6 +20,7 @@ use kernel::{
         keyboard::KeypressStream,
         timer::Interval,
     },
+    thread::JoinHandle,
     vga::{BUFFER_HEIGHT, BUFFER_WIDTH, ScreenChar},
 };
 use pc_keyboard::{DecodedKey, KeyCode, KeyEvent, KeyState};
@@ -27,9 +29,147 @@ use spinlock::LazyStatic;
 const HELP_MESSAGE: &str = "Available commands:
 snake - run snake
 flappy / fb - run flappy bird
-exit - exit the shell
+acpi - list the ACPI tables
+date - show the current date and time
+user - run a demo program in user mode
+exec <program> [args] [&] - run an embedded ELF program, in the background with &
+    Ctrl-C kills the program running in the foreground
+ps - list the processes
+kill <pid> - kill a process
+irqs - show the interrupt counts of the IRQ lines
+shutdown - power off
+reboot - restart the machine
+exit - exit the shell and power off
 help / ? - show this help message";
 
+/// Wait for a program run in the foreground to exit without blocking the executor, killing its
+/// process when Ctrl-C is pressed. Other keys pressed in the meantime are dropped.
+async fn wait_foreground<E: Debug>(
+    keypresses: &mut KeypressStream,
+    handle: JoinHandle<Result<i64, E>>,
+) -> String {
+    let mut interval = Interval::new(10);
+    while !handle.is_finished() {
+        let key = select_biased! {
+            _ = interval.tick().fuse() => continue,
+            key = keypresses.next() => key,
+        };
+        let ctrl_c = matches!(
+            key,
+            Some((
+                KeyEvent {
+                    code: KeyCode::C,
+                    state: KeyState::Down
+                },
+                _
+            ))
+        ) && keypresses.keyboard.get_modifiers().is_ctrl();
+        if let Some(pid) = handle.thread().process().filter(|_| ctrl_c) {
+            kernel::process::kill(pid);
+        }
+    }
+    match handle.join() {
+        Ok(code) => format!("The program exited with code {code}"),
+        Err(err) => format!("Failed to load the program: {err:?}"),
+    }
+}
+
+/// Run the embedded program named by the first argument of an `exec` command line, passing it the
+/// arguments. A trailing `&` runs it in the background, otherwise Ctrl-C kills it.
+async fn exec(keypresses: &mut KeypressStream, command: &str) -> String {
+    use kernel::user::{programs, spawn_exec};
+
+    let mut argv: Vec<String> = command
+        .split_whitespace()
+        .skip(1)
+        .map(String::from)
+        .collect();
+    let background = argv.last().is_some_and(|arg| arg == "&");
+    if background {
+        argv.pop();
+    }
+    let Some(name) = argv.first() else {
+        let names: Vec<&str> = programs::PROGRAMS.iter().map(|&(name, _)| name).collect();
+        return format!(
+            "Usage: exec <program> [args] [&]\nPrograms: {}",
+            names.join(", ")
+        );
+    };
+    let Some(elf) = programs::get(name) else {
+        return format!("No program called {name}");
+    };
+    let handle = spawn_exec(elf, argv, Vec::new());
+    if background {
+        return String::from("Started the program in the background, see ps");
+    }
+    wait_foreground(keypresses, handle).await
+}
+
+/// List the processes in the process table.
+fn processes() -> String {
+    use core::fmt::Write;
+    use kernel::process::{self, State};
+
+    let mut out = String::from("PID   PPID  STATE       NAME");
+    for process in process::list() {
+        let parent = process
+            .parent()
+            .map_or(String::from("-"), |pid| format!("{pid}"));
+        let state = match process.state() {
+            State::Running if process.is_killed() => String::from("killed"),
+            State::Running => String::from("running"),
+            State::Exited(code) => format!("exited {code}"),
+        };
+        let _ = write!(
+            out,
+            "\n{:<5} {parent:<5} {state:<11} {}",
+            process.pid(),
+            process.name()
+        );
+    }
+    out
+}
+
+/// Kill the process with the PID given as the argument of a `kill` command line.
+fn kill(command: &str) -> String {
+    use kernel::process::{self, Pid};
+
+    let Some(pid) = command
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pid| pid.parse().ok())
+        .and_then(Pid::from_u64)
+    else {
+        return String::from("Usage: kill <pid>");
+    };
+    if process::kill(pid) {
+        format!("Killed process {pid}")
+    } else {
+        format!("No running process with PID {pid}")
+    }
+}
+
+/// List the IRQ lines with their interrupt counts.
+fn irqs() -> String {
+    use core::fmt::Write;
+    use kernel::interrupts::{self, IrqStats};
+
+    let mut out = String::from("IRQ  COUNT       HANDLERS  STATE");
+    for line in interrupts::irq_stats() {
+        let state = match line {
+            IrqStats { masked: true, .. } => "masked",
+            IrqStats { silenced: true, .. } => "silenced",
+            _ => "enabled",
+        };
+        let _ = write!(
+            out,
+            "\n{:<4} {:<11} {:<9} {state}",
+            line.irq, line.count, line.handlers
+        );
+    }
+    out
+}
+
 fn split_lines_and_wrap(text: &[u8], width: usize) -> impl DoubleEndedIterator<Item = &[u8]> {
     let lines = text.split(|&b| b == b'\n');
     lines.flat_map(move |line| {
@@ -40,6 +180,28 @@ fn split_lines_and_wrap(text: &[u8], width: usize) -> impl DoubleEndedIterator<I
     })
 }
 
+/// List the ACPI tables found, including the DSDT.
+fn acpi_tables() -> String {
+    use core::fmt::Write;
+    use kernel::acpi;
+
+    if !acpi::is_available() {
+        return String::from("No ACPI tables found");
+    }
+    let dsdt = acpi::Fadt::get().and_then(|fadt| fadt.dsdt_table());
+    let mut out = String::from("ACPI tables:");
+    for table in acpi::tables().chain(dsdt) {
+        let (revision, length) = (table.revision, table.length);
+        let oem = core::str::from_utf8(&table.oem_id).unwrap_or("?");
+        _ = write!(
+            out,
+            "\n{} rev {revision} {length:>6} bytes OEM {oem}",
+            table.signature_str(),
+        );
+    }
+    out
+}
+
 async fn main() {
     async fn print_mem_stats() {
         let mut timer = Interval::new(1000);
@@ -122,6 +284,34 @@ async fn shell() {
                     "snek" | "snake" => snek::run().await,
                     "flappy" | "fb" => flappy::run().await,
                     "help" | "?" => print_and_wait_for_input(&mut keypresses, HELP_MESSAGE).await,
+                    "acpi" => print_and_wait_for_input(&mut keypresses, &acpi_tables()).await,
+                    "date" => {
+                        let date = match kernel::clock::WallClock::now() {
+                            Some(now) => format!("{now}"),
+                            None => String::from("The wall clock is not available"),
+                        };
+                        print_and_wait_for_input(&mut keypresses, &date).await
+                    }
+                    "user" => {
+                        print(b"");
+                        let handle = kernel::user::spawn(kernel::user::demo());
+                        let text = wait_foreground(&mut keypresses, handle).await;
+                        println!("{text}\nPress any button to return to shell.");
+                        while !matches!(keypresses.next().await, Some((_, Some(_)))) {}
+                    }
+                    command if command.split_whitespace().next() == Some("exec") => {
+                        print(b"");
+                        let text = exec(&mut keypresses, command).await;
+                        println!("{text}\nPress any button to return to shell.");
+                        while !matches!(keypresses.next().await, Some((_, Some(_)))) {}
+                    }
+                    "ps" => print_and_wait_for_input(&mut keypresses, &processes()).await,
+                    "irqs" => print_and_wait_for_input(&mut keypresses, &irqs()).await,
+                    command if command.split_whitespace().next() == Some("kill") => {
+                        print_and_wait_for_input(&mut keypresses, &kill(command)).await
+                    }
+                    "shutdown" => kernel::power::shutdown(),
+                    "reboot" => kernel::power::reboot(),
                     "exit" => return,
                     _ => print_and_wait_for_input(&mut keypresses, "No such command, type").await,
                 }
@@ -151,6 +341,5 @@ fn entrypoint(boot_info: &'static BootInfo) -> ! {
     executor.run();
 
     println!(fgcolor = LightCyan, "Async executor exited successfully.");
-    println!(fgcolor = White, "Please shut down the system.");
-    kernel::hlt_loop()
+    kernel::power::shutdown()
 }
@@ -2,20 +2,81 @@ pub mod bump_alloc;
 pub mod frame_alloc;
 pub mod freelist_alloc;
 pub mod global_alloc;
+mod retire;
+pub mod slab_alloc;
+pub mod stack;
+pub mod vmm;
 use bootloader::BootInfo;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering::*};
+use frame_alloc::BitmapFrameAllocator;
+use vmm::{VMM, Vmm, VmmLock};
 use x86_64::{
-    VirtAddr,
-    structures::paging::{PageTable, mapper::OffsetPageTable},
+    PhysAddr, VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame, mapper::OffsetPageTable},
 };
 
-/// Initialize the ALLOCATOR.
+/// The virtual address at which the bootloader mapped all of physical memory.
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// The physical address of the kernel's level 4 table.
+static KERNEL_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Initialize the VMM and the ALLOCATOR.
 pub fn init(boot_info: &'static BootInfo) {
+    PHYS_OFFSET.store(boot_info.physical_memory_offset, Release);
     let phys_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { get_table(phys_offset) };
-    let mut frame_alloc =
-        unsafe { frame_alloc::BootInfoFrameAllocator::new(&boot_info.memory_map) };
-    global_alloc::init_heap(&mut mapper, &mut frame_alloc).unwrap();
+    let mapper = unsafe { get_table(phys_offset) };
+    let frame_alloc = unsafe {
+        BitmapFrameAllocator::new(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    VMM.insert_if_uninit(VmmLock::new(Vmm::new(mapper, frame_alloc)))
+        .unwrap_or_else(|_| panic!("memory::init called more than once"));
+    global_alloc::init_heap().unwrap();
+}
+
+/// Returns the frame of the kernel's level 4 table, which kernel threads run on.
+pub fn kernel_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_TABLE.load(Acquire)))
+}
+
+/// Load `table` into CR3, unless it is already the active level 4 table.
+pub fn activate_table(table: PhysFrame) {
+    let (active, flags) = Cr3::read();
+    if active != table {
+        unsafe { Cr3::write(table, flags) };
+    }
+}
+
+/// Returns the virtual address through which the given physical address can be accessed.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYS_OFFSET.load(Acquire) + phys.as_u64())
+}
+
+/// Returns whether `addr` is mapped in the active page table. Walks the table directly instead of
+/// going through the [VMM], so it can be used while the VMM may be locked, like when panicking.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    if PHYS_OFFSET.load(Acquire) == 0 {
+        return false;
+    }
+    let (mut frame, _) = Cr3::read();
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (level, index) in indices.into_iter().enumerate() {
+        let table = unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<PageTable>() };
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if level > 0 && flags.contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        frame = PhysFrame::containing_address(table[index].addr());
+    }
+    true
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -32,11 +93,10 @@ unsafe fn get_table(phys_offset: VirtAddr) -> OffsetPageTable<'static> {
         !CALLED.swap(true, core::sync::atomic::Ordering::AcqRel),
         "get_table called more than once"
     );
-    use x86_64::registers::control::Cr3;
-
     let (level_4_table_frame, _) = Cr3::read();
 
     let phys = level_4_table_frame.start_address();
+    KERNEL_TABLE.store(phys.as_u64(), Release);
     let virt = phys_offset + phys.as_u64();
     let page_table: *mut PageTable = virt.as_mut_ptr();
 
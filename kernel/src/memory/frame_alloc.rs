@@ -1,38 +1,276 @@
+use super::retire::RetireBatches;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
     PhysAddr,
-    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB, frame::PhysFrameRange,
+    },
 };
 
-pub struct BootInfoFrameAllocator {
-    memory: &'static MemoryMap,
+const FRAME_SIZE: u64 = 4096;
+const BITS: usize = u64::BITS as usize;
+
+/// A physical frame allocator that tracks the state of every frame with a single bit, and the
+/// number of mappings sharing every allocated frame.
+///
+/// A set bit marks a frame as used. Frames that are not `Usable` in the boot memory map are
+/// permanently marked as used, so they can never be handed out.
+///
+/// Frames that were unmapped are [retired](Self::retire_frame) rather than freed, and stay marked
+/// as used until every CPU has flushed them from its TLB.
+///
+/// The global instance is owned by the [VMM](super::vmm::VMM).
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// The reference count of every frame. Frames are freed once their count drops to 0.
+    refcounts: &'static mut [u16],
+    /// The frames in each batch of retired frames.
+    retired: [&'static mut [u64]; 2],
+    retired_batches: RetireBatches,
+    /// The number of usable frames managed by the allocator.
+    total: usize,
+    /// The number of frames currently available for allocation.
+    free: usize,
+    /// Word index to start searching for free frames from.
     next: usize,
 }
 
-impl BootInfoFrameAllocator {
+/// A snapshot of the physical frame usage.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub used_frames: usize,
+    /// Frames that were freed, but may still be cached in the TLB of another CPU.
+    pub retired_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Build a frame allocator from the boot memory map. The bitmaps and reference counts are
+    /// stored in the first usable region large enough to hold them, and those frames are marked
+    /// as used.
+    ///
     /// # Safety
     /// The passed memory map must be valid. The main requirement is
     /// that all frames that are marked as `USABLE` in it are really unused.
-    pub unsafe fn new(memory: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator { memory, next: 0 }
+    /// All of physical memory must be mapped at `phys_offset`.
+    pub unsafe fn new(memory: &'static MemoryMap, phys_offset: u64) -> Self {
+        let usable = || {
+            memory
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| r.range.start_frame_number as usize..r.range.end_frame_number as usize)
+        };
+        let frame_count = usable().map(|r| r.end).max().unwrap_or(0);
+        let words = frame_count.div_ceil(BITS);
+        let bitmap_size = words * size_of::<u64>();
+        let metadata_size = bitmap_size * 3 + frame_count * size_of::<u16>();
+        let bitmap_frames = metadata_size.div_ceil(FRAME_SIZE as usize);
+        let bitmap_start = usable()
+            .find(|r| r.len() >= bitmap_frames)
+            .expect("No usable memory region is large enough to hold the frame bitmap")
+            .start;
+
+        let bitmap_addr = phys_offset + bitmap_start as u64 * FRAME_SIZE;
+        let bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap_addr as *mut u64, words) };
+        bitmap.fill(u64::MAX);
+        let retired = [1, 2].map(|i| {
+            let addr = bitmap_addr + (i * bitmap_size) as u64;
+            let retired = unsafe { core::slice::from_raw_parts_mut(addr as *mut u64, words) };
+            retired.fill(0);
+            retired
+        });
+        let refcounts_addr = bitmap_addr + 3 * bitmap_size as u64;
+        let refcounts =
+            unsafe { core::slice::from_raw_parts_mut(refcounts_addr as *mut u16, frame_count) };
+        refcounts.fill(0);
+
+        let mut alloc = BitmapFrameAllocator {
+            bitmap,
+            refcounts,
+            retired,
+            retired_batches: RetireBatches::new(),
+            total: 0,
+            free: 0,
+            next: 0,
+        };
+        for range in usable() {
+            alloc.total += range.len();
+            range.for_each(|frame| alloc.clear(frame));
+        }
+        alloc.free = alloc.total;
+        (bitmap_start..bitmap_start + bitmap_frames).for_each(|frame| alloc.mark_used(frame));
+        alloc
     }
-}
-impl BootInfoFrameAllocator {
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory.iter();
-        let usable = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable
-            .map(|r| r.range)
-            .map(|r| r.start_addr()..r.end_addr());
-        let frame_addrs = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addrs.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+    fn set(&mut self, frame: usize) {
+        self.bitmap[frame / BITS] |= 1 << (frame % BITS);
+    }
+    fn clear(&mut self, frame: usize) {
+        self.bitmap[frame / BITS] &= !(1 << (frame % BITS));
+    }
+    fn mark_used(&mut self, frame: usize) {
+        debug_assert!(!self.is_used(frame));
+        self.set(frame);
+        self.refcounts[frame] = 1;
+        self.free -= 1;
+    }
+    /// Drop a reference to the frame, returning whether it was the last one.
+    fn unref(&mut self, frame: usize) -> bool {
+        assert!(
+            self.is_used(frame) && self.refcounts.get(frame).is_none_or(|&rc| rc != 0),
+            "Attempted to free frame {:#x} which is not allocated",
+            frame as u64 * FRAME_SIZE
+        );
+        match self.refcounts.get_mut(frame) {
+            Some(refcount) => {
+                *refcount -= 1;
+                *refcount == 0
+            }
+            None => true,
+        }
+    }
+    /// Drop a reference to the frame, freeing it if it was the last one.
+    fn release(&mut self, frame: usize) {
+        if !self.unref(frame) {
+            return;
+        }
+        self.clear(frame);
+        self.free += 1;
+        self.next = self.next.min(frame / BITS);
+    }
+    /// Drop a reference to the frame, retiring it if it was the last one.
+    fn retire(&mut self, frame: usize) {
+        if self.unref(frame) {
+            let batch = self.retired_batches.retire(1);
+            self.retired[batch][frame / BITS] |= 1 << (frame % BITS);
+        }
+    }
+    /// Drop a reference to a frame that was unmapped. Once no references are left, the frame is
+    /// only freed after the next [TLB shootdown](crate::smp::flush_remote_tlbs) completed.
+    ///
+    /// # Safety
+    /// The caller must ensure that the frame is no longer mapped through this reference, and
+    /// start a TLB shootdown afterwards.
+    pub unsafe fn retire_frame(&mut self, frame: PhysFrame) {
+        self.retire(Self::frame_index(frame));
+    }
+    /// Drop a reference to every frame in a range that was unmapped, see [Self::retire_frame].
+    ///
+    /// # Safety
+    /// See [Self::retire_frame].
+    pub unsafe fn retire_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.retire(Self::frame_index(frame));
+        }
+    }
+    /// Free the retired frames every CPU has flushed from its TLB.
+    pub fn reclaim(&mut self) {
+        let Some(batch) = self.retired_batches.reclaimable() else {
+            return;
+        };
+        for word in 0..self.bitmap.len() {
+            let retired = core::mem::take(&mut self.retired[batch][word]);
+            if retired != 0 {
+                self.bitmap[word] &= !retired;
+                self.free += retired.count_ones() as usize;
+                self.next = self.next.min(word);
+            }
+        }
+    }
+    /// Register another mapping sharing an allocated frame. The frame is only freed once
+    /// [FrameDeallocator::deallocate_frame] has been called for every reference.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let refcount = &mut self.refcounts[Self::frame_index(frame)];
+        assert_ne!(
+            *refcount, 0,
+            "Attempted to share a frame which is not allocated"
+        );
+        *refcount = refcount
+            .checked_add(1)
+            .expect("Frame reference count overflowed");
+    }
+    /// Returns the number of references to the frame, 0 if it is free or not managed by the
+    /// allocator.
+    pub fn refcount(&self, frame: PhysFrame) -> u16 {
+        self.refcounts
+            .get(Self::frame_index(frame))
+            .copied()
+            .unwrap_or(0)
+    }
+    fn frame_index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+    fn index_frame(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+    /// Allocate `count` physically contiguous frames, with the first frame aligned to
+    /// `align` frames.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        self.reclaim();
+        if count == 0 || count > self.free {
+            return None;
+        }
+        let align = align.max(1);
+        let frame_count = self.bitmap.len() * BITS;
+        let mut start = 0;
+        while start + count <= frame_count {
+            match (start..start + count).rfind(|&frame| self.is_used(frame)) {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    (start..start + count).for_each(|frame| self.mark_used(frame));
+                    return Some(PhysFrame::range(
+                        Self::index_frame(start),
+                        Self::index_frame(start + count),
+                    ));
+                }
+            }
+        }
+        None
+    }
+    /// Drop a reference to every frame in a range previously returned by
+    /// [Self::allocate_contiguous].
+    ///
+    /// # Safety
+    /// The caller must ensure that none of the frames are still in use through this reference.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.release(Self::frame_index(frame));
+        }
+    }
+    pub fn stats(&self) -> FrameStats {
+        let retired = self.retired_batches.len();
+        FrameStats {
+            total_frames: self.total,
+            free_frames: self.free,
+            used_frames: self.total - self.free - retired,
+            retired_frames: retired,
+        }
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        self.reclaim();
+        if self.free == 0 {
+            return None;
+        }
+        let words = self.bitmap.len();
+        let word = (self.next..words)
+            .chain(0..self.next)
+            .find(|&word| self.bitmap[word] != u64::MAX)?;
+        self.next = word;
+        let frame = word * BITS + self.bitmap[word].trailing_ones() as usize;
+        self.mark_used(frame);
+        Some(Self::index_frame(frame))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Drop a reference to the frame, freeing it once no references are left.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.release(Self::frame_index(frame));
     }
 }
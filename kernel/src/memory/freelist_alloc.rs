@@ -4,11 +4,48 @@ use core::{
     ptr::{self, NonNull},
 };
 use spinlock::{DisableInterrupts, SpinLock};
+
+use super::slab_alloc::{SIZE_CLASSES, SizeClassStats};
+
+#[cfg(feature = "heap_debug")]
+mod debug;
+#[cfg(feature = "heap_debug")]
+pub use debug::HeapError;
+
+/// Once the free region at the end of a growable heap reaches this many [HeapGrowth::step]s, the
+/// memory backing it is released through [HeapGrowth::shrink].
+const TRIM_STEPS: usize = 2;
+
 /// A fairly simple FreeList-backed heap allocator.
 pub struct FreeListAlloc {
     total_mem: usize,
     allocs: usize,
     head: ListNode,
+    /// The end of the memory currently backing the heap.
+    heap_end: usize,
+    /// The heap is never shrunk below this address.
+    initial_end: usize,
+    growth: Option<HeapGrowth>,
+    #[cfg(feature = "heap_debug")]
+    debug: debug::DebugState,
+}
+
+/// Hooks used by a [FreeListAlloc] to request more memory past the end of its heap when no free
+/// region can satisfy an allocation.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapGrowth {
+    /// Makes `len` bytes starting at `addr` usable, returning whether it succeeded.
+    /// Both `addr` and `len` are multiples of [Self::step].
+    pub grow: fn(addr: usize, len: usize) -> bool,
+    /// Releases the `len` bytes starting at `addr` which are no longer used by the heap.
+    /// Both `addr` and `len` are multiples of [Self::step]. The heap is never shrunk if this is
+    /// `None`.
+    pub shrink: Option<fn(addr: usize, len: usize)>,
+    /// The address the heap will never grow past.
+    pub limit: usize,
+    /// The granularity the heap grows and shrinks by, a power of two multiple of the page size
+    /// the end of the initial heap is aligned to.
+    pub step: usize,
 }
 
 /// Represents a free region of memory in the freelist.
@@ -38,6 +75,11 @@ impl FreeListAlloc {
             head: ListNode::new(0),
             total_mem: 0,
             allocs: 0,
+            heap_end: 0,
+            initial_end: 0,
+            growth: None,
+            #[cfg(feature = "heap_debug")]
+            debug: debug::DebugState::new(),
         }
     }
     /// # Safety
@@ -48,17 +90,170 @@ impl FreeListAlloc {
             self.add_free_region(start, size);
         }
         self.total_mem = size;
+        self.heap_end = start + size;
+        self.initial_end = self.heap_end;
+        #[cfg(feature = "heap_debug")]
+        {
+            self.debug.heap_start = start;
+        }
+    }
+    /// Allow the heap to grow past its initial size through the provided hooks.
+    ///
+    /// # Safety
+    /// The memory made usable by [HeapGrowth::grow] must be valid for the allocator to freely
+    /// create mutable references into, up to [HeapGrowth::limit].
+    pub unsafe fn set_growth(&mut self, growth: HeapGrowth) {
+        self.growth = Some(growth);
+    }
+    /// Set the address the heap will never grow past.
+    pub fn set_limit(&mut self, limit: usize) {
+        if let Some(growth) = &mut self.growth {
+            growth.limit = limit;
+        }
     }
     /// Release a region of memory to the allocator and register a dealloction in the allocation
-    /// counter.
-    unsafe fn dealloc(&mut self, addr: usize, size: usize) {
+    /// counter. `layout` is the layout it was allocated with.
+    pub(super) unsafe fn dealloc(&mut self, addr: usize, layout: Layout) {
+        #[cfg(feature = "heap_debug")]
+        unsafe {
+            self.debug_dealloc(addr, layout)
+        }
+        #[cfg(not(feature = "heap_debug"))]
+        unsafe {
+            self.dealloc_raw(addr, Self::prepare_layout(layout).size())
+        }
+    }
+    /// Release a region of memory without any debug checks.
+    unsafe fn dealloc_raw(&mut self, addr: usize, size: usize) {
         self.allocs -= 1;
+        unsafe { self.release(addr, size) };
+    }
+    /// Release a region of memory to the allocator, shrinking the heap if this leaves a large
+    /// enough free region at its end.
+    unsafe fn release(&mut self, addr: usize, size: usize) {
+        let node = unsafe { &mut *self.add_free_region(addr, size) };
+        let Some(growth) = self.growth else {
+            return;
+        };
+        if node.end_addr() == self.heap_end && node.size >= TRIM_STEPS * growth.step {
+            self.trim_tail(node);
+        }
+    }
+    /// Attempt to resize the allocation at `addr` without moving it, by either taking memory from
+    /// the free region directly after it or returning its tail to the free list.
+    /// Both sizes must be prepared with [Self::prepare_layout].
+    ///
+    /// # Safety
+    /// `addr` and `old_size` must describe a live allocation made by this allocator.
+    pub(super) unsafe fn realloc_in_place(
+        &mut self,
+        addr: usize,
+        old_size: usize,
+        new_size: usize,
+    ) -> bool {
+        const MIN: usize = mem::size_of::<ListNode>();
+        if cfg!(feature = "heap_debug") {
+            // Moving the back red zone is not worth the complexity in debug mode
+            return false;
+        }
+        if new_size <= old_size {
+            let tail = old_size - new_size;
+            if tail == 0 {
+                return true;
+            }
+            if tail < MIN {
+                return false;
+            }
+            unsafe { self.release(addr + new_size, tail) };
+            return true;
+        }
+        let end = addr + old_size;
+        let extra = new_size - old_size;
+        let mut cur = &mut self.head;
+        while let Some(mut region) = cur.next {
+            let region = unsafe { region.as_mut() };
+            if region.start_addr() < end {
+                cur = region;
+                continue;
+            }
+            if region.start_addr() != end || region.size < extra {
+                return false;
+            }
+            let remaining = region.size - extra;
+            let next = region.next.take();
+            if remaining == 0 {
+                cur.next = next;
+                return true;
+            }
+            if remaining < MIN {
+                region.next = next;
+                return false;
+            }
+            let node_ptr = NonNull::new((end + extra) as *mut ListNode).unwrap();
+            unsafe {
+                node_ptr.write(ListNode {
+                    size: remaining,
+                    next,
+                })
+            };
+            cur.next = Some(node_ptr);
+            return true;
+        }
+        false
+    }
+    /// Release all fully-free pages at the end of the heap, never shrinking it below its initial
+    /// size.
+    pub fn trim(&mut self) {
+        let mut last = &mut self.head;
+        while let Some(mut node) = last.next {
+            last = unsafe { node.as_mut() };
+        }
+        if last.size != 0 && last.end_addr() == self.heap_end {
+            let last = last as *mut ListNode;
+            self.trim_tail(unsafe { &mut *last });
+        }
+    }
+    /// Shrink the heap by releasing the steps covered by `tail`, the free region at the end of
+    /// the heap. The node itself is kept in the list.
+    fn trim_tail(&mut self, tail: &mut ListNode) {
+        let Some(growth) = self.growth else {
+            return;
+        };
+        let Some(shrink) = growth.shrink else {
+            return;
+        };
+        let cut = (tail.start_addr() + mem::size_of::<ListNode>())
+            .max(self.initial_end)
+            .next_multiple_of(growth.step);
+        if cut >= self.heap_end {
+            return;
+        }
+        let released = self.heap_end - cut;
+        tail.size -= released;
+        self.heap_end = cut;
+        self.total_mem -= released;
+        shrink(cut, released);
+    }
+    /// Attempt to grow the heap enough to fit an allocation of the given size and alignment.
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        let Some(growth) = self.growth else {
+            return false;
+        };
+        let needed = size + align + mem::size_of::<ListNode>();
+        let len = needed.next_multiple_of(growth.step);
+        let len = len.min(growth.limit.saturating_sub(self.heap_end));
+        if len < needed || !(growth.grow)(self.heap_end, len) {
+            return false;
+        }
         unsafe {
-            self.add_free_region(addr, size);
+            self.add_free_region(self.heap_end, len);
         }
+        self.heap_end += len;
+        self.total_mem += len;
+        true
     }
-    /// Release a region of memory to the allocator.
-    unsafe fn add_free_region(&mut self, addr: usize, mut size: usize) {
+    /// Release a region of memory to the allocator, returning the free node now containing it.
+    unsafe fn add_free_region(&mut self, addr: usize, mut size: usize) -> *mut ListNode {
         assert_eq!(
             addr.next_multiple_of(mem::align_of::<ListNode>()),
             addr,
@@ -90,7 +285,7 @@ impl FreeListAlloc {
         }
         if merge_left {
             closest_before.size += size;
-            return;
+            return closest_before;
         }
         let mut node = ListNode::new(size);
         node.next = closest_before.next.take();
@@ -101,13 +296,39 @@ impl FreeListAlloc {
             node_ptr.write(node);
             closest_before.next = Some(node_ptr);
         }
+        node_ptr.as_ptr()
     }
     /// Sets the total size of the memory region for the allocator. Only used for [Self::stats].
     pub fn set_total(&mut self, free: usize) {
         self.total_mem = free;
     }
-    /// Request sufficient memory for the given allocation from the allocator.
-    unsafe fn alloc(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+    /// Request sufficient memory for the given allocation from the allocator, growing the heap if
+    /// no free region is large enough.
+    pub(super) unsafe fn alloc(&mut self, layout: Layout) -> Option<*mut u8> {
+        #[cfg(feature = "heap_debug")]
+        unsafe {
+            self.debug_alloc(layout)
+        }
+        #[cfg(not(feature = "heap_debug"))]
+        unsafe {
+            let layout = Self::prepare_layout(layout);
+            self.alloc_raw(layout.size(), layout.align())
+        }
+    }
+    /// Allocate memory without any debug checks.
+    unsafe fn alloc_raw(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        unsafe {
+            if let Some(ptr) = self.alloc_in_free_list(size, align) {
+                return Some(ptr);
+            }
+            if !self.grow(size, align) {
+                return None;
+            }
+            self.alloc_in_free_list(size, align)
+        }
+    }
+    /// Walk the free list for a region that can fit the given allocation.
+    unsafe fn alloc_in_free_list(&mut self, size: usize, align: usize) -> Option<*mut u8> {
         let mut cur = &mut self.head as *mut ListNode;
         unsafe {
             while let Some(mut region) = (*cur).next {
@@ -172,7 +393,7 @@ impl FreeListAlloc {
             end_node,
         })
     }
-    fn prepare_layout(mut layout: Layout) -> Layout {
+    pub(super) fn prepare_layout(mut layout: Layout) -> Layout {
         layout = layout.align_to(mem::size_of::<ListNode>()).unwrap();
         layout.pad_to_align()
     }
@@ -191,6 +412,7 @@ impl FreeListAlloc {
             total: self.total_mem,
             used: self.total_mem.wrapping_sub(free_mem),
             allocations: self.allocs,
+            size_classes: SIZE_CLASSES.map(SizeClassStats::new),
         }
     }
 }
@@ -201,6 +423,9 @@ pub struct AllocStats {
     pub allocations: usize,
     pub total: usize,
     pub used: usize,
+    /// Statistics for the size classes of a [SlabAlloc](super::slab_alloc::SlabAlloc).
+    /// Left empty for a bare [FreeListAlloc].
+    pub size_classes: [SizeClassStats; SIZE_CLASSES.len()],
 }
 struct RegionAllocSplit {
     /// The address and length of the start free node
@@ -215,18 +440,34 @@ pub struct SpinLockFreelist(pub SpinLock<FreeListAlloc, DisableInterrupts>);
 
 unsafe impl GlobalAlloc for SpinLockFreelist {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let layout = FreeListAlloc::prepare_layout(layout);
         unsafe {
-            let Some(ptr) = self.0.lock().alloc(layout.size(), layout.align()) else {
+            let Some(ptr) = self.0.lock().alloc(layout) else {
                 return ptr::null_mut();
             };
             ptr
         }
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let layout = FreeListAlloc::prepare_layout(layout);
+        unsafe { self.0.lock().dealloc(ptr as usize, layout) };
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let old = FreeListAlloc::prepare_layout(layout);
+        let new = FreeListAlloc::prepare_layout(new_layout);
         unsafe {
-            self.0.lock().dealloc(ptr as usize, layout.size());
+            if self
+                .0
+                .lock()
+                .realloc_in_place(ptr as usize, old.size(), new.size())
+            {
+                return ptr;
+            }
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            new_ptr
         }
     }
 }
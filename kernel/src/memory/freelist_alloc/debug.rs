@@ -0,0 +1,310 @@
+//! Heap corruption and leak detection for the [FreeListAlloc], enabled by the `heap_debug` feature.
+//!
+//! Every allocation is surrounded by red zones filled with [CANARY] and preceded by an
+//! [AllocHeader] which links it into a list of live allocations:
+//! ```text
+//! [padding][AllocHeader][front red zone][user memory][back red zone]
+//! ```
+use super::{FreeListAlloc, ListNode};
+use crate::serial_println;
+use core::{alloc::Layout, fmt, mem, ptr, ptr::NonNull};
+
+const LIVE_MAGIC: u64 = 0xA110_CA7E_D0D0_CAFE;
+const FREED_MAGIC: u64 = 0xF4EE_D0D0_DEAD_CAFE;
+/// The byte pattern filling the red zones.
+const CANARY: u8 = 0xFD;
+/// The byte pattern filling freshly allocated memory.
+const POISON_ALLOCATED: u8 = 0xAA;
+/// The byte pattern filling freed memory.
+const POISON_FREED: u8 = 0xDD;
+const RED_ZONE: usize = 16;
+const HEADER: usize = mem::size_of::<AllocHeader>();
+
+/// Metadata stored in front of every allocation.
+///
+/// `magic` is the last field so it survives the [ListNode] written to the start of the freed
+/// region, allowing double frees to be told apart from invalid pointers.
+#[repr(C)]
+struct AllocHeader {
+    prev: Option<NonNull<AllocHeader>>,
+    next: Option<NonNull<AllocHeader>>,
+    /// The start of the underlying allocation.
+    base: usize,
+    /// The size of the underlying allocation.
+    raw_size: usize,
+    /// The size requested by the user.
+    size: usize,
+    align: usize,
+    magic: u64,
+}
+
+/// Misuse or corruption of an allocation, detected when it is freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapError {
+    DoubleFree {
+        addr: usize,
+    },
+    NotAllocated {
+        addr: usize,
+    },
+    HeaderOverwritten {
+        addr: usize,
+    },
+    /// The allocation was freed with a different size or alignment than it was allocated with.
+    LayoutMismatch {
+        addr: usize,
+        size: usize,
+        align: usize,
+        freed_size: usize,
+        freed_align: usize,
+    },
+    /// The red zone in front of the allocation was overwritten at `at`.
+    Underflow {
+        addr: usize,
+        at: usize,
+    },
+    /// The red zone after the allocation was overwritten at `at`.
+    Overflow {
+        addr: usize,
+        at: usize,
+    },
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            HeapError::DoubleFree { addr } => write!(f, "double free of {addr:#x}"),
+            HeapError::NotAllocated { addr } => write!(
+                f,
+                "attempted to free {addr:#x}, which is not a live allocation"
+            ),
+            HeapError::HeaderOverwritten { addr } => {
+                write!(f, "header of allocation {addr:#x} was overwritten")
+            }
+            HeapError::LayoutMismatch {
+                addr,
+                size,
+                align,
+                freed_size,
+                freed_align,
+            } => write!(
+                f,
+                "allocation {addr:#x} of {size} bytes (align {align}) freed with a layout of \
+                 {freed_size} bytes (align {freed_align})"
+            ),
+            HeapError::Underflow { addr, at } => {
+                write!(
+                    f,
+                    "buffer underflow in allocation {addr:#x} detected at {at:#x}"
+                )
+            }
+            HeapError::Overflow { addr, at } => {
+                write!(
+                    f,
+                    "buffer overflow in allocation {addr:#x} detected at {at:#x}"
+                )
+            }
+        }
+    }
+}
+
+pub(super) struct DebugState {
+    pub(super) heap_start: usize,
+    live: Option<NonNull<AllocHeader>>,
+    live_count: usize,
+}
+unsafe impl Send for DebugState {}
+unsafe impl Sync for DebugState {}
+
+impl DebugState {
+    pub(super) const fn new() -> Self {
+        DebugState {
+            heap_start: 0,
+            live: None,
+            live_count: 0,
+        }
+    }
+}
+
+fn header_of(addr: usize) -> *mut AllocHeader {
+    (addr - RED_ZONE - HEADER) as *mut AllocHeader
+}
+
+fn find_corruption(start: usize, len: usize) -> Option<usize> {
+    (start..start + len).find(|&addr| unsafe { *(addr as *const u8) } != CANARY)
+}
+
+impl FreeListAlloc {
+    pub(super) unsafe fn debug_alloc(&mut self, layout: Layout) -> Option<*mut u8> {
+        self.validate_free_list();
+        let (size, align) = (layout.size(), layout.align());
+        let prepared = Self::prepare_layout(layout);
+        let pad = (HEADER + RED_ZONE).next_multiple_of(prepared.align());
+        let raw_size = pad + prepared.size() + RED_ZONE;
+        let base = unsafe { self.alloc_raw(raw_size, prepared.align())? } as usize;
+        let addr = base + pad;
+        let header = header_of(addr);
+        unsafe {
+            ptr::write_bytes(base as *mut u8, CANARY, header as usize - base);
+            header.write(AllocHeader {
+                prev: None,
+                next: self.debug.live,
+                base,
+                raw_size,
+                size,
+                align,
+                magic: LIVE_MAGIC,
+            });
+            ptr::write_bytes((addr - RED_ZONE) as *mut u8, CANARY, RED_ZONE);
+            ptr::write_bytes(addr as *mut u8, POISON_ALLOCATED, size);
+            ptr::write_bytes((addr + size) as *mut u8, CANARY, RED_ZONE);
+            if let Some(mut next) = self.debug.live {
+                next.as_mut().prev = NonNull::new(header);
+            }
+        }
+        self.debug.live = NonNull::new(header);
+        self.debug.live_count += 1;
+        self.validate_free_list();
+        Some(addr as *mut u8)
+    }
+    /// Check that `addr` is a live allocation of `size` bytes aligned to `align` with intact red
+    /// zones, as freeing it would.
+    pub fn check_allocation(
+        &self,
+        addr: usize,
+        size: usize,
+        align: usize,
+    ) -> Result<(), HeapError> {
+        let header = header_of(addr);
+        if !self.is_live(header) {
+            let magic = unsafe { (*header).magic };
+            if magic == FREED_MAGIC {
+                return Err(HeapError::DoubleFree { addr });
+            }
+            return Err(HeapError::NotAllocated { addr });
+        }
+        let header = unsafe { &*header };
+        if header.magic != LIVE_MAGIC {
+            return Err(HeapError::HeaderOverwritten { addr });
+        }
+        if header.size != size || header.align != align {
+            return Err(HeapError::LayoutMismatch {
+                addr,
+                size: header.size,
+                align: header.align,
+                freed_size: size,
+                freed_align: align,
+            });
+        }
+        if let Some(at) = find_corruption(addr - RED_ZONE, RED_ZONE) {
+            return Err(HeapError::Underflow { addr, at });
+        }
+        if let Some(at) = find_corruption(addr + size, RED_ZONE) {
+            return Err(HeapError::Overflow { addr, at });
+        }
+        Ok(())
+    }
+    pub(super) unsafe fn debug_dealloc(&mut self, addr: usize, layout: Layout) {
+        self.validate_free_list();
+        let size = layout.size();
+        if let Err(err) = self.check_allocation(addr, size, layout.align()) {
+            panic!("HEAP: {err}");
+        }
+        let header_ptr = header_of(addr);
+        let header = unsafe { &mut *header_ptr };
+        unsafe {
+            match header.prev {
+                Some(mut prev) => prev.as_mut().next = header.next,
+                None => self.debug.live = header.next,
+            }
+            if let Some(mut next) = header.next {
+                next.as_mut().prev = header.prev;
+            }
+        }
+        self.debug.live_count -= 1;
+        let (base, raw_size) = (header.base, header.raw_size);
+        header.magic = FREED_MAGIC;
+        unsafe {
+            ptr::write_bytes(base as *mut u8, POISON_FREED, header_ptr as usize - base);
+            ptr::write_bytes(addr as *mut u8, POISON_FREED, size + RED_ZONE);
+            self.dealloc_raw(base, raw_size);
+        }
+        self.validate_free_list();
+    }
+    fn is_live(&self, header: *mut AllocHeader) -> bool {
+        let mut cur = self.debug.live;
+        while let Some(node) = cur {
+            if node.as_ptr() == header {
+                return true;
+            }
+            cur = unsafe { node.as_ref().next };
+        }
+        false
+    }
+    /// Panic if the free list is not sorted, contains overlapping or unmerged regions, or regions
+    /// outside of the heap.
+    fn validate_free_list(&self) {
+        let mut prev_end = None;
+        let mut cur = &self.head;
+        while let Some(node) = cur.next {
+            let node = unsafe { node.as_ref() };
+            let (start, end) = (node.start_addr(), node.end_addr());
+            if start % mem::align_of::<ListNode>() != 0 || node.size < mem::size_of::<ListNode>() {
+                panic!(
+                    "HEAP: corrupted free region {start:#x} of {} bytes",
+                    node.size
+                );
+            }
+            if start < self.debug.heap_start || end > self.heap_end {
+                panic!("HEAP: free region {start:#x}..{end:#x} lies outside of the heap");
+            }
+            match prev_end {
+                Some(prev_end) if start < prev_end => {
+                    panic!("HEAP: free region {start:#x} overlaps or is out of order")
+                }
+                Some(prev_end) if start == prev_end => {
+                    panic!("HEAP: free region {start:#x} was not merged with its neighbour")
+                }
+                _ => (),
+            }
+            prev_end = Some(end);
+            cur = node;
+        }
+    }
+    /// Check the red zones of every live allocation.
+    pub fn check_live_allocations(&self) {
+        let mut cur = self.debug.live;
+        while let Some(node) = cur {
+            let header = unsafe { node.as_ref() };
+            let addr = node.as_ptr() as usize + HEADER + RED_ZONE;
+            if header.magic != LIVE_MAGIC {
+                panic!("HEAP: header of allocation {addr:#x} was overwritten");
+            }
+            if find_corruption(addr - RED_ZONE, RED_ZONE)
+                .or_else(|| find_corruption(addr + header.size, RED_ZONE))
+                .is_some()
+            {
+                panic!("HEAP: red zone of allocation {addr:#x} was overwritten");
+            }
+            cur = header.next;
+        }
+    }
+    /// Print every live allocation over serial.
+    pub fn dump_live_allocations(&self) {
+        serial_println!("HEAP: {} live allocations", self.debug.live_count);
+        let mut total = 0;
+        let mut cur = self.debug.live;
+        while let Some(node) = cur {
+            let header = unsafe { node.as_ref() };
+            let addr = node.as_ptr() as usize + HEADER + RED_ZONE;
+            serial_println!(
+                "  {addr:#x}: {} bytes (align {})",
+                header.size,
+                header.align
+            );
+            total += header.size;
+            cur = header.next;
+        }
+        serial_println!("HEAP: {total} bytes in use");
+    }
+}
@@ -1,48 +1,109 @@
+use core::sync::atomic::{AtomicUsize, Ordering::*};
 use spinlock::SpinLock;
 use x86_64::{
     VirtAddr,
-    structures::paging::{
-        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
-    },
+    structures::paging::{PageSize, PageTableFlags, Size2MiB},
 };
 
-use super::freelist_alloc::{FreeListAlloc, SpinLockFreelist};
+use super::{
+    freelist_alloc::{FreeListAlloc, HeapGrowth},
+    slab_alloc::{SlabAlloc, SpinLockSlab},
+    vmm::{VMM, VirtRange, VmmError},
+};
 
-/// The global heap allocator, backed by a [free-list backed allocator](FreeListAlloc)
+/// The global heap allocator, backed by a [size-class block allocator](SlabAlloc) in front of a
+/// [free-list backed allocator](FreeListAlloc)
 #[global_allocator]
-pub static ALLOCATOR: SpinLockFreelist =
-    SpinLockFreelist(SpinLock::disable_interrupts(FreeListAlloc::empty()));
+pub static ALLOCATOR: SpinLockSlab = SpinLockSlab(SpinLock::disable_interrupts(SlabAlloc::new(
+    FreeListAlloc::empty(),
+)));
+
+/// The start of the heap, aligned so that it can be mapped with huge pages.
+pub const HEAP_START: usize = 0x_4444_4440_0000;
+/// The amount of memory the heap grows and shrinks by, one huge page.
+pub const HEAP_STEP: usize = Size2MiB::SIZE as usize;
+/// The amount of memory mapped for the heap at boot.
+pub const HEAP_SIZE: usize = HEAP_STEP;
+/// The default ceiling the heap can grow up to.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 256 * 1024;
+/// The number of times growing the heap failed.
+static FAILED_GROWTHS: AtomicUsize = AtomicUsize::new(0);
 
 /// Initialize the global heap allocator.
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE as u64 - 1;
-        let heap_start = Page::containing_address(heap_start);
-        let heap_end = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start, heap_end)
-    };
-
-    for page in page_range {
-        let frame = frame_alloc
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_alloc)?.flush() };
-    }
+pub fn init_heap() -> Result<(), VmmError> {
+    map_heap_pages(HEAP_START, HEAP_SIZE)?;
 
     // unsafe {
     //     *ALLOCATOR.0.lock() = BumpAlloc::init(NonZeroUsize::new(HEAP_START).unwrap(), HEAP_SIZE);
     // }
+    let mut alloc = ALLOCATOR.0.lock();
+    let alloc = alloc.freelist();
     unsafe {
-        ALLOCATOR.0.lock().init(HEAP_START, HEAP_SIZE);
+        alloc.init(HEAP_START, HEAP_SIZE);
+        alloc.set_growth(HeapGrowth {
+            grow: grow_heap,
+            shrink: Some(shrink_heap),
+            limit: HEAP_START + HEAP_MAX_SIZE,
+            step: HEAP_STEP,
+        });
     }
 
     Ok(())
 }
+
+/// Set the maximum size the heap is allowed to grow to, rounded down to a multiple of
+/// [HEAP_STEP]. Memory that is already in use by the heap is not released.
+pub fn set_heap_limit(size: usize) {
+    let size = size - size % HEAP_STEP;
+    ALLOCATOR.0.lock().freelist().set_limit(HEAP_START + size);
+}
+
+/// Returns how many times growing the heap failed, either for lack of physical memory or because
+/// mapping the new pages failed.
+pub fn failed_growths() -> usize {
+    FAILED_GROWTHS.load(Relaxed)
+}
+
+/// Back the given range of the heap with freshly allocated frames, using huge pages where
+/// contiguous memory is available.
+fn map_heap_pages(start: usize, len: usize) -> Result<(), VmmError> {
+    let range = VirtRange::new(VirtAddr::new(start as u64), len as u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    VMM.lock().map_range(range, flags)
+}
+
+fn grow_heap(addr: usize, len: usize) -> bool {
+    if map_heap_pages(addr, len).is_ok() {
+        return true;
+    }
+    FAILED_GROWTHS.fetch_add(1, Relaxed);
+    false
+}
+
+fn shrink_heap(addr: usize, len: usize) {
+    let range = VirtRange::new(VirtAddr::new(addr as u64), len as u64);
+    VMM.lock().unmap_range(range);
+}
+
+/// Check that `ptr` is a live heap allocation of `layout` with intact red zones, as freeing it
+/// would.
+#[cfg(feature = "heap_debug")]
+pub fn check_allocation(
+    ptr: *const u8,
+    layout: core::alloc::Layout,
+) -> Result<(), super::freelist_alloc::HeapError> {
+    ALLOCATOR
+        .0
+        .lock()
+        .freelist()
+        .check_allocation(ptr as usize, layout.size(), layout.align())
+}
+
+/// Print every live heap allocation over serial, after checking their red zones.
+#[cfg(feature = "heap_debug")]
+pub fn dump_live_allocations() {
+    let mut alloc = ALLOCATOR.0.lock();
+    alloc.freelist().check_live_allocations();
+    alloc.freelist().dump_live_allocations();
+}
@@ -0,0 +1,55 @@
+//! Memory that was unmapped, but may still be reachable through the TLB of another CPU.
+//!
+//! Unmapped memory is retired into one of two batches instead of being freed. A batch can be
+//! freed once every CPU has flushed its TLB for the last [shootdown](crate::smp::flush_remote_tlbs)
+//! started after memory was added to it. Memory is only added to the current batch, and the
+//! batches switch roles once the other one was freed, so that a steady stream of unmapping can't
+//! keep either batch from being freed forever.
+use crate::smp;
+
+/// Tracks which of two batches of retired memory can be freed.
+pub(super) struct RetireBatches {
+    /// The TLB shootdown generation every CPU has to have flushed before each batch can be freed.
+    generation: [u64; 2],
+    /// The amount of memory in each batch.
+    len: [usize; 2],
+    /// The batch memory is currently retired to.
+    current: usize,
+}
+
+impl RetireBatches {
+    pub(super) const fn new() -> Self {
+        RetireBatches {
+            generation: [0; 2],
+            len: [0; 2],
+            current: 0,
+        }
+    }
+    /// Add `len` units of memory that were just unmapped, returning the batch to store them in.
+    pub(super) fn retire(&mut self, len: usize) -> usize {
+        self.generation[self.current] = smp::next_tlb_generation();
+        self.len[self.current] += len;
+        self.current
+    }
+    /// Returns the amount of retired memory.
+    pub(super) fn len(&self) -> usize {
+        self.len[0] + self.len[1]
+    }
+    /// Returns a batch that every CPU has flushed from its TLB, which the caller must free right
+    /// away.
+    pub(super) fn reclaimable(&mut self) -> Option<usize> {
+        let mut old = 1 - self.current;
+        if self.len[old] == 0 {
+            if self.len[self.current] == 0 {
+                return None;
+            }
+            self.current = old;
+            old = 1 - old;
+        }
+        if !smp::tlbs_flushed(self.generation[old]) {
+            return None;
+        }
+        self.len[old] = 0;
+        Some(old)
+    }
+}
@@ -0,0 +1,316 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+use spinlock::{DisableInterrupts, SpinLock};
+
+use super::freelist_alloc::{AllocStats, FreeListAlloc};
+
+/// The block sizes served by the [SlabAlloc]. Larger allocations go to the fallback allocator.
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// The smallest chunk requested from the fallback allocator to be carved into blocks.
+const MIN_SLAB_SIZE: usize = 4096;
+
+/// A fixed-size block allocator for small allocations, falling back to a [FreeListAlloc] for
+/// large allocations and for backing memory of its own blocks.
+///
+/// Blocks are carved from slabs aligned to their size, which start with a [Slab] header tracking
+/// the free blocks within. Slabs whose blocks are all free are returned to the fallback
+/// allocator, unless it is the only slab of its class with free blocks.
+pub struct SlabAlloc {
+    classes: [SizeClass; SIZE_CLASSES.len()],
+    fallback: FreeListAlloc,
+}
+
+/// A free block in a size class, reusing the block's own memory.
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// The header at the start of every slab.
+struct Slab {
+    free: Option<NonNull<FreeBlock>>,
+    /// The number of blocks handed out.
+    used: usize,
+    /// The neighbours in the class' list of slabs with free blocks.
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+}
+
+struct SizeClass {
+    /// The slabs with free blocks.
+    partial: Option<NonNull<Slab>>,
+    stats: SizeClassStats,
+}
+unsafe impl Send for SizeClass {}
+unsafe impl Sync for SizeClass {}
+
+/// Usage statistics of a single size class.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    /// The number of slabs currently taken from the fallback allocator.
+    pub slabs: usize,
+    /// The number of blocks currently handed out.
+    pub allocated: usize,
+    /// The number of blocks available for allocation.
+    pub free: usize,
+}
+
+impl SizeClassStats {
+    pub const fn new(block_size: usize) -> Self {
+        SizeClassStats {
+            block_size,
+            slabs: 0,
+            allocated: 0,
+            free: 0,
+        }
+    }
+}
+
+impl SizeClass {
+    const fn new(block_size: usize) -> Self {
+        SizeClass {
+            partial: None,
+            stats: SizeClassStats::new(block_size),
+        }
+    }
+    /// The size and alignment of the slabs of this class.
+    const fn slab_size(&self) -> usize {
+        let size = self.stats.block_size * 8;
+        if size < MIN_SLAB_SIZE {
+            MIN_SLAB_SIZE
+        } else {
+            size
+        }
+    }
+    /// The offset of the first block in a slab, past the [Slab] header.
+    const fn first_block(&self) -> usize {
+        mem::size_of::<Slab>().next_multiple_of(self.stats.block_size)
+    }
+    /// Returns the slab containing `block`.
+    fn slab_of(&self, block: NonNull<u8>) -> NonNull<Slab> {
+        let addr = block.as_ptr() as usize & !(self.slab_size() - 1);
+        NonNull::new(addr as *mut Slab).unwrap()
+    }
+    fn link(&mut self, mut slab: NonNull<Slab>) {
+        unsafe {
+            let header = slab.as_mut();
+            header.prev = None;
+            header.next = self.partial;
+            if let Some(mut next) = self.partial {
+                next.as_mut().prev = Some(slab);
+            }
+        }
+        self.partial = Some(slab);
+    }
+    fn unlink(&mut self, mut slab: NonNull<Slab>) {
+        unsafe {
+            let header = slab.as_mut();
+            match header.prev {
+                Some(mut prev) => prev.as_mut().next = header.next,
+                None => self.partial = header.next,
+            }
+            if let Some(mut next) = header.next {
+                next.as_mut().prev = header.prev;
+            }
+            header.prev = None;
+            header.next = None;
+        }
+    }
+    /// # Safety
+    /// `slab` must point to `slab_size` unused bytes aligned to `slab_size`.
+    unsafe fn add_slab(&mut self, slab: NonNull<u8>) {
+        let block_size = self.stats.block_size;
+        let mut free = None;
+        for offset in (self.first_block()..self.slab_size())
+            .step_by(block_size)
+            .rev()
+        {
+            let block = unsafe { slab.add(offset).cast::<FreeBlock>() };
+            unsafe { block.write(FreeBlock { next: free }) };
+            free = Some(block);
+            self.stats.free += 1;
+        }
+        let slab = slab.cast::<Slab>();
+        unsafe {
+            slab.write(Slab {
+                free,
+                used: 0,
+                prev: None,
+                next: None,
+            })
+        };
+        self.stats.slabs += 1;
+        self.link(slab);
+    }
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        let mut slab = self.partial?;
+        let header = unsafe { slab.as_mut() };
+        let mut block = header.free?;
+        header.free = unsafe { block.as_mut().next.take() };
+        header.used += 1;
+        if header.free.is_none() {
+            self.unlink(slab);
+        }
+        self.stats.free -= 1;
+        Some(block.cast())
+    }
+    /// Return a block to its slab, returning the slab if it is now empty and should be released.
+    ///
+    /// # Safety
+    /// `block` must be a block handed out by [Self::pop].
+    unsafe fn push(&mut self, block: NonNull<u8>) -> Option<NonNull<u8>> {
+        let mut slab = self.slab_of(block);
+        let header = unsafe { slab.as_mut() };
+        let was_full = header.free.is_none();
+        let block = block.cast::<FreeBlock>();
+        unsafe { block.write(FreeBlock { next: header.free }) };
+        header.free = Some(block);
+        header.used -= 1;
+        self.stats.free += 1;
+        if was_full {
+            self.link(slab);
+        }
+        let header = unsafe { slab.as_ref() };
+        // Keep the last slab with free blocks around to avoid churn.
+        if header.used != 0 || (header.prev.is_none() && header.next.is_none()) {
+            return None;
+        }
+        self.unlink(slab);
+        self.stats.slabs -= 1;
+        self.stats.free -= (self.slab_size() - self.first_block()) / self.stats.block_size;
+        Some(slab.cast())
+    }
+}
+
+impl SlabAlloc {
+    /// Create a SlabAlloc with no cached blocks on top of the given fallback allocator.
+    pub const fn new(fallback: FreeListAlloc) -> Self {
+        let mut classes = [const { SizeClass::new(0) }; SIZE_CLASSES.len()];
+        let mut i = 0;
+        while i < SIZE_CLASSES.len() {
+            classes[i] = SizeClass::new(SIZE_CLASSES[i]);
+            i += 1;
+        }
+        SlabAlloc { classes, fallback }
+    }
+    /// Access the fallback allocator, used for large allocations and slabs.
+    pub fn freelist(&mut self) -> &mut FreeListAlloc {
+        &mut self.fallback
+    }
+    /// Returns the index of the smallest size class that can fit the given layout.
+    fn class_index(layout: Layout) -> Option<usize> {
+        if cfg!(feature = "heap_debug") {
+            // Route everything through the checked fallback allocator
+            return None;
+        }
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|&class| class >= size)
+    }
+    /// Carve a new slab from the fallback allocator into blocks of the given class.
+    fn refill(&mut self, index: usize) -> Option<()> {
+        let class = &mut self.classes[index];
+        let size = class.slab_size();
+        let layout = Layout::from_size_align(size, size).ok()?;
+        let slab = NonNull::new(unsafe { self.fallback.alloc(layout)? })?;
+        unsafe { class.add_slab(slab) };
+        Some(())
+    }
+    pub fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let Some(index) = Self::class_index(layout) else {
+            let ptr = unsafe { self.fallback.alloc(layout)? };
+            return NonNull::new(ptr);
+        };
+        if self.classes[index].partial.is_none() {
+            self.refill(index)?;
+        }
+        let class = &mut self.classes[index];
+        let block = class.pop()?;
+        class.stats.allocated += 1;
+        Some(block)
+    }
+    /// # Safety
+    /// `ptr` must have been returned by [Self::alloc] with the same layout.
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let Some(index) = Self::class_index(layout) else {
+            unsafe { self.fallback.dealloc(ptr.as_ptr() as usize, layout) };
+            return;
+        };
+        let class = &mut self.classes[index];
+        class.stats.allocated -= 1;
+        if let Some(slab) = unsafe { class.push(ptr) } {
+            let size = class.slab_size();
+            let layout = unsafe { Layout::from_size_align_unchecked(size, size) };
+            unsafe { self.fallback.dealloc(slab.as_ptr() as usize, layout) };
+        }
+    }
+    /// Attempt to resize an allocation without moving it. Blocks can be resized within their size
+    /// class, while large allocations are resized in the fallback allocator.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [Self::alloc] with the same layout.
+    pub unsafe fn realloc_in_place(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_layout: Layout,
+    ) -> bool {
+        match (Self::class_index(layout), Self::class_index(new_layout)) {
+            (Some(old), Some(new)) => old == new,
+            (None, None) => {
+                let old = FreeListAlloc::prepare_layout(layout);
+                let new = FreeListAlloc::prepare_layout(new_layout);
+                unsafe {
+                    self.fallback
+                        .realloc_in_place(ptr.as_ptr() as usize, old.size(), new.size())
+                }
+            }
+            _ => false,
+        }
+    }
+    pub fn stats(&self) -> AllocStats {
+        let mut stats = self.fallback.stats();
+        let slabs: usize = self.classes.iter().map(|c| c.stats.slabs).sum();
+        let blocks: usize = self.classes.iter().map(|c| c.stats.allocated).sum();
+        stats.allocations = stats.allocations - slabs + blocks;
+        stats.size_classes = self.classes.each_ref().map(|c| c.stats);
+        stats
+    }
+}
+
+/// Ensure every block can hold a [FreeBlock] while it is not in use.
+const _: () = assert!(SIZE_CLASSES[0] >= mem::size_of::<FreeBlock>());
+
+pub struct SpinLockSlab(pub SpinLock<SlabAlloc, DisableInterrupts>);
+
+unsafe impl GlobalAlloc for SpinLockSlab {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.0.lock().alloc(layout) {
+            Some(ptr) => ptr.as_ptr(),
+            None => ptr::null_mut(),
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(ptr) = NonNull::new(ptr) else {
+            return;
+        };
+        unsafe { self.0.lock().dealloc(ptr, layout) };
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        unsafe {
+            let block = NonNull::new_unchecked(ptr);
+            if self.0.lock().realloc_in_place(block, layout, new_layout) {
+                return ptr;
+            }
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            new_ptr
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use super::vmm::{VMM, VirtRange, VmmError};
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// A kernel stack allocated at runtime, with an unmapped guard page below it so that an overflow
+/// page faults instead of silently corrupting adjacent memory.
+///
+/// The stack is unmapped and its frames are released when dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    /// The reserved range, including the guard page.
+    range: VirtRange,
+}
+
+impl KernelStack {
+    /// Allocate a stack of `pages` mapped pages.
+    pub fn new(pages: usize) -> Result<Self, VmmError> {
+        let mut vmm = VMM.lock();
+        let range = vmm.alloc_range((pages as u64 + 1) * PAGE_SIZE)?;
+        let stack = VirtRange {
+            start: range.start + PAGE_SIZE,
+            end: range.end,
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if let Err(err) = vmm.map_range(stack, flags) {
+            vmm.free_range(range);
+            return Err(err);
+        }
+        Ok(KernelStack { range })
+    }
+    /// The initial stack pointer. Stacks grow downwards, so this is the end of the stack.
+    pub fn top(&self) -> VirtAddr {
+        self.range.end
+    }
+    /// The lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.range.start + PAGE_SIZE
+    }
+    /// The unmapped page directly below the stack.
+    pub fn guard_page(&self) -> VirtRange {
+        VirtRange::new(self.range.start, PAGE_SIZE)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        VMM.lock().free_mapped(self.range);
+    }
+}
@@ -0,0 +1,391 @@
+//! Kernel virtual memory management.
+//!
+//! The [VMM] owns the active page table and the physical frame allocator, and hands out ranges of
+//! the kernel's virtual address space for things like MMIO windows, stacks and buffers.
+//!
+//! Pages of lazily-backed regions are mapped on first touch by the page fault handler, see
+//! [handle_page_fault], which also gives [copy-on-write](COW) pages a private frame on their first
+//! write.
+//!
+//! Large, suitably aligned mappings use 2MiB and 1GiB pages where possible. The bootloader's
+//! mapping of physical memory already uses 2MiB pages.
+//!
+//! Other address spaces get [their own level 4 table](table) sharing the kernel's mappings.
+//!
+//! Nothing in this module allocates on the heap, so the heap allocator is free to call into the
+//! [VMM] while growing. Lock order is `ALLOCATOR` -> `VMM`.
+//!
+//! Every CPU is asked to flush its TLB after mappings are removed or restricted, without waiting
+//! for it. Unmapped frames and released ranges are [retired](super::retire) instead, and only
+//! reused once every CPU has flushed its TLB.
+pub mod cow;
+pub mod demand;
+mod huge;
+mod lock;
+pub mod range_alloc;
+mod table;
+
+use super::{
+    frame_alloc::{BitmapFrameAllocator, FrameStats},
+    retire::RetireBatches,
+};
+use demand::LazyRegions;
+pub use demand::{FaultStats, LazyRegion, fault_stats, handle_page_fault};
+pub use lock::{VmmGuard, VmmLock};
+pub use range_alloc::{RangeAllocator, VirtRange};
+use spinlock::LazyStatic;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::control::{Cr0, Cr0Flags},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+        mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError},
+    },
+};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// The start of the virtual address range handed out by [Vmm::alloc_range].
+pub const KERNEL_REGION_START: u64 = 0x_5000_0000_0000;
+/// The size of the virtual address range handed out by [Vmm::alloc_range], one level 4 entry.
+pub const KERNEL_REGION_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// Marks a mapping of physical memory that is not owned by the frame allocator, such as MMIO, so
+/// that its frames are not freed when it is unmapped.
+pub const PHYS_MAPPING: PageTableFlags = PageTableFlags::BIT_10;
+/// Marks a read-only page whose frame is shared copy-on-write, see [Vmm::clone_cow].
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The global virtual memory manager, initialized by [memory::init](super::init).
+pub static VMM: LazyStatic<VmmLock> =
+    LazyStatic::new(|| panic!("Attempted to use the VMM before memory::init"));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// No physical frames are left.
+    OutOfMemory,
+    /// No free virtual address range of the requested size is left.
+    OutOfAddressSpace,
+    /// A page in the range is already mapped.
+    AlreadyMapped,
+    /// A page in the range is not mapped.
+    NotMapped,
+    /// A page in the range is part of a huge page mapping.
+    HugePage,
+    /// No more lazily-backed regions can be registered.
+    TooManyRegions,
+}
+
+impl<S: PageSize> From<MapToError<S>> for VmmError {
+    fn from(err: MapToError<S>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => VmmError::OutOfMemory,
+            MapToError::ParentEntryHugePage => VmmError::HugePage,
+            MapToError::PageAlreadyMapped(_) => VmmError::AlreadyMapped,
+        }
+    }
+}
+
+impl From<FlagUpdateError> for VmmError {
+    fn from(err: FlagUpdateError) -> Self {
+        match err {
+            FlagUpdateError::PageNotMapped => VmmError::NotMapped,
+            FlagUpdateError::ParentEntryHugePage => VmmError::HugePage,
+        }
+    }
+}
+
+impl From<UnmapError> for VmmError {
+    fn from(err: UnmapError) -> Self {
+        match err {
+            UnmapError::ParentEntryHugePage => VmmError::HugePage,
+            UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => VmmError::NotMapped,
+        }
+    }
+}
+
+/// Owns the kernel page table, the physical frame allocator and the kernel virtual address space.
+pub struct Vmm {
+    mapper: OffsetPageTable<'static>,
+    frames: BitmapFrameAllocator,
+    ranges: RangeAllocator,
+    /// Released ranges in each batch, see [Self::free_range].
+    retired_ranges: [RangeAllocator; 2],
+    range_batches: RetireBatches,
+    lazy: LazyRegions,
+    /// Whether the CPU supports 1GiB pages.
+    gigabyte_pages: bool,
+    /// The TLB shootdown that took permissions away, which the [VmmGuard] waits for once the lock
+    /// is released.
+    pending_shootdown: Option<u64>,
+}
+
+/// The alignment needed for a range of `len` bytes to make use of huge pages.
+fn huge_alignment(len: u64) -> u64 {
+    if len >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        PAGE_SIZE
+    }
+}
+
+fn pages(range: VirtRange) -> impl Iterator<Item = Page> {
+    let start = Page::containing_address(range.start);
+    let end = Page::containing_address(range.end.align_up(PAGE_SIZE));
+    Page::range(start, end)
+}
+
+impl Vmm {
+    pub fn new(mapper: OffsetPageTable<'static>, frames: BitmapFrameAllocator) -> Self {
+        let region = VirtRange::new(VirtAddr::new(KERNEL_REGION_START), KERNEL_REGION_SIZE);
+        let entry = Page::<Size4KiB>::containing_address(region.start).p4_index();
+        assert!(
+            mapper.level_4_table()[entry].is_unused(),
+            "The kernel virtual region is already in use"
+        );
+        // Copy-on-write relies on the kernel faulting on writes to read-only pages.
+        unsafe { Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT)) };
+        let mut vmm = Vmm {
+            mapper,
+            frames,
+            ranges: RangeAllocator::new(region),
+            retired_ranges: [RangeAllocator::empty(), RangeAllocator::empty()],
+            range_batches: RetireBatches::new(),
+            lazy: LazyRegions::new(),
+            gigabyte_pages: huge::gigabyte_pages_supported(),
+            pending_shootdown: None,
+        };
+        vmm.populate_entry(entry.into())
+            .expect("Failed to allocate a page table for the kernel virtual region");
+        vmm
+    }
+    /// Access the physical frame allocator.
+    pub fn frames(&mut self) -> &mut BitmapFrameAllocator {
+        &mut self.frames
+    }
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frames.stats()
+    }
+    /// Map every page in the range to freshly allocated frames, using huge pages for suitably
+    /// aligned parts of the range when enough contiguous memory is available.
+    ///
+    /// On failure, the pages mapped so far are unmapped again.
+    pub fn map_range(&mut self, range: VirtRange, flags: PageTableFlags) -> Result<(), VmmError> {
+        let end = range.end.align_up(PAGE_SIZE);
+        let mut addr = range.start.align_down(PAGE_SIZE);
+        while addr < end {
+            if let Some(size) = self.map_huge(addr, end, None, flags) {
+                addr += size;
+                continue;
+            }
+            let result = match self.frames.allocate_frame() {
+                Some(frame) => unsafe {
+                    self.map_page(Page::containing_address(addr), frame, flags, true)
+                },
+                None => Err(VmmError::OutOfMemory),
+            };
+            if let Err(err) = result {
+                self.unmap_range(VirtRange {
+                    start: range.start,
+                    end: addr,
+                });
+                return Err(err);
+            }
+            addr += PAGE_SIZE;
+        }
+        Ok(())
+    }
+    /// Map the range to the physical memory starting at `phys`. The frames are not considered to
+    /// be owned by the mapping and won't be freed when it's unmapped.
+    ///
+    /// # Safety
+    /// The caller must ensure that mapping the physical memory does not violate memory safety,
+    /// e.g. by creating a writable alias of memory in use elsewhere.
+    pub unsafe fn map_phys_range(
+        &mut self,
+        range: VirtRange,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), VmmError> {
+        let flags = flags | PHYS_MAPPING;
+        let phys = phys.align_down(PAGE_SIZE);
+        let start = range.start.align_down(PAGE_SIZE);
+        let end = range.end.align_up(PAGE_SIZE);
+        let mut addr = start;
+        while addr < end {
+            let frame = phys + (addr - start);
+            if let Some(size) = self.map_huge(addr, end, Some(frame), flags) {
+                addr += size;
+                continue;
+            }
+            let page = Page::containing_address(addr);
+            let frame = PhysFrame::containing_address(frame);
+            if let Err(err) = unsafe { self.map_page(page, frame, flags, false) } {
+                self.unmap_range(VirtRange {
+                    start: range.start,
+                    end: addr,
+                });
+                return Err(err);
+            }
+            addr += PAGE_SIZE;
+        }
+        Ok(())
+    }
+    /// # Safety
+    /// See [Mapper::map_to].
+    unsafe fn map_page(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        owned: bool,
+    ) -> Result<(), VmmError> {
+        match unsafe { self.mapper.map_to(page, frame, flags, &mut self.frames) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => {
+                if owned {
+                    unsafe { self.frames.deallocate_frame(frame) };
+                }
+                Err(err.into())
+            }
+        }
+    }
+    /// Unmap every mapped page in the range, retiring the frames owned by the mapping. Pages that
+    /// are not mapped are skipped.
+    ///
+    /// Huge pages only partially covered by the range are split first. If that fails for lack of
+    /// memory, they are left mapped.
+    pub fn unmap_range(&mut self, range: VirtRange) {
+        let end = range.end.align_up(PAGE_SIZE);
+        let mut addr = range.start.align_down(PAGE_SIZE);
+        while addr < end {
+            let TranslateResult::Mapped { frame, flags, .. } = self.mapper.translate(addr) else {
+                addr += PAGE_SIZE;
+                continue;
+            };
+            let size = frame.size();
+            if !addr.is_aligned(size) || end < addr + size {
+                if self.split_huge_page(addr).is_err() {
+                    addr = addr.align_down(size) + size;
+                }
+                continue;
+            }
+            let frames = match frame {
+                MappedFrame::Size4KiB(_) => self.unmap_page::<Size4KiB>(addr),
+                MappedFrame::Size2MiB(_) => self.unmap_page::<Size2MiB>(addr),
+                MappedFrame::Size1GiB(_) => self.unmap_page::<Size1GiB>(addr),
+            };
+            if let Some(frames) = frames {
+                if !flags.contains(PHYS_MAPPING) {
+                    unsafe { self.frames.retire_contiguous(frames) };
+                }
+            }
+            addr += size;
+        }
+        crate::smp::flush_remote_tlbs();
+    }
+    /// Change the flags of every page in the range, splitting huge pages that are only partially
+    /// covered. Other CPUs stop using the old flags before the lock is released.
+    pub fn protect(&mut self, range: VirtRange, flags: PageTableFlags) -> Result<(), VmmError> {
+        let end = range.end.align_up(PAGE_SIZE);
+        let mut addr = range.start.align_down(PAGE_SIZE);
+        while addr < end {
+            let TranslateResult::Mapped {
+                frame, flags: old, ..
+            } = self.mapper.translate(addr)
+            else {
+                return Err(VmmError::NotMapped);
+            };
+            let size = frame.size();
+            if !addr.is_aligned(size) || end < addr + size {
+                self.split_huge_page(addr)?;
+                continue;
+            }
+            let flags = flags | (old & PHYS_MAPPING);
+            match frame {
+                MappedFrame::Size4KiB(_) => self.update_page::<Size4KiB>(addr, flags)?,
+                MappedFrame::Size2MiB(_) => self.update_page::<Size2MiB>(addr, flags)?,
+                MappedFrame::Size1GiB(_) => self.update_page::<Size1GiB>(addr, flags)?,
+            }
+            addr += size;
+        }
+        self.flush_downgraded();
+        Ok(())
+    }
+    /// Make every CPU flush its TLB after permissions were taken away. Other CPUs may keep using
+    /// the old permissions until the shootdown completes, which the [VmmGuard] waits for when it
+    /// is dropped, after releasing the lock so they can take it while flushing.
+    fn flush_downgraded(&mut self) {
+        self.pending_shootdown = Some(crate::smp::flush_remote_tlbs());
+    }
+    /// Translate a virtual address to the physical address it's mapped to.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper.translate_addr(addr)
+    }
+    /// Returns the flags of the page containing `addr`, if it is mapped.
+    pub fn flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        match self.mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+    /// Reserve a page-aligned range of the kernel virtual address space, without mapping it.
+    /// Ranges of at least 2MiB are aligned so that they can be mapped with huge pages.
+    pub fn alloc_range(&mut self, len: u64) -> Result<VirtRange, VmmError> {
+        let len = len.next_multiple_of(PAGE_SIZE);
+        if let Some(batch) = self.range_batches.reclaimable() {
+            self.retired_ranges[batch].move_into(&mut self.ranges);
+        }
+        self.ranges
+            .alloc(len, huge_alignment(len))
+            .ok_or(VmmError::OutOfAddressSpace)
+    }
+    /// Release a range reserved with [Self::alloc_range]. The range must already be unmapped, and
+    /// is only handed out again once every CPU has flushed its TLB.
+    pub fn free_range(&mut self, range: VirtRange) {
+        let batch = self.range_batches.retire(1);
+        self.retired_ranges[batch].free(range);
+    }
+    /// Reserve and map a range of the kernel virtual address space.
+    pub fn alloc_mapped(&mut self, len: u64, flags: PageTableFlags) -> Result<VirtRange, VmmError> {
+        let range = self.alloc_range(len)?;
+        if let Err(err) = self.map_range(range, flags) {
+            self.free_range(range);
+            return Err(err);
+        }
+        Ok(range)
+    }
+    /// Unmap and release a range returned by [Self::alloc_mapped].
+    pub fn free_mapped(&mut self, range: VirtRange) {
+        self.unmap_range(range);
+        self.free_range(range);
+    }
+    /// Map `len` bytes of MMIO registers at `phys` as uncacheable, returning the virtual address
+    /// corresponding to `phys`. Release the mapping with [Self::unmap_mmio].
+    ///
+    /// # Safety
+    /// `phys` must point to device memory that is not otherwise in use.
+    pub unsafe fn map_mmio(&mut self, phys: PhysAddr, len: u64) -> Result<VirtAddr, VmmError> {
+        let offset = phys.as_u64() % huge_alignment(len);
+        let range = self.alloc_range(offset + len)?;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH;
+        if let Err(err) = unsafe { self.map_phys_range(range, phys - offset, flags) } {
+            self.free_range(range);
+            return Err(err);
+        }
+        Ok(range.start + offset)
+    }
+    /// Unmap and release `len` bytes of MMIO registers mapped at `virt` by [Self::map_mmio].
+    pub fn unmap_mmio(&mut self, virt: VirtAddr, len: u64) {
+        let offset = virt.as_u64() % huge_alignment(len);
+        let len = (offset + len).next_multiple_of(PAGE_SIZE);
+        self.free_mapped(VirtRange::new(virt - offset, len));
+    }
+}
@@ -0,0 +1,104 @@
+//! Copy-on-write mappings: pages that share a frame until one of them is written to.
+use super::{COW, PAGE_SIZE, PHYS_MAPPING, VirtRange, Vmm, VmmError, pages};
+use crate::memory::phys_to_virt;
+use x86_64::{
+    VirtAddr,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Translate,
+            mapper::{MappedFrame, TranslateResult},
+        },
+    },
+};
+
+impl Vmm {
+    /// Map the pages of `src` at `dst` sharing the same frames. Writable pages become read-only
+    /// copy-on-write pages in both mappings, and get a private copy of their frame on the first
+    /// write. `dst` must be reserved and not mapped.
+    ///
+    /// Every page of `src` must be mapped with 4KiB pages, and must not be a physical mapping.
+    /// Other CPUs stop writing to `src` before the lock is released.
+    pub fn clone_cow(&mut self, src: VirtRange, dst: VirtAddr) -> Result<(), VmmError> {
+        let dst_pages = pages(VirtRange::new(dst, src.len()));
+        let mut result = Ok(());
+        for (i, (src_page, dst_page)) in pages(src).zip(dst_pages).enumerate() {
+            result = self.share_page(src_page, dst_page);
+            if result.is_err() {
+                self.unmap_range(VirtRange::new(dst, i as u64 * PAGE_SIZE));
+                break;
+            }
+        }
+        // Other CPUs may still have the source pages cached as writable.
+        self.flush_downgraded();
+        result
+    }
+    fn share_page(&mut self, src: Page, dst: Page) -> Result<(), VmmError> {
+        let (frame, mut flags) = match self.mapper.translate(src.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => (frame, flags),
+            TranslateResult::Mapped { .. } => return Err(VmmError::HugePage),
+            _ => return Err(VmmError::NotMapped),
+        };
+        assert!(
+            !flags.contains(PHYS_MAPPING),
+            "Attempted to share a physical mapping copy-on-write"
+        );
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | COW;
+            unsafe { self.mapper.update_flags(src, flags)?.flush() };
+        }
+        self.frames.share_frame(frame);
+        unsafe { self.map_page(dst, frame, flags, true) }
+    }
+    /// Give a copy-on-write page that was written to its own writable frame, copying the
+    /// contents if the frame is still shared.
+    pub(super) fn resolve_cow_fault(
+        &mut self,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> bool {
+        if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            return false;
+        }
+        let page = Page::containing_address(addr);
+        let TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(old),
+            flags,
+            ..
+        } = self.mapper.translate(page.start_address())
+        else {
+            return false;
+        };
+        if !flags.contains(COW) {
+            return false;
+        }
+        let flags = (flags - COW) | PageTableFlags::WRITABLE;
+        if self.frames.refcount(old) == 1 {
+            return unsafe { self.mapper.update_flags(page, flags) }
+                .map(|flush| flush.flush())
+                .is_ok();
+        }
+        let Some(frame) = self.frames.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(old.start_address()).as_ptr::<u8>(),
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            );
+        }
+        let Ok((_, flush)) = self.mapper.unmap(page) else {
+            unsafe { self.frames.deallocate_frame(frame) };
+            return false;
+        };
+        flush.flush();
+        unsafe { self.frames.retire_frame(old) };
+        crate::smp::flush_remote_tlbs();
+        unsafe { self.map_page(page, frame, flags, true).is_ok() }
+    }
+}
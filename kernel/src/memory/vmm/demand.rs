@@ -0,0 +1,178 @@
+//! Demand paging: lazily-backed regions that are only mapped on first touch.
+use super::{PAGE_SIZE, VMM, VirtRange, Vmm, VmmError};
+use crate::memory::phys_to_virt;
+use core::sync::atomic::{AtomicU64, Ordering::*};
+use x86_64::{
+    VirtAddr,
+    instructions::tlb,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{FrameAllocator, Page, PageTableFlags},
+    },
+};
+
+/// The maximum number of lazily-backed regions that can be registered at once.
+const MAX_LAZY_REGIONS: usize = 32;
+
+static HANDLED_FAULTS: AtomicU64 = AtomicU64::new(0);
+static FATAL_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+/// A region of virtual memory whose pages are backed by zeroed frames on first access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyRegion {
+    pub range: VirtRange,
+    /// The flags pages in the region are mapped with.
+    pub flags: PageTableFlags,
+}
+
+/// The lazily-backed regions registered with the [Vmm](super::Vmm).
+pub(super) struct LazyRegions {
+    regions: [Option<LazyRegion>; MAX_LAZY_REGIONS],
+}
+
+impl LazyRegions {
+    pub(super) const fn new() -> Self {
+        LazyRegions {
+            regions: [None; MAX_LAZY_REGIONS],
+        }
+    }
+    fn find(&self, addr: VirtAddr) -> Option<LazyRegion> {
+        self.regions
+            .iter()
+            .flatten()
+            .find(|region| region.range.contains(addr))
+            .copied()
+    }
+}
+
+/// Page fault counters, for diagnostics.
+#[derive(Debug, Clone, Copy)]
+pub struct FaultStats {
+    /// Faults resolved by mapping a lazily-backed page or copying a copy-on-write page.
+    pub handled: u64,
+    /// Faults that could not be resolved.
+    pub fatal: u64,
+}
+
+pub fn fault_stats() -> FaultStats {
+    FaultStats {
+        handled: HANDLED_FAULTS.load(Relaxed),
+        fatal: FATAL_FAULTS.load(Relaxed),
+    }
+}
+
+/// Attempt to resolve a page fault at `addr`, returning whether execution can resume.
+///
+/// Faults are fatal if they occur before the [VMM] is initialized, while it is locked by the
+/// faulting CPU, outside of a lazily-backed region, or if they are caused by a protection
+/// violation other than a write to a copy-on-write page. Faults while another CPU holds the
+/// [VMM] wait for it to be released.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let handled = VMM
+        .get_if_init()
+        .filter(|vmm| !vmm.is_held_by_current_cpu())
+        .is_some_and(|vmm| vmm.lock().resolve_fault(addr, error_code));
+    let counter = if handled {
+        &HANDLED_FAULTS
+    } else {
+        &FATAL_FAULTS
+    };
+    counter.fetch_add(1, Relaxed);
+    handled
+}
+
+impl Vmm {
+    /// Register a region to be backed by zeroed frames on first access.
+    /// The range must not be mapped or overlap another lazily-backed region.
+    pub fn reserve_lazy(
+        &mut self,
+        range: VirtRange,
+        flags: PageTableFlags,
+    ) -> Result<(), VmmError> {
+        let slot = self
+            .lazy
+            .regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmmError::TooManyRegions)?;
+        *slot = Some(LazyRegion {
+            range,
+            flags: flags | PageTableFlags::PRESENT,
+        });
+        Ok(())
+    }
+    /// Unregister a lazily-backed region, unmapping any pages that were touched.
+    pub fn release_lazy(&mut self, range: VirtRange) {
+        if let Some(slot) = self
+            .lazy
+            .regions
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|region| region.range == range))
+        {
+            *slot = None;
+        }
+        self.unmap_range(range);
+    }
+    /// Reserve a zero-filled anonymous region of the kernel virtual address space, which is only
+    /// backed by physical memory once touched.
+    pub fn alloc_lazy(&mut self, len: u64, flags: PageTableFlags) -> Result<VirtRange, VmmError> {
+        let range = self.alloc_range(len)?;
+        if let Err(err) = self.reserve_lazy(range, flags) {
+            self.free_range(range);
+            return Err(err);
+        }
+        Ok(range)
+    }
+    /// Release a region returned by [Self::alloc_lazy].
+    pub fn free_lazy(&mut self, range: VirtRange) {
+        self.release_lazy(range);
+        self.free_range(range);
+    }
+    fn resolve_fault(&mut self, addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+        // Another CPU may have resolved the fault first, or changed the mapping after it was
+        // cached in the TLB.
+        if self
+            .flags(addr)
+            .is_some_and(|flags| allows(flags, error_code))
+        {
+            tlb::flush(addr);
+            return true;
+        }
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return self.resolve_cow_fault(addr, error_code);
+        }
+        let Some(region) = self.lazy.find(addr) else {
+            return false;
+        };
+        let Some(frame) = self.frames.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, PAGE_SIZE as usize);
+        }
+        let page = Page::containing_address(addr);
+        unsafe { self.map_page(page, frame, region.flags, true).is_ok() }
+    }
+}
+
+/// Returns whether a page mapped with `flags` permits the access that caused a fault.
+fn allows(flags: PageTableFlags, error_code: PageFaultErrorCode) -> bool {
+    let required = [
+        (
+            PageFaultErrorCode::CAUSED_BY_WRITE,
+            PageTableFlags::WRITABLE,
+        ),
+        (
+            PageFaultErrorCode::USER_MODE,
+            PageTableFlags::USER_ACCESSIBLE,
+        ),
+    ];
+    flags.contains(PageTableFlags::PRESENT)
+        && required
+            .iter()
+            .all(|&(access, flag)| !error_code.contains(access) || flags.contains(flag))
+        && !(error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && flags.contains(PageTableFlags::NO_EXECUTE))
+}
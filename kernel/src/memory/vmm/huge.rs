@@ -0,0 +1,151 @@
+//! Huge page mappings: 2MiB pages, and 1GiB pages where the CPU supports them.
+//!
+//! Large, suitably aligned parts of a mapping are mapped with huge pages, falling back to 4KiB
+//! pages when no physically contiguous memory is left. Huge pages that are only partially
+//! unmapped or protected are split into smaller pages first.
+use super::{PAGE_SIZE, Vmm, VmmError};
+use crate::memory::phys_to_virt;
+use core::arch::x86_64::__cpuid;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::tlb,
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB, frame::PhysFrameRange, page_table::PageTableEntry,
+    },
+};
+
+/// Returns the page table an entry points to.
+///
+/// # Safety
+/// The entry must be present and point to a page table rather than a huge page, and the caller
+/// must ensure the returned table is not aliased.
+unsafe fn next_table<'a>(entry: &PageTableEntry) -> &'a mut PageTable {
+    unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr() }
+}
+
+/// Returns whether the CPU supports 1GiB pages.
+pub(super) fn gigabyte_pages_supported() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+impl Vmm {
+    /// Map a single huge page at `addr` if one fits below `end`, returning its size.
+    ///
+    /// The page is backed by the physical memory at `phys` if given, and by freshly allocated
+    /// contiguous frames otherwise.
+    pub(super) fn map_huge(
+        &mut self,
+        addr: VirtAddr,
+        end: VirtAddr,
+        phys: Option<PhysAddr>,
+        flags: PageTableFlags,
+    ) -> Option<u64> {
+        if self.gigabyte_pages && self.try_map_huge::<Size1GiB>(addr, end, phys, flags) {
+            return Some(Size1GiB::SIZE);
+        }
+        if self.try_map_huge::<Size2MiB>(addr, end, phys, flags) {
+            return Some(Size2MiB::SIZE);
+        }
+        None
+    }
+    fn try_map_huge<S: PageSize>(
+        &mut self,
+        addr: VirtAddr,
+        end: VirtAddr,
+        phys: Option<PhysAddr>,
+        flags: PageTableFlags,
+    ) -> bool
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        if !addr.is_aligned(S::SIZE) || end < addr + S::SIZE {
+            return false;
+        }
+        let count = (S::SIZE / PAGE_SIZE) as usize;
+        let (start, owned) = match phys {
+            Some(phys) if phys.is_aligned(S::SIZE) => (phys, None),
+            Some(_) => return false,
+            None => match self.frames.allocate_contiguous(count, count) {
+                Some(frames) => (frames.start.start_address(), Some(frames)),
+                None => return false,
+            },
+        };
+        let page = Page::<S>::containing_address(addr);
+        let frame = PhysFrame::<S>::containing_address(start);
+        match unsafe { self.mapper.map_to(page, frame, flags, &mut self.frames) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => {
+                if let Some(frames) = owned {
+                    unsafe { self.frames.deallocate_contiguous(frames) };
+                }
+                false
+            }
+        }
+    }
+    /// Unmap the page of size `S` containing `addr`, returning the 4KiB frames it was mapped to.
+    pub(super) fn unmap_page<S: PageSize>(&mut self, addr: VirtAddr) -> Option<PhysFrameRange>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let (frame, flush) = self
+            .mapper
+            .unmap(Page::<S>::containing_address(addr))
+            .ok()?;
+        flush.flush();
+        let start = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+        Some(PhysFrame::range(start, start + S::SIZE / PAGE_SIZE))
+    }
+    /// Change the flags of the page of size `S` containing `addr`.
+    pub(super) fn update_page<S: PageSize>(
+        &mut self,
+        addr: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), VmmError>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let page = Page::<S>::containing_address(addr);
+        unsafe { self.mapper.update_flags(page, flags)?.flush() };
+        Ok(())
+    }
+    /// Split the huge page containing `addr` into pages of the next smaller size, keeping its
+    /// frames and flags.
+    pub(super) fn split_huge_page(&mut self, addr: VirtAddr) -> Result<(), VmmError> {
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let l4 = self.mapper.level_4_table_mut();
+        let l3_entry = unsafe { &mut next_table(&l4[page.p4_index()])[page.p3_index()] };
+        let (entry, child_size) = if l3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            (l3_entry, Size2MiB::SIZE)
+        } else {
+            let l2_entry = unsafe { &mut next_table(l3_entry)[page.p2_index()] };
+            if !l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Ok(());
+            }
+            (l2_entry, Size4KiB::SIZE)
+        };
+
+        let frame = self.frames.allocate_frame().ok_or(VmmError::OutOfMemory)?;
+        let children =
+            unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() };
+        let flags = entry.flags();
+        let child_flags = if child_size == Size4KiB::SIZE {
+            flags - PageTableFlags::HUGE_PAGE
+        } else {
+            flags
+        };
+        for (i, child) in children.iter_mut().enumerate() {
+            child.set_addr(entry.addr() + i as u64 * child_size, child_flags);
+        }
+        let parent_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | (flags & PageTableFlags::USER_ACCESSIBLE);
+        entry.set_frame(frame, parent_flags);
+        tlb::flush(addr);
+        Ok(())
+    }
+}
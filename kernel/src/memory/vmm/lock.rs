@@ -0,0 +1,92 @@
+//! The lock around the [VMM](super::VMM), which remembers the CPU holding it.
+use super::Vmm;
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering::*},
+};
+use spinlock::{DisableInterrupts, SpinLock, SpinLockGuard};
+
+/// Marks a [VmmLock] not held by any CPU.
+const NO_OWNER: usize = usize::MAX;
+
+/// A spinlock around the [Vmm] that records which CPU holds it.
+///
+/// The lock disables interrupts, so a CPU holding it keeps running the code that took it until
+/// it's released. A page fault on that CPU while it holds the lock can therefore only come from
+/// that code, and must not wait for the lock.
+pub struct VmmLock {
+    vmm: SpinLock<Vmm, DisableInterrupts>,
+    /// The index of the CPU holding the lock, or [NO_OWNER].
+    owner: AtomicUsize,
+}
+
+/// Proof of holding a [VmmLock], releasing it when dropped.
+///
+/// If permissions were taken away while the lock was held, dropping the guard waits for every CPU
+/// to flush its TLB, so it must not be dropped with interrupts disabled.
+pub struct VmmGuard<'l> {
+    guard: ManuallyDrop<SpinLockGuard<'l, Vmm, DisableInterrupts>>,
+    owner: &'l AtomicUsize,
+}
+
+/// The index of the CPU the caller runs on. Only the bootstrap processor runs before SMP is
+/// initialized.
+fn current_cpu() -> usize {
+    crate::smp::current().map_or(0, |cpu| cpu.index())
+}
+
+impl VmmLock {
+    pub const fn new(vmm: Vmm) -> Self {
+        VmmLock {
+            vmm: SpinLock::disable_interrupts(vmm),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+    pub fn try_lock(&self) -> Option<VmmGuard<'_>> {
+        let guard = self.vmm.try_lock()?;
+        self.owner.store(current_cpu(), Relaxed);
+        Some(VmmGuard {
+            guard: ManuallyDrop::new(guard),
+            owner: &self.owner,
+        })
+    }
+    pub fn lock(&self) -> VmmGuard<'_> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    /// Returns whether the CPU the caller runs on holds the lock.
+    pub fn is_held_by_current_cpu(&self) -> bool {
+        self.owner.load(Relaxed) == current_cpu()
+    }
+}
+
+impl Drop for VmmGuard<'_> {
+    fn drop(&mut self) {
+        let pending = self.guard.pending_shootdown.take();
+        // Cleared before the spinlock is released.
+        self.owner.store(NO_OWNER, Relaxed);
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        // Other CPUs may need the lock before they get to handle the shootdown.
+        if let Some(generation) = pending {
+            crate::smp::wait_for_tlbs(generation);
+        }
+    }
+}
+
+impl Deref for VmmGuard<'_> {
+    type Target = Vmm;
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl DerefMut for VmmGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
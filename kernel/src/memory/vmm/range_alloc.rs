@@ -0,0 +1,141 @@
+use x86_64::VirtAddr;
+
+/// The maximum number of disjoint free ranges that can be tracked.
+const MAX_FREE_RANGES: usize = 128;
+
+/// A half-open range of virtual addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRange {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+}
+
+impl VirtRange {
+    pub fn new(start: VirtAddr, len: u64) -> Self {
+        VirtRange {
+            start,
+            end: start + len,
+        }
+    }
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// A first-fit allocator of virtual address ranges.
+///
+/// The free ranges are stored inline, sorted by address, so that the allocator never touches the
+/// heap. This allows it to be used while growing the heap.
+pub struct RangeAllocator {
+    free: [VirtRange; MAX_FREE_RANGES],
+    len: usize,
+}
+
+impl RangeAllocator {
+    /// Create an allocator managing the entirety of the provided range.
+    pub const fn new(range: VirtRange) -> Self {
+        let mut alloc = Self::empty();
+        alloc.free[0] = range;
+        alloc.len = 1;
+        alloc
+    }
+    /// Create an allocator without any free ranges.
+    pub const fn empty() -> Self {
+        let free = [VirtRange {
+            start: VirtAddr::zero(),
+            end: VirtAddr::zero(),
+        }; MAX_FREE_RANGES];
+        RangeAllocator { free, len: 0 }
+    }
+    fn ranges(&self) -> &[VirtRange] {
+        &self.free[..self.len]
+    }
+    /// Reserve `len` bytes of address space aligned to `align` bytes.
+    pub fn alloc(&mut self, len: u64, align: u64) -> Option<VirtRange> {
+        let (index, start) = self.ranges().iter().enumerate().find_map(|(i, range)| {
+            let start = range.start.align_up(align);
+            (start < range.end && range.end - start >= len).then_some((i, start))
+        })?;
+        let allocated = VirtRange::new(start, len);
+        self.split(index, allocated);
+        Some(allocated)
+    }
+    /// Reserve a specific range, returning false if any of it is already in use.
+    pub fn reserve(&mut self, range: VirtRange) -> bool {
+        let index = self
+            .ranges()
+            .iter()
+            .position(|free| free.start <= range.start && range.end <= free.end);
+        if let Some(index) = index {
+            self.split(index, range);
+        }
+        index.is_some()
+    }
+    /// Remove `allocated` from the free range at `index`, which contains it.
+    fn split(&mut self, index: usize, allocated: VirtRange) {
+        let range = self.free[index];
+        let before = VirtRange {
+            start: range.start,
+            end: allocated.start,
+        };
+        let after = VirtRange {
+            start: allocated.end,
+            end: range.end,
+        };
+        self.remove(index);
+        if !after.is_empty() {
+            self.insert(index, after);
+        }
+        if !before.is_empty() {
+            self.insert(index, before);
+        }
+    }
+    /// Return a range previously handed out by [Self::alloc] or [Self::reserve], merging it with
+    /// its neighbours.
+    pub fn free(&mut self, mut range: VirtRange) {
+        let index = self
+            .ranges()
+            .iter()
+            .position(|free| free.start >= range.end)
+            .unwrap_or(self.len);
+        if index < self.len && self.free[index].start == range.end {
+            range.end = self.free[index].end;
+            self.remove(index);
+        }
+        if index > 0 && self.free[index - 1].end == range.start {
+            self.free[index - 1].end = range.end;
+            return;
+        }
+        self.insert(index, range);
+    }
+    /// Free every range of this allocator in `other`, leaving this one empty.
+    pub fn move_into(&mut self, other: &mut RangeAllocator) {
+        for &range in self.ranges() {
+            other.free(range);
+        }
+        self.len = 0;
+    }
+    fn remove(&mut self, index: usize) {
+        self.free.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+    }
+    fn insert(&mut self, index: usize, range: VirtRange) {
+        assert!(
+            self.len < MAX_FREE_RANGES,
+            "Virtual address space is too fragmented"
+        );
+        self.free.copy_within(index..self.len, index + 1);
+        self.free[index] = range;
+        self.len += 1;
+    }
+    /// Returns the amount of free address space.
+    pub fn free_space(&self) -> u64 {
+        self.ranges().iter().map(VirtRange::len).sum()
+    }
+}
@@ -0,0 +1,90 @@
+//! Page tables of other address spaces, which share the kernel's mappings.
+//!
+//! A new level 4 table starts out as a copy of the kernel's, with the entries covering its private
+//! range cleared. Since the kernel's entries point to the same lower level tables, anything the
+//! kernel maps later shows up in every table, as long as the level 4 entry already existed. The
+//! [Vmm] creates the entry of its own region up front for that reason.
+use super::{VirtRange, Vmm, VmmError};
+use crate::memory::phys_to_virt;
+use core::ops::Range;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+    Size4KiB,
+};
+
+/// Returns the table stored in `frame`.
+///
+/// # Safety
+/// The frame must hold a page table that is not otherwise borrowed.
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() }
+}
+
+/// The level 4 indices covering `range`.
+fn p4_indices(range: VirtRange) -> Range<usize> {
+    let start = Page::<Size4KiB>::containing_address(range.start).p4_index();
+    let end = Page::<Size4KiB>::containing_address(range.end - 1u64).p4_index();
+    usize::from(start)..usize::from(end) + 1
+}
+
+impl Vmm {
+    /// Create an empty page table under the level 4 entry at `index` of the kernel's table.
+    pub(super) fn populate_entry(&mut self, index: usize) -> Result<(), VmmError> {
+        let frame = self.frames.allocate_frame().ok_or(VmmError::OutOfMemory)?;
+        unsafe { table_mut(frame) }.zero();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        self.mapper.level_4_table_mut()[index].set_frame(frame, flags);
+        Ok(())
+    }
+    /// Create a level 4 table sharing the kernel's mappings, except for the entries covering
+    /// `private`, which start out empty.
+    pub fn create_table(&mut self, private: VirtRange) -> Result<PhysFrame, VmmError> {
+        let frame = self.frames.allocate_frame().ok_or(VmmError::OutOfMemory)?;
+        let table = unsafe { table_mut(frame) };
+        for (entry, kernel) in table.iter_mut().zip(self.mapper.level_4_table().iter()) {
+            *entry = kernel.clone();
+        }
+        for index in p4_indices(private) {
+            table[index].set_unused();
+        }
+        Ok(frame)
+    }
+    /// Run `f` on the page table rooted at `table` instead of the kernel's. The kernel's mappings
+    /// are shared, so only the private entries of the table behave differently.
+    pub fn with_table<T>(&mut self, table: PhysFrame, f: impl FnOnce(&mut Self) -> T) -> T {
+        let phys_offset = self.mapper.phys_offset();
+        let mapper = unsafe { OffsetPageTable::new(table_mut(table), phys_offset) };
+        let kernel = core::mem::replace(&mut self.mapper, mapper);
+        let result = f(self);
+        self.mapper = kernel;
+        result
+    }
+    /// Free a table returned by [Self::create_table], along with the page tables under its
+    /// private entries.
+    ///
+    /// # Safety
+    /// Everything in the private range must already be unmapped, and the table must not be in use
+    /// by any CPU.
+    pub unsafe fn free_table(&mut self, table: PhysFrame, private: VirtRange) {
+        let l4 = unsafe { table_mut(table) };
+        for index in p4_indices(private) {
+            if let Ok(frame) = l4[index].frame() {
+                unsafe { self.free_subtables(frame, 3) };
+            }
+        }
+        unsafe { self.frames.deallocate_frame(table) };
+    }
+    /// Free the table at `level` stored in `frame`, and every table below it.
+    unsafe fn free_subtables(&mut self, frame: PhysFrame, level: u8) {
+        if level > 1 {
+            let table = unsafe { table_mut(frame) };
+            for entry in table.iter() {
+                // Huge pages are mapped frames rather than tables, and must be unmapped already.
+                if let Ok(frame) = entry.frame() {
+                    unsafe { self.free_subtables(frame, level - 1) };
+                }
+            }
+        }
+        unsafe { self.frames.deallocate_frame(frame) };
+    }
+}
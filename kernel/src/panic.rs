@@ -7,6 +7,11 @@ pub fn set_qemu_test_panic() {
     QEMU_TEST_PANIC.store(true, core::sync::atomic::Ordering::Release);
 }
 
+/// Returns whether the kernel is running under the QEMU test harness.
+pub fn is_qemu_test() -> bool {
+    QEMU_TEST_PANIC.load(core::sync::atomic::Ordering::Acquire)
+}
+
 /// # Safety
 /// The caller must ensure the VGA_OUT writer is not accessed by other threads when this function
 /// is called
@@ -16,6 +21,7 @@ pub fn set_qemu_test_panic() {
 #[panic_handler]
 pub unsafe fn panic(info: &PanicInfo) -> ! {
     use crate::prelude::{vga_color::*, *};
+    let backtrace = crate::backtrace::Backtrace::capture();
     if QEMU_TEST_PANIC.load(core::sync::atomic::Ordering::Acquire) {
         use crate::prelude::*;
         use crate::qemu::{QemuExitCode, exit_qemu};
@@ -26,11 +32,14 @@ pub unsafe fn panic(info: &PanicInfo) -> ! {
         let out = unsafe { &mut *SERIAL1.get_inner_mut() };
         _ = writeln!(out, "[failed]\n");
         _ = writeln!(out, "Error: {}\n", info);
+        _ = writeln!(out, "{backtrace}");
         exit_qemu(QemuExitCode::Failed);
     }
 
     // SAFETY: The panic may have happened inside a fmt::Display implementation,
     // which would leave the SpinLock locked forever.
     println!(fgcolor = LightRed, bgcolor = Black, "{info}");
+    println!("{backtrace}");
+    serial_println!("{info}\n{backtrace}");
     crate::hlt_loop()
 }
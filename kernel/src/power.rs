@@ -0,0 +1,209 @@
+//! Powering off and rebooting the machine.
+//!
+//! Both go through ACPI when possible. Rebooting falls back to the keyboard controller and
+//! finally a triple fault.
+use crate::{
+    acpi::{AddressSpace, Fadt},
+    memory::vmm::VMM,
+    prelude::*,
+};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+};
+
+/// PM1 control register bits.
+const SCI_EN: u16 = 1;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+/// How many times to poll hardware before giving up on it.
+const POLL_LIMIT: usize = 1_000_000;
+
+/// Power off the machine. When running tests, QEMU is exited through the `isa-debug-exit`
+/// device instead.
+pub fn shutdown() -> ! {
+    if crate::panic::is_qemu_test() {
+        crate::qemu::exit_qemu(crate::qemu::QemuExitCode::Success);
+    }
+    interrupts::disable();
+    if let Some(fadt) = Fadt::get() {
+        acpi_shutdown(&fadt);
+    }
+    println!("Failed to power off, please shut down the system.");
+    crate::hlt_loop()
+}
+
+/// Reboot the machine.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Some(fadt) = Fadt::get() {
+        acpi_reset(&fadt);
+    }
+    keyboard_controller_reset();
+    triple_fault()
+}
+
+/// Enter the S5 sleep state, which powers the machine off.
+fn acpi_shutdown(fadt: &Fadt) -> Option<()> {
+    let dsdt = fadt.dsdt_table()?;
+    let (slp_typ_a, slp_typ_b) = s5_sleep_type(dsdt.data())?;
+    enable_acpi(fadt);
+    for (block, slp_typ) in [
+        (fadt.pm1a_control_block(), slp_typ_a),
+        (fadt.pm1b_control_block(), slp_typ_b),
+    ] {
+        if block == 0 {
+            continue;
+        }
+        let mut port = Port::<u16>::new(block as u16);
+        unsafe {
+            let value = port.read() & !SLP_TYP_MASK;
+            port.write(value | (slp_typ << SLP_TYP_SHIFT) | SLP_EN);
+        }
+    }
+    // Powering off is not instantaneous.
+    (0..POLL_LIMIT).for_each(|_| core::hint::spin_loop());
+    Some(())
+}
+
+/// Switch the system into ACPI mode if the firmware hasn't already.
+fn enable_acpi(fadt: &Fadt) {
+    let mut pm1a = Port::<u16>::new(fadt.pm1a_control_block() as u16);
+    let smi = fadt.smi_command_port();
+    if unsafe { pm1a.read() } & SCI_EN != 0 || smi == 0 || fadt.acpi_enable() == 0 {
+        return;
+    }
+    unsafe { Port::<u8>::new(smi as u16).write(fadt.acpi_enable()) };
+    for _ in 0..POLL_LIMIT {
+        if unsafe { pm1a.read() } & SCI_EN != 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Find the `SLP_TYPa` and `SLP_TYPb` values of the `\_S5` package in the DSDT's AML.
+fn s5_sleep_type(aml: &[u8]) -> Option<(u16, u16)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    let pos = aml.windows(4).position(|name| name == b"_S5_")?;
+    // The name must be defined with a NameOp, optionally with a root prefix.
+    if !matches!(aml[..pos], [.., NAME_OP] | [.., NAME_OP, b'\\']) {
+        return None;
+    }
+    let mut rest = aml.get(pos + 4..)?;
+    if *rest.first()? != PACKAGE_OP {
+        return None;
+    }
+    // The top 2 bits of the first PkgLength byte hold the number of extra length bytes.
+    let pkg_length_bytes = (*rest.get(1)? >> 6) as usize + 1;
+    // Skip the PackageOp, the PkgLength and the element count.
+    rest = rest.get(1 + pkg_length_bytes + 1..)?;
+    let slp_typ_a = aml_integer(&mut rest)?;
+    let slp_typ_b = aml_integer(&mut rest)?;
+    Some((slp_typ_a, slp_typ_b))
+}
+
+/// Parse a small AML integer constant, advancing past it.
+fn aml_integer(aml: &mut &[u8]) -> Option<u16> {
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    const BYTE_PREFIX: u8 = 0x0a;
+    const WORD_PREFIX: u8 = 0x0b;
+    const DWORD_PREFIX: u8 = 0x0c;
+    let (value, len) = match *aml.first()? {
+        ZERO_OP => (0, 1),
+        ONE_OP => (1, 1),
+        BYTE_PREFIX => (*aml.get(1)? as u32, 2),
+        WORD_PREFIX => (
+            u16::from_le_bytes(aml.get(1..3)?.try_into().ok()?) as u32,
+            3,
+        ),
+        DWORD_PREFIX => (u32::from_le_bytes(aml.get(1..5)?.try_into().ok()?), 5),
+        _ => return None,
+    };
+    *aml = aml.get(len..)?;
+    value.try_into().ok()
+}
+
+/// Write the reset value to the FADT's reset register.
+fn acpi_reset(fadt: &Fadt) -> Option<()> {
+    let register = fadt.reset_register()?;
+    let value = fadt.reset_value();
+    match register.space {
+        AddressSpace::SystemIo => unsafe { Port::new(register.address as u16).write(value) },
+        AddressSpace::SystemMemory => {
+            let mut vmm = VMM.get_if_init()?.try_lock()?;
+            let addr = unsafe { vmm.map_mmio(PhysAddr::new(register.address), 1) }.ok()?;
+            unsafe { addr.as_mut_ptr::<u8>().write_volatile(value) };
+            vmm.unmap_mmio(addr, 1);
+        }
+        _ => return None,
+    }
+    (0..POLL_LIMIT).for_each(|_| core::hint::spin_loop());
+    Some(())
+}
+
+/// Pulse the CPU reset line through the 8042 keyboard controller.
+fn keyboard_controller_reset() {
+    let mut command = Port::<u8>::new(0x64);
+    for _ in 0..POLL_LIMIT {
+        // Wait for the input buffer to be empty
+        if unsafe { command.read() } & 0b10 == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { command.write(0xfe) };
+    (0..POLL_LIMIT).for_each(|_| core::hint::spin_loop());
+}
+
+/// Reset the CPU by raising an exception with an empty IDT.
+fn triple_fault() -> ! {
+    let idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        lidt(&idt);
+        core::arch::asm!("int3");
+    }
+    crate::hlt_loop()
+}
+
+#[test_case]
+fn s5_with_byte_constants() {
+    let aml = b"\x10\x08_S5_\x12\x08\x04\x0a\x05\x0a\x06\x00\x00";
+    assert_eq!(s5_sleep_type(aml), Some((5, 6)));
+}
+
+#[test_case]
+fn s5_with_root_prefix_and_zero_ops() {
+    let aml = b"\x08\\_S5_\x12\x06\x04\x00\x01\x00\x00";
+    assert_eq!(s5_sleep_type(aml), Some((0, 1)));
+}
+
+#[test_case]
+fn s5_with_word_constants() {
+    let aml = b"\x08_S5_\x12\x0a\x04\x0b\x07\x00\x0b\x02\x01\x00\x00";
+    assert_eq!(s5_sleep_type(aml), Some((7, 0x0102)));
+}
+
+#[test_case]
+fn s5_with_long_package_length() {
+    let aml = b"\x08_S5_\x12\x40\x01\x04\x0c\x03\x00\x00\x00\x0a\x03\x00\x00";
+    assert_eq!(s5_sleep_type(aml), Some((3, 3)));
+}
+
+#[test_case]
+fn s5_must_be_a_named_package() {
+    // Referenced by a Store rather than defined
+    assert_eq!(s5_sleep_type(b"\x70_S5_\x12\x06\x04\x00\x00\x00\x00"), None);
+    // Not a package
+    assert_eq!(s5_sleep_type(b"\x08_S5_\x0a\x05"), None);
+    // Truncated
+    assert_eq!(s5_sleep_type(b"\x08_S5_\x12\x08\x04\x0b\x07"), None);
+}
@@ -0,0 +1,233 @@
+//! Processes: user programs with their own address space and handles.
+//!
+//! Every process runs on a single kernel [thread], which enters user mode in
+//! [user::run](crate::user::run) or [user::exec](crate::user::exec). The thread runs on the page
+//! table of the process, which the scheduler switches to along with the thread. Once the program
+//! exits, its memory and handles are freed, and the process stays in the table until it is
+//! [reaped](reap).
+//!
+//! A [killed](kill) process exits the next time it would return to user mode, at the end of a
+//! syscall or of an interrupt. A program blocked in a syscall only exits once it returns.
+use crate::{
+    memory,
+    thread::{self, Thread},
+    user::{self, AddressSpace},
+};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering::*},
+};
+use spinlock::{DisableInterrupts, SpinLock};
+use x86_64::{
+    PrivilegeLevel,
+    instructions::interrupts,
+    structures::{idt::InterruptStackFrame, paging::PhysFrame},
+};
+
+/// The exit code of killed processes, the one shells report for `SIGKILL`.
+pub const KILLED_EXIT_CODE: i64 = 128 + 9;
+
+static PROCESSES: SpinLock<BTreeMap<Pid, Arc<Process>>, DisableInterrupts> =
+    SpinLock::disable_interrupts(BTreeMap::new());
+
+/// A unique process ID, starting at 1.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT.fetch_add(1, Relaxed))
+    }
+    pub fn from_u64(pid: u64) -> Option<Self> {
+        (pid != 0).then_some(Pid(pid))
+    }
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// The program exited with the code, and the process is waiting to be reaped.
+    Exited(i64),
+}
+
+/// A kernel object a process refers to by its index in its handle table, like a file descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handle {
+    Keyboard,
+    Vga,
+    Serial,
+}
+
+pub struct Process {
+    pid: Pid,
+    parent: Option<Pid>,
+    name: String,
+    thread: Thread,
+    /// The level 4 page table of the address space.
+    table: PhysFrame,
+    killed: AtomicBool,
+    inner: SpinLock<Inner, DisableInterrupts>,
+}
+
+struct Inner {
+    state: State,
+    /// The memory of the process, `None` once it has exited.
+    space: Option<AddressSpace>,
+    handles: Vec<Option<Handle>>,
+}
+
+impl fmt::Debug for Process {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Process")
+            .field("pid", &self.pid)
+            .field("name", &self.name)
+            .field("state", &self.state())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+    /// The process of the thread that started this one, if any.
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+    pub fn state(&self) -> State {
+        self.inner.lock().state
+    }
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Acquire)
+    }
+    /// Returns the handle at index `fd` of the handle table.
+    pub fn handle(&self, fd: u64) -> Option<Handle> {
+        let inner = self.inner.lock();
+        *inner.handles.get(usize::try_from(fd).ok()?)?
+    }
+    /// Run `f` on the address space of the process, unless it has exited.
+    pub fn with_space<T>(&self, f: impl FnOnce(&mut AddressSpace) -> T) -> Option<T> {
+        self.inner.lock().space.as_mut().map(f)
+    }
+    /// Record that the program exited with `code`, switch the current thread back to the
+    /// kernel's page table and free the memory and handles of the process. Must be called on the
+    /// thread of the process.
+    pub(crate) fn exit(&self, code: i64) {
+        let code = if self.is_killed() {
+            KILLED_EXIT_CODE
+        } else {
+            code
+        };
+        let (space, handles) = interrupts::without_interrupts(|| {
+            self.thread.set_process(None);
+            self.thread.set_page_table(None);
+            memory::activate_table(memory::kernel_table());
+            let mut inner = self.inner.lock();
+            inner.state = State::Exited(code);
+            (inner.space.take(), core::mem::take(&mut inner.handles))
+        });
+        drop(space);
+        drop(handles);
+    }
+}
+
+/// Create a process for the current thread, running in `space`, and switch to its page table.
+/// The handle table starts out with the keyboard, the VGA console and the serial port.
+pub(crate) fn start(name: &str, space: AddressSpace) -> Arc<Process> {
+    let thread = thread::current();
+    let process = Arc::new(Process {
+        pid: Pid::new(),
+        parent: current().map(|parent| parent.pid),
+        name: name.into(),
+        table: space.table(),
+        thread: thread.clone(),
+        killed: AtomicBool::new(false),
+        inner: SpinLock::disable_interrupts(Inner {
+            state: State::Running,
+            space: Some(space),
+            handles: vec![
+                Some(Handle::Keyboard),
+                Some(Handle::Vga),
+                Some(Handle::Serial),
+            ],
+        }),
+    });
+    PROCESSES.lock().insert(process.pid, process.clone());
+    interrupts::without_interrupts(|| {
+        thread.set_process(Some(process.pid));
+        thread.set_page_table(Some(process.table));
+        memory::activate_table(process.table);
+    });
+    process
+}
+
+/// Returns the process the current thread runs.
+pub fn current() -> Option<Arc<Process>> {
+    let pid = thread::try_current()?.process()?;
+    get(pid)
+}
+
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// Returns every process in the table, ordered by PID.
+pub fn list() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+/// Remove an exited process from the table, returning its exit code. Returns `None` if there is
+/// no such process, or if it is still running.
+pub fn reap(pid: Pid) -> Option<i64> {
+    let mut processes = PROCESSES.lock();
+    let State::Exited(code) = processes.get(&pid)?.state() else {
+        return None;
+    };
+    processes.remove(&pid);
+    Some(code)
+}
+
+/// Make a process exit with [KILLED_EXIT_CODE]. Returns false if there is no running process with
+/// the PID.
+pub fn kill(pid: Pid) -> bool {
+    let Some(process) = get(pid) else {
+        return false;
+    };
+    if process.state() != State::Running {
+        return false;
+    }
+    process.killed.store(true, Release);
+    // Make a program blocked in a syscall return from it.
+    process.thread.unpark();
+    // Make a program that never makes syscalls enter the kernel.
+    process.thread.interrupt();
+    true
+}
+
+/// Exit the current program if its process was killed and the interrupt came from user mode.
+/// Called at the end of interrupt handlers, after the end of interrupt was signalled.
+pub fn exit_if_killed(frame: &InterruptStackFrame) {
+    if frame.code_segment.rpl() == PrivilegeLevel::Ring3
+        && current().is_some_and(|process| process.is_killed())
+    {
+        user::exit(KILLED_EXIT_CODE);
+    }
+}
@@ -0,0 +1,284 @@
+//! Symmetric multiprocessing.
+//!
+//! The application processors (APs) listed in the MADT are started with the INIT-SIPI-SIPI
+//! sequence through a real mode [trampoline]. Every CPU gets its own GDT, TSS, IDT and stack, and
+//! a [PerCpu] block reachable through its `KERNEL_GS_BASE` MSR, which user code can't change.
+//!
+//! Once started, APs run an [Executor] in their boot [thread], which picks up tasks queued with
+//! [spawn_shared](crate::task::executor::spawn_shared), and run other threads when it is idle.
+mod trampoline;
+
+use crate::{
+    acpi::{Madt, MadtEntry},
+    clock::Instant,
+    gdt::CpuTables,
+    interrupts::{self, apic},
+    memory::stack::KernelStack,
+    task::executor::Executor,
+    thread::{self, CpuScheduler},
+};
+use alloc::{boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::*};
+use spinlock::{DisableInterrupts, SpinLock};
+use trampoline::{Args, Trampoline};
+use x86_64::{
+    VirtAddr, registers::model_specific::KernelGsBase, structures::idt::InterruptDescriptorTable,
+    structures::tss::TaskStateSegment,
+};
+
+/// The number of pages in the initial stack of an AP.
+const AP_STACK_PAGES: usize = 16;
+/// The MADT local APIC flag marking a usable processor.
+const PROCESSOR_ENABLED: u32 = 1;
+
+/// Every CPU that has been brought up, in order of their index.
+static CPUS: SpinLock<Vec<&'static PerCpu>, DisableInterrupts> =
+    SpinLock::disable_interrupts(Vec::new());
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+/// Set once the kernel GS base of the bootstrap processor points to its [PerCpu].
+static READY: AtomicBool = AtomicBool::new(false);
+/// Set by an AP once it's done using the trampoline.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+/// The number of TLB shootdowns started so far, see [flush_remote_tlbs].
+static TLB_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Data owned by a single CPU.
+pub struct PerCpu {
+    /// The stack the kernel is entered on by `syscall`, see [PerCpu::set_kernel_stack].
+    pub(crate) kernel_stack: AtomicU64,
+    /// Scratch space for the user stack pointer while switching to the kernel stack.
+    pub(crate) user_stack: AtomicU64,
+    /// The deadline in milliseconds the [tickless](crate::clock::tickless) timer of this CPU is
+    /// armed for, `u64::MAX` if none.
+    pub(crate) timer_deadline: AtomicU64,
+    /// The last TLB shootdown generation this CPU has flushed its TLB for.
+    tlb_generation: AtomicU64,
+    index: usize,
+    apic_id: u8,
+    tables: CpuTables,
+    idt: &'static InterruptDescriptorTable,
+    pub(crate) scheduler: CpuScheduler,
+}
+
+impl PerCpu {
+    fn new(
+        index: usize,
+        apic_id: u8,
+        tables: CpuTables,
+        idt: &'static InterruptDescriptorTable,
+    ) -> &'static PerCpu {
+        Box::leak(Box::new(PerCpu {
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            timer_deadline: AtomicU64::new(u64::MAX),
+            tlb_generation: AtomicU64::new(TLB_GENERATION.load(Acquire)),
+            index,
+            apic_id,
+            tables,
+            idt,
+            scheduler: CpuScheduler::new(),
+        }))
+    }
+    /// The index of the CPU, 0 for the bootstrap processor.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+    /// The TSS loaded on this CPU.
+    pub fn tss(&self) -> &'static SpinLock<TaskStateSegment, DisableInterrupts> {
+        self.tables.tss
+    }
+    /// Set the stack the CPU switches to when entering the kernel from user mode, both through
+    /// interrupts (RSP0 in the TSS) and `syscall`.
+    pub fn set_kernel_stack(&self, top: VirtAddr) {
+        self.tables.tss.lock().privilege_stack_table[0] = top;
+        self.kernel_stack.store(top.as_u64(), Relaxed);
+    }
+    /// Returns whether this is the CPU the caller is running on.
+    pub fn is_current(&self) -> bool {
+        current().is_some_and(|cpu| core::ptr::eq(cpu, self))
+    }
+    /// Interrupt the CPU so that it notices new work if it is halted.
+    pub fn wake(&self) {
+        if !self.is_current() {
+            apic::send_ipi(self.apic_id, apic::WAKEUP_VECTOR);
+        }
+    }
+}
+
+/// Returns the [PerCpu] block of the current CPU, if [init] has been called.
+pub fn current() -> Option<&'static PerCpu> {
+    if !READY.load(Acquire) {
+        return None;
+    }
+    let cpu = KernelGsBase::read().as_ptr::<PerCpu>();
+    // SAFETY: The kernel GS base of every CPU is set to its leaked PerCpu by `online`, and only
+    // ever swapped with the user GS base while interrupts are disabled.
+    Some(unsafe { &*cpu })
+}
+
+/// Returns the number of CPUs running.
+pub fn cpu_count() -> usize {
+    ONLINE.load(Acquire).max(1)
+}
+
+/// Returns every CPU that has been brought up.
+pub fn cpus() -> Vec<&'static PerCpu> {
+    CPUS.lock().clone()
+}
+
+/// Run `f` for every CPU that has been brought up, without allocating.
+pub fn for_each_cpu(f: impl FnMut(&'static PerCpu)) {
+    CPUS.lock().iter().copied().for_each(f);
+}
+
+/// Make every CPU flush its TLB after the page tables were changed, returning the generation of
+/// the shootdown. The CPUs are not waited for, see [tlbs_flushed] and [wait_for_tlbs].
+///
+/// The current CPU is interrupted as well, since it may not have handled earlier shootdowns yet.
+pub fn flush_remote_tlbs() -> u64 {
+    let generation = TLB_GENERATION.fetch_add(1, AcqRel) + 1;
+    if cpu_count() > 1 {
+        apic::broadcast_ipi_including_self(apic::TLB_SHOOTDOWN_VECTOR);
+    } else if let Some(cpu) = current() {
+        // Every mapping was removed by this CPU, which flushed it from its own TLB.
+        cpu.tlb_generation.fetch_max(generation, Release);
+    }
+    generation
+}
+
+/// Returns the generation of the next TLB shootdown, the first one to flush mappings removed now.
+pub fn next_tlb_generation() -> u64 {
+    TLB_GENERATION.load(Acquire) + 1
+}
+
+/// Returns whether every CPU has flushed its TLB for the shootdown of the given generation.
+/// Conservatively returns false if the list of CPUs is locked, so it can be called with other
+/// locks held.
+pub fn tlbs_flushed(generation: u64) -> bool {
+    CPUS.try_lock().is_some_and(|cpus| {
+        cpus.iter()
+            .all(|cpu| cpu.tlb_generation.load(Acquire) >= generation)
+    })
+}
+
+/// Wait until every CPU has flushed its TLB for the shootdown of the given generation. Must be
+/// called with interrupts enabled, so that the current CPU can handle the shootdown itself.
+pub fn wait_for_tlbs(generation: u64) {
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    while !tlbs_flushed(generation) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Flush the TLB of the current CPU in response to a shootdown.
+pub(crate) fn handle_tlb_shootdown() {
+    let generation = TLB_GENERATION.load(Acquire);
+    x86_64::instructions::tlb::flush_all();
+    if let Some(cpu) = current() {
+        cpu.tlb_generation.fetch_max(generation, Release);
+    }
+}
+
+fn online(cpu: &'static PerCpu) {
+    KernelGsBase::write(VirtAddr::from_ptr(cpu));
+    CPUS.lock().push(cpu);
+    ONLINE.fetch_add(1, Release);
+}
+
+/// Set up the [PerCpu] block of the bootstrap processor and start the application processors.
+/// Requires the APIC and the clock to be initialized.
+pub fn init() {
+    if READY.load(Acquire) {
+        return;
+    }
+    let bsp_id = apic::local_apic_id().unwrap_or(0);
+    online(PerCpu::new(0, bsp_id, CpuTables::bsp(), interrupts::idt()));
+    READY.store(true, Release);
+    crate::clock::tickless::smp_ready();
+
+    if !apic::is_enabled() {
+        return;
+    }
+    let Some(madt) = Madt::get() else {
+        return;
+    };
+    let aps: Vec<u8> = madt
+        .entries()
+        .filter_map(|entry| match entry {
+            MadtEntry::LocalApic { apic_id, flags, .. }
+                if flags & PROCESSOR_ENABLED != 0 && apic_id != bsp_id =>
+            {
+                Some(apic_id)
+            }
+            _ => None,
+        })
+        .collect();
+    if aps.is_empty() {
+        return;
+    }
+    let Some(mut trampoline) = Trampoline::new() else {
+        crate::serial_println!("No memory below 1MiB is free, not starting the APs");
+        return;
+    };
+    for apic_id in aps {
+        if !start_ap(&mut trampoline, apic_id) {
+            crate::serial_println!("The AP with APIC ID {apic_id} did not start");
+        }
+    }
+}
+
+/// Start the AP with the given local APIC ID, returning whether it came online.
+fn start_ap(trampoline: &mut Trampoline, apic_id: u8) -> bool {
+    let (Ok(tables), Ok(stack)) = (CpuTables::new(), KernelStack::new(AP_STACK_PAGES)) else {
+        return false;
+    };
+    let index = CPUS.lock().len();
+    let cpu = PerCpu::new(index, apic_id, tables, interrupts::new_idt());
+    trampoline.set_args(Args::new(
+        stack.top(),
+        ap_entry,
+        cpu as *const PerCpu as u64,
+    ));
+    // APs are never shut down, and one that is late to start may still use its stack.
+    core::mem::forget(stack);
+
+    AP_STARTED.store(false, Release);
+    apic::send_init(apic_id);
+    wait_ms(10);
+    // The second startup IPI is only needed if the first one was missed.
+    for timeout in [1, 100] {
+        apic::send_startup(apic_id, trampoline.page());
+        let start = Instant::now();
+        while start.elapsed_ms() < timeout {
+            if AP_STARTED.load(Acquire) {
+                return true;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    false
+}
+
+fn wait_ms(ms: u64) {
+    let start = Instant::now();
+    while start.elapsed_ms() < ms {
+        core::hint::spin_loop();
+    }
+}
+
+/// The long mode entry point of APs, called by the trampoline.
+extern "C" fn ap_entry(cpu: u64) -> ! {
+    let cpu = unsafe { &*(cpu as *const PerCpu) };
+    cpu.tables.load();
+    cpu.idt.load();
+    apic::init_ap();
+    online(cpu);
+    crate::user::init();
+    thread::init();
+    AP_STARTED.store(true, Release);
+    x86_64::instructions::interrupts::enable();
+    Executor::new().run_forever()
+}
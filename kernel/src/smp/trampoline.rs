@@ -0,0 +1,219 @@
+//! The real mode entry point of application processors.
+//!
+//! A startup IPI starts a CPU in real mode at a page-aligned address below 1MiB, so the
+//! trampoline is copied to such a page, which is identity mapped while the APs start. It loads a
+//! temporary GDT, switches to protected mode and then to long mode with the kernel's page table,
+//! and finally calls into the kernel on the stack given in its [Args].
+use crate::memory::{
+    phys_to_virt,
+    vmm::{VMM, VirtRange},
+};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    registers::control::{Cr0, Cr3, Cr4, Cr4Flags},
+    structures::paging::{PageTableFlags, frame::PhysFrameRange},
+};
+
+/// Startup IPIs can only start CPUs at pages below 1MiB.
+const REAL_MODE_LIMIT: u64 = 0x10_0000;
+const PAGE_SIZE: u64 = 4096;
+
+core::arch::global_asm!(
+    r#"
+.section .text.ap_trampoline, "ax"
+.balign 16
+.global ap_trampoline_start
+.global ap_trampoline_args
+.global ap_trampoline_end
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    xor %ebx, %ebx
+    mov %ax, %bx
+    shl $4, %ebx
+    # ebx now holds the physical address of the trampoline, patch it into the absolute pointers.
+    lea (gdt - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (gdt_pointer - ap_trampoline_start + 2)
+    lea (protected_mode - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (protected_mode_pointer - ap_trampoline_start)
+    lea (long_mode - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (long_mode_pointer - ap_trampoline_start)
+    lgdtl (gdt_pointer - ap_trampoline_start)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl *(protected_mode_pointer - ap_trampoline_start)
+
+.code32
+protected_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov (ap_trampoline_args - ap_trampoline_start + 16)(%ebx), %eax
+    mov %eax, %cr4
+    mov (ap_trampoline_args - ap_trampoline_start)(%ebx), %eax
+    mov %eax, %cr3
+    # Enable long mode and no-execute pages in the EFER
+    mov $0xc0000080, %ecx
+    rdmsr
+    or $0x900, %eax
+    wrmsr
+    mov (ap_trampoline_args - ap_trampoline_start + 8)(%ebx), %eax
+    mov %eax, %cr0
+    ljmpl *(long_mode_pointer - ap_trampoline_start)(%ebx)
+
+.code64
+long_mode:
+    xor %eax, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+    mov (ap_trampoline_args - ap_trampoline_start + 24)(%rbx), %rsp
+    mov (ap_trampoline_args - ap_trampoline_start + 32)(%rbx), %rax
+    mov (ap_trampoline_args - ap_trampoline_start + 40)(%rbx), %rdi
+    call *%rax
+    ud2
+
+.balign 8
+gdt:
+    .quad 0
+    # 32-bit code
+    .quad 0x00cf9a000000ffff
+    # 32-bit data
+    .quad 0x00cf92000000ffff
+    # 64-bit code
+    .quad 0x00af9a000000ffff
+gdt_pointer:
+    .word gdt_pointer - gdt - 1
+    .long 0
+protected_mode_pointer:
+    .long 0
+    .word 0x08
+long_mode_pointer:
+    .long 0
+    .word 0x18
+
+.balign 8
+ap_trampoline_args:
+    .space 48
+ap_trampoline_end:
+.text
+"#,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_args: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The parameters read by the trampoline, at `ap_trampoline_args`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Args {
+    pub cr3: u64,
+    pub cr0: u64,
+    pub cr4: u64,
+    pub stack: VirtAddr,
+    /// The function called in long mode, with `arg` as its first argument.
+    pub entry: extern "C" fn(u64) -> !,
+    pub arg: u64,
+}
+
+impl Args {
+    /// Arguments entering `entry` with the control registers and page table of the current CPU.
+    pub fn new(stack: VirtAddr, entry: extern "C" fn(u64) -> !, arg: u64) -> Self {
+        let (table, _) = Cr3::read();
+        let cr3 = table.start_address().as_u64();
+        assert!(
+            cr3 < 1 << 32,
+            "The level 4 page table must be below 4GiB to start APs"
+        );
+        Args {
+            cr3,
+            cr0: Cr0::read_raw(),
+            // PCID can't be enabled outside of long mode.
+            cr4: (Cr4::read() - Cr4Flags::PCID).bits(),
+            stack,
+            entry,
+            arg,
+        }
+    }
+}
+
+/// The trampoline copied to an identity mapped page below 1MiB.
+pub struct Trampoline {
+    frames: PhysFrameRange,
+    /// Whether the identity mapping was created by [Trampoline::new], rather than already
+    /// present.
+    mapped: bool,
+}
+
+impl Trampoline {
+    /// Copy the trampoline to a free page below 1MiB and identity map it.
+    pub fn new() -> Option<Self> {
+        let start = &raw const ap_trampoline_start;
+        let len = unsafe { (&raw const ap_trampoline_end).offset_from(start) } as usize;
+        assert!(len as u64 <= PAGE_SIZE);
+
+        let mut vmm = VMM.lock();
+        // The lowest free frame is returned, so if it's not below 1MiB none are.
+        let frames = vmm.frames().allocate_contiguous(1, 1)?;
+        let phys = frames.start.start_address();
+        if phys.as_u64() >= REAL_MODE_LIMIT {
+            unsafe { vmm.frames().deallocate_contiguous(frames) };
+            return None;
+        }
+        let identity = VirtRange::new(VirtAddr::new(phys.as_u64()), PAGE_SIZE);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let mapped = unsafe { vmm.map_phys_range(identity, phys, flags) }.is_ok();
+        let executable = vmm
+            .flags(identity.start)
+            .is_some_and(|flags| !flags.contains(PageTableFlags::NO_EXECUTE));
+        if !mapped && (vmm.translate(identity.start) != Some(phys) || !executable) {
+            unsafe { vmm.frames().deallocate_contiguous(frames) };
+            return None;
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(start, phys_to_virt(phys).as_mut_ptr::<u8>(), len);
+        }
+        Some(Trampoline { frames, mapped })
+    }
+    fn phys(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+    /// The page number to send in the startup IPI.
+    pub fn page(&self) -> u8 {
+        (self.phys().as_u64() / PAGE_SIZE) as u8
+    }
+    /// Set the arguments read by the next CPU to run the trampoline.
+    pub fn set_args(&mut self, args: Args) {
+        let offset =
+            unsafe { (&raw const ap_trampoline_args).offset_from(&raw const ap_trampoline_start) };
+        let ptr = (phys_to_virt(self.phys()) + offset as u64).as_mut_ptr::<Args>();
+        unsafe { ptr.write_volatile(args) };
+    }
+}
+
+impl Drop for Trampoline {
+    fn drop(&mut self) {
+        let mut vmm = VMM.lock();
+        if self.mapped {
+            vmm.unmap_range(VirtRange::new(
+                VirtAddr::new(self.phys().as_u64()),
+                PAGE_SIZE,
+            ));
+        }
+        // Other CPUs may still have the identity mapping cached.
+        unsafe { vmm.frames().retire_contiguous(self.frames) };
+        crate::smp::flush_remote_tlbs();
+    }
+}
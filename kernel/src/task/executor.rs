@@ -1,20 +1,41 @@
 use super::{Task, TaskId, TaskInnerFuture};
+use crate::thread::{self, Thread};
 use alloc::{
     boxed::Box,
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     sync::Arc,
     task::Wake,
+    vec::Vec,
 };
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
 use spinlock::{DisableInterrupts, SpinLock};
 use x86_64::instructions::interrupts;
 
+/// Tasks that can be run by the executor of any CPU, see [spawn_shared].
+static SHARED_TASKS: SpinLock<VecDeque<Task>, DisableInterrupts> =
+    SpinLock::disable_interrupts(VecDeque::new());
+
+/// The threads of idle executors, waiting for [shared](spawn_shared) tasks.
+static SHARED_WAITERS: SpinLock<Vec<Thread>, DisableInterrupts> =
+    SpinLock::disable_interrupts(Vec::new());
+
+/// Queue a task to be run by whichever CPU's executor picks it up first.
+pub fn spawn_shared(task: Task) {
+    SHARED_TASKS.lock().push_back(task);
+    let waiters = core::mem::take(&mut *SHARED_WAITERS.lock());
+    for waiter in waiters {
+        waiter.unpark();
+    }
+}
+
 pub struct Executor {
     tasks: BTreeMap<TaskId, TaskInnerFuture>,
     to_be_woken: Arc<SpinLock<BTreeSet<TaskId>, DisableInterrupts>>,
     wakers: BTreeMap<TaskId, Waker>,
     spawner: Arc<ArrayQueue<Task>>,
+    /// The thread the executor was created on, which wakers unpark to run their task.
+    thread: Option<Thread>,
 }
 
 /// Allows spawning tasks onto the Executor from nested tasks running within it.
@@ -50,6 +71,7 @@ impl Executor {
             wakers: BTreeMap::new(),
             spawner: Arc::new(ArrayQueue::new(64)),
             to_be_woken: Arc::new(SpinLock::disable_interrupts(BTreeSet::new())),
+            thread: thread::try_current(),
         }
     }
     /// Create a spawner that will send tasks to this executor. This is a cheap operation.
@@ -70,11 +92,16 @@ impl Executor {
     pub fn has_woken_tasks(&self) -> bool {
         !self.to_be_woken.lock().is_empty()
     }
-    /// Spawns any tasks sent by the Spawner
+    /// Spawns any tasks sent by the Spawner, and takes one task queued by [spawn_shared].
     pub fn poll_spawner(&mut self) {
         while let Some(task) = self.spawner.pop() {
             self.spawn(task);
         }
+        // Only take a single shared task, leaving the rest to other CPUs.
+        let shared = SHARED_TASKS.lock().pop_front();
+        if let Some(task) = shared {
+            self.spawn(task);
+        }
     }
     /// Returns false if there are no tasks to run, false otherwise
     pub fn poll_one(&mut self) -> bool {
@@ -83,6 +110,7 @@ impl Executor {
             tasks,
             wakers,
             to_be_woken,
+            thread,
             ..
         } = self;
         let Some(id) = to_be_woken.lock().pop_first() else {
@@ -94,7 +122,7 @@ impl Executor {
         };
         let waker = wakers
             .entry(id)
-            .or_insert_with(|| TaskWaker::new_waker(id, to_be_woken.clone()));
+            .or_insert_with(|| TaskWaker::new_waker(id, to_be_woken.clone(), thread.clone()));
         let mut cx = Context::from_waker(waker);
         match task.as_mut().poll(&mut cx) {
             Poll::Ready(()) => {
@@ -116,9 +144,32 @@ impl Executor {
             self.poll_spawner();
         }
     }
+    /// Run tasks forever, waiting for [shared](spawn_shared) ones once there are none left.
+    pub fn run_forever(&mut self) -> ! {
+        loop {
+            self.poll_spawner();
+            while self.poll_one() {}
+            self.sleep_if_idle();
+        }
+    }
+    /// Park the executor's thread until a task is woken or shared, halting the CPU instead if the
+    /// executor isn't running on a thread.
     fn sleep_if_idle(&self) {
+        if let Some(thread) = &self.thread {
+            {
+                // Register before checking, so that a task shared in between still unparks us.
+                let mut waiters = SHARED_WAITERS.lock();
+                if !waiters.iter().any(|waiter| waiter.id() == thread.id()) {
+                    waiters.push(thread.clone());
+                }
+            }
+            if self.to_be_woken.lock().is_empty() && SHARED_TASKS.lock().is_empty() {
+                thread::park();
+            }
+            return;
+        }
         interrupts::disable();
-        if self.to_be_woken.lock().is_empty() {
+        if self.to_be_woken.lock().is_empty() && SHARED_TASKS.lock().is_empty() {
             interrupts::enable_and_hlt();
         } else {
             interrupts::enable();
@@ -129,17 +180,27 @@ impl Executor {
 struct TaskWaker {
     id: TaskId,
     to_be_woken: Arc<SpinLock<BTreeSet<TaskId>, DisableInterrupts>>,
+    thread: Option<Thread>,
 }
 
 impl TaskWaker {
     fn new_waker(
         id: TaskId,
         to_be_woken: Arc<SpinLock<BTreeSet<TaskId>, DisableInterrupts>>,
+        thread: Option<Thread>,
     ) -> Waker {
-        Waker::from(Arc::new(TaskWaker { id, to_be_woken }))
+        Waker::from(Arc::new(TaskWaker {
+            id,
+            to_be_woken,
+            thread,
+        }))
     }
     fn wake_task(&self) {
         self.to_be_woken.lock().insert(self.id);
+        // The executor may be parked, or running on another CPU.
+        if let Some(thread) = &self.thread {
+            thread.unpark();
+        }
     }
 }
 
@@ -1,4 +1,7 @@
-use crate::prelude::{vga_color::*, *};
+use crate::{
+    interrupts::deferred::defer,
+    prelude::{vga_color::*, *},
+};
 use core::task::{Poll, ready};
 use crossbeam_queue::ArrayQueue;
 use futures::stream::FusedStream;
@@ -9,19 +12,28 @@ use spinlock::LazyStatic;
 pub static SCANCODE_QUEUE: LazyStatic<ArrayQueue<u8>> = LazyStatic::new(|| ArrayQueue::new(64));
 static SCANCODE_WAKER: AtomicWaker = AtomicWaker::new();
 
+/// Queue a scancode read by the keyboard interrupt, deferring waking the reader and any warnings.
 pub fn add_scancode(scancode: u8) {
     let Some(queue) = SCANCODE_QUEUE.get_if_init() else {
-        println!(fgcolor = Red, "WARNING: SCANCODE_QUEUE not initialized.");
+        defer(
+            |_| println!(fgcolor = Red, "WARNING: SCANCODE_QUEUE not initialized."),
+            0,
+        );
         return;
     };
     if queue.push(scancode).is_err() {
-        println!(
-            fgcolor = Red,
-            "WARNING: SCANCODE_QUEUE is full, dropping scancode"
+        defer(
+            |_| {
+                println!(
+                    fgcolor = Red,
+                    "WARNING: SCANCODE_QUEUE is full, dropping scancode"
+                )
+            },
+            0,
         );
         return;
     }
-    SCANCODE_WAKER.wake();
+    defer(|_| SCANCODE_WAKER.wake(), 0);
 }
 
 struct ScancodeStream(());
@@ -1,11 +1,10 @@
-use crate::clock::MS_CLOCK;
+use crate::clock::{now_ms, tickless};
 use alloc::{
     boxed::Box,
     collections::{BTreeMap, btree_map::Entry},
 };
 use core::{
     num::NonZeroU64,
-    sync::atomic::Ordering::*,
     task::{Poll, Waker},
 };
 use futures_util::{Stream, StreamExt};
@@ -20,7 +19,31 @@ struct NestedWaker {
     next: Option<Box<NestedWaker>>,
 }
 
-/// Wake any tasks registered to fire before the provided timestamp of the MS_CLOCK.
+/// Returns the earliest timestamp a task is waiting for.
+pub fn next_deadline() -> Option<u64> {
+    TIMER_WAKERS
+        .lock()
+        .first_key_value()
+        .map(|(&timestamp, _)| timestamp)
+}
+
+/// Register `waker` to be woken once the [now_ms] clock reaches `timestamp`.
+pub fn wake_at(timestamp: u64, waker: Waker) {
+    match TIMER_WAKERS.lock().entry(timestamp) {
+        Entry::Vacant(vacant) => {
+            vacant.insert(NestedWaker { waker, next: None });
+        }
+        Entry::Occupied(mut entry) => {
+            let entry = entry.get_mut();
+            let next = entry.next.take();
+            let node = NestedWaker { waker, next };
+            entry.next = Some(Box::new(node))
+        }
+    };
+    tickless::schedule(timestamp);
+}
+
+/// Wake any tasks registered to fire before the provided timestamp of the [now_ms] clock.
 pub fn wake_tasks(timestamp: u64) {
     let mut wakers = TIMER_WAKERS.lock();
     loop {
@@ -47,14 +70,14 @@ pub struct Interval {
 impl Interval {
     pub fn new(ms: u64) -> Self {
         Self {
-            last: MS_CLOCK.load(Relaxed).wrapping_sub(ms),
+            last: now_ms().wrapping_sub(ms),
             interval: ms
                 .try_into()
                 .expect("Cannot create an interval that yields every 0 ms."),
         }
     }
     pub fn reset(&mut self) {
-        self.last = MS_CLOCK.load(Relaxed).wrapping_sub(self.interval.get());
+        self.last = now_ms().wrapping_sub(self.interval.get());
     }
     pub async fn tick(&mut self) {
         self.next().await;
@@ -62,7 +85,7 @@ impl Interval {
 }
 
 pub async fn sleep(ms: u64) {
-    sleep_until(MS_CLOCK.load(Relaxed) + ms).await
+    sleep_until(now_ms() + ms).await
 }
 
 pub async fn sleep_until(timestamp: u64) {
@@ -80,23 +103,12 @@ impl Stream for Interval {
         cx: &mut core::task::Context<'_>,
     ) -> core::task::Poll<Option<Self::Item>> {
         let timestamp = self.last.wrapping_add(self.interval.get());
-        if MS_CLOCK.load(Relaxed) >= timestamp {
+        if now_ms() >= timestamp {
             self.last = timestamp;
             return Poll::Ready(Some(timestamp));
         }
-        let waker = cx.waker().clone();
-        match TIMER_WAKERS.lock().entry(timestamp) {
-            Entry::Vacant(vacant) => {
-                vacant.insert(NestedWaker { waker, next: None });
-            }
-            Entry::Occupied(mut entry) => {
-                let entry = entry.get_mut();
-                let next = entry.next.take();
-                let node = NestedWaker { waker, next };
-                entry.next = Some(Box::new(node))
-            }
-        };
-        if MS_CLOCK.load(Relaxed) >= timestamp {
+        wake_at(timestamp, cx.waker().clone());
+        if now_ms() >= timestamp {
             self.last = timestamp;
             return Poll::Ready(Some(timestamp));
         }
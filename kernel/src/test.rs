@@ -25,3 +25,42 @@ pub fn test_runner(tests: &[&dyn Testable]) -> ! {
     }
     exit_qemu(QemuExitCode::Success);
 }
+
+/// An ISA line nothing in QEMU's default machine raises, for tests to register handlers on.
+pub const UNUSED_IRQ: u8 = 5;
+
+/// Deliver the vector of [UNUSED_IRQ] as a software interrupt.
+pub fn raise_unused_irq() {
+    const VECTOR: u8 = crate::interrupts::irq_vector(UNUSED_IRQ);
+    unsafe { core::arch::asm!("int {}", const VECTOR) };
+}
+
+/// Define a function returning the machine code of a user program, written in AT&T syntax, for
+/// tests to run with [user::run](crate::user::run).
+///
+/// Example: `user_program!(halt: "hlt");`
+#[macro_export]
+macro_rules! user_program {
+    ($name:ident: $($asm:literal),* $(,)?) => {
+        core::arch::global_asm!(
+            concat!(".pushsection .rodata.", stringify!($name), ", \"a\""),
+            concat!(".global ", stringify!($name), "_start"),
+            concat!(stringify!($name), "_start:"),
+            $($asm,)*
+            concat!(".global ", stringify!($name), "_end"),
+            concat!(stringify!($name), "_end:"),
+            ".popsection",
+            options(att_syntax),
+        );
+        fn $name() -> &'static [u8] {
+            unsafe extern "C" {
+                #[link_name = concat!(stringify!($name), "_start")]
+                static START: u8;
+                #[link_name = concat!(stringify!($name), "_end")]
+                static END: u8;
+            }
+            let (start, end) = (&raw const START, &raw const END);
+            unsafe { core::slice::from_raw_parts(start, end.offset_from(start) as usize) }
+        }
+    };
+}
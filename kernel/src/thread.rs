@@ -0,0 +1,328 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own guard-paged kernel stack, on which its registers are saved while it
+//! is switched out. Threads are scheduled round-robin across all CPUs, and preempted by the timer
+//! interrupt, see [scheduler].
+//!
+//! The code every CPU boots into becomes a thread as well, which is where the async
+//! [Executor](crate::task::executor::Executor)s run.
+mod scheduler;
+mod switch;
+
+pub(crate) use scheduler::CpuScheduler;
+pub use scheduler::{TIME_SLICE_MS, preempt, preemption_deadline, reschedule_interrupt, tick};
+
+use crate::{
+    clock::now_ms,
+    memory::{self, stack::KernelStack, vmm::VmmError},
+    process::Pid,
+};
+use alloc::{boxed::Box, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    fmt::Debug,
+    sync::atomic::{AtomicU64, Ordering::*},
+    task::{Context, Poll, Waker},
+};
+use scheduler::Reason;
+use spinlock::{DisableInterrupts, SpinLock};
+use switch::ThreadMain;
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts, structures::paging::PhysFrame};
+
+/// The number of pages in the stack of every thread.
+const STACK_PAGES: usize = 16;
+
+/// A unique ID generated when a thread is created.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT.fetch_add(1, Relaxed))
+    }
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting in the run queue.
+    Ready,
+    Running,
+    /// Switched out until unparked.
+    Parked,
+    Exited,
+}
+
+struct Sched {
+    state: State,
+    /// Set by [Thread::unpark] when the thread wasn't parked, making the next [park] return
+    /// immediately.
+    unparked: bool,
+}
+
+struct Inner {
+    id: ThreadId,
+    /// The saved stack pointer while the thread is switched out.
+    context: AtomicU64,
+    /// The thread's stack, `None` for the threads CPUs booted into, and once it has exited.
+    stack: SpinLock<Option<KernelStack>, DisableInterrupts>,
+    sched: SpinLock<Sched, DisableInterrupts>,
+    /// The threads waiting for this one to exit.
+    joiners: SpinLock<Vec<Thread>, DisableInterrupts>,
+    /// The stack pointer the kernel is entered on while the thread runs in user mode, 0 if it
+    /// doesn't.
+    kernel_entry: AtomicU64,
+    /// The physical address of the level 4 table the thread runs on, 0 for the kernel's.
+    page_table: AtomicU64,
+    /// The ID of the process the thread runs, 0 if it doesn't.
+    process: AtomicU64,
+}
+
+/// A handle to a thread.
+#[derive(Clone)]
+pub struct Thread(Arc<Inner>);
+
+impl Debug for Thread {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Thread")
+            .field("id", &self.0.id)
+            .field("state", &self.0.sched.lock().state)
+            .finish()
+    }
+}
+
+impl Thread {
+    fn with_stack(stack: Option<KernelStack>, state: State) -> Self {
+        Thread(Arc::new(Inner {
+            id: ThreadId::new(),
+            context: AtomicU64::new(0),
+            stack: SpinLock::disable_interrupts(stack),
+            sched: SpinLock::disable_interrupts(Sched {
+                state,
+                unparked: false,
+            }),
+            joiners: SpinLock::disable_interrupts(Vec::new()),
+            kernel_entry: AtomicU64::new(0),
+            page_table: AtomicU64::new(0),
+            process: AtomicU64::new(0),
+        }))
+    }
+    /// Create a thread that runs `main` once it is first switched to.
+    fn new(main: ThreadMain) -> Result<Self, VmmError> {
+        let stack = KernelStack::new(STACK_PAGES)?;
+        let context = unsafe { switch::init_stack(stack.top(), main) };
+        let thread = Thread::with_stack(Some(stack), State::Ready);
+        thread.0.context.store(context, Release);
+        Ok(thread)
+    }
+    /// Create a thread for the code currently running on the CPU.
+    fn adopt() -> Self {
+        Thread::with_stack(None, State::Running)
+    }
+    pub fn id(&self) -> ThreadId {
+        self.0.id
+    }
+    /// Make the thread runnable if it is [parked](park), or make its next call to [park] return
+    /// immediately otherwise.
+    pub fn unpark(&self) {
+        let mut sched = self.0.sched.lock();
+        if sched.state == State::Parked {
+            sched.state = State::Ready;
+            drop(sched);
+            scheduler::enqueue(self.clone());
+        } else {
+            sched.unparked = true;
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        self.0.sched.lock().state == State::Exited
+    }
+    /// Returns a [Waker] that unparks the thread.
+    pub fn waker(&self) -> Waker {
+        Waker::from(Arc::new(Unparker(self.clone())))
+    }
+    /// Returns the stack the kernel is entered on while the thread runs in user mode.
+    pub(crate) fn kernel_entry(&self) -> Option<VirtAddr> {
+        match self.0.kernel_entry.load(Relaxed) {
+            0 => None,
+            rsp => Some(VirtAddr::new(rsp)),
+        }
+    }
+    pub(crate) fn set_kernel_entry(&self, rsp: Option<VirtAddr>) {
+        let rsp = rsp.map_or(0, VirtAddr::as_u64);
+        self.0.kernel_entry.store(rsp, Relaxed);
+    }
+    /// Returns the level 4 table the scheduler loads when switching to the thread.
+    pub(crate) fn page_table(&self) -> PhysFrame {
+        match self.0.page_table.load(Acquire) {
+            0 => memory::kernel_table(),
+            table => PhysFrame::containing_address(PhysAddr::new(table)),
+        }
+    }
+    /// Set the level 4 table of the thread, the kernel's if `None`. The caller has to load it
+    /// into CR3 if the thread is running.
+    pub(crate) fn set_page_table(&self, table: Option<PhysFrame>) {
+        let table = table.map_or(0, |table| table.start_address().as_u64());
+        self.0.page_table.store(table, Release);
+    }
+    /// Returns the process the thread runs, if any.
+    pub fn process(&self) -> Option<Pid> {
+        Pid::from_u64(self.0.process.load(Acquire))
+    }
+    pub(crate) fn set_process(&self, pid: Option<Pid>) {
+        let pid = pid.map_or(0, |pid| pid.as_u64());
+        self.0.process.store(pid, Release);
+    }
+    /// Make the thread enter the kernel if it is running on another CPU, so that it notices
+    /// changes like being killed without waiting for its time slice to end.
+    pub fn interrupt(&self) {
+        scheduler::interrupt(self);
+    }
+}
+
+struct Unparker(Thread);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+/// Turn the code running on the current CPU into a thread. Requires [smp::init](crate::smp::init).
+pub fn init() {
+    scheduler::init_cpu();
+}
+
+/// Returns the current thread, if the CPU has been turned into one with [init].
+pub fn try_current() -> Option<Thread> {
+    scheduler::current()
+}
+
+/// Returns the current thread.
+pub fn current() -> Thread {
+    try_current().expect("Attempted to get the current thread before thread::init")
+}
+
+/// Allows waiting for a thread to exit and taking its result.
+pub struct JoinHandle<T> {
+    thread: Thread,
+    result: Arc<SpinLock<Option<T>, DisableInterrupts>>,
+}
+
+impl<T> Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("JoinHandle")
+            .field("thread", &self.thread)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+    /// Wait for the thread to exit, returning the value it returned.
+    pub fn join(self) -> T {
+        // Register before checking, so that the exit can't be missed.
+        self.thread.0.joiners.lock().push(current());
+        while !self.thread.is_finished() {
+            park();
+        }
+        self.result
+            .lock()
+            .take()
+            .expect("The thread exited without a result")
+    }
+}
+
+/// Spawn a thread running `f`.
+///
+/// # Panics
+/// Panics if the stack of the thread can't be allocated.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(SpinLock::disable_interrupts(None));
+    let packet = result.clone();
+    let thread = Thread::new(Box::new(move || *packet.lock() = Some(f())))
+        .expect("Failed to allocate a thread stack");
+    scheduler::enqueue(thread.clone());
+    JoinHandle { thread, result }
+}
+
+/// Let other runnable threads run before continuing.
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+/// Block the current thread until it is [unparked](Thread::unpark). Like its `std` counterpart,
+/// this may also return spuriously.
+pub fn park() {
+    interrupts::without_interrupts(|| {
+        let thread = current();
+        {
+            let mut sched = thread.0.sched.lock();
+            if sched.unparked {
+                sched.unparked = false;
+                return;
+            }
+        }
+        drop(thread);
+        scheduler::switch_away(Reason::Park);
+    })
+}
+
+/// Run a future to completion on the current thread, parking it while the future is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    block_on_unless(future, || false).unwrap()
+}
+
+/// Like [block_on], but give up and return `None` once `stop` returns true. Whatever makes `stop`
+/// return true must [unpark](Thread::unpark) the thread afterwards.
+pub fn block_on_unless<F: Future>(future: F, stop: impl Fn() -> bool) -> Option<F::Output> {
+    let mut future = core::pin::pin!(future);
+    let waker = current().waker();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
+        if stop() {
+            return None;
+        }
+        park();
+    }
+}
+
+/// Block the current thread for at least `ms` milliseconds.
+pub fn sleep(ms: u64) {
+    let deadline = now_ms() + ms;
+    crate::task::timer::wake_at(deadline, current().waker());
+    while now_ms() < deadline {
+        park();
+    }
+}
+
+/// Exit the current thread, waking the threads joining it.
+fn exit() -> ! {
+    interrupts::disable();
+    let thread = current();
+    thread.0.sched.lock().state = State::Exited;
+    for joiner in thread.0.joiners.lock().drain(..) {
+        joiner.unpark();
+    }
+    drop(thread);
+    scheduler::switch_away(Reason::Exit);
+    unreachable!("Switched back to an exited thread")
+}
@@ -0,0 +1,271 @@
+//! The round-robin scheduler.
+//!
+//! Runnable threads wait in a single run queue shared by all CPUs. A CPU switches to the next one
+//! when its thread parks, yields or exits, or is preempted once its [TIME_SLICE_MS] is up while
+//! other threads are waiting. CPUs with nothing to run switch to their idle thread, which halts
+//! until woken.
+//!
+//! A thread that was switched away from is only put back in the run queue by [finish_switch],
+//! once the next thread is running and its stack is no longer in use.
+use super::{State, Thread};
+use crate::{
+    clock::{now_ms, tickless},
+    interrupts::apic,
+    memory,
+    smp::{self, PerCpu},
+};
+use alloc::{boxed::Box, collections::VecDeque};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering::*};
+use spinlock::{DisableInterrupts, SpinLock};
+use x86_64::instructions::interrupts;
+
+/// How long a thread may run while others are waiting before it is preempted.
+pub const TIME_SLICE_MS: u64 = 10;
+
+static RUN_QUEUE: SpinLock<VecDeque<Thread>, DisableInterrupts> =
+    SpinLock::disable_interrupts(VecDeque::new());
+
+/// Why a thread is being switched away from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Reason {
+    /// The thread is still runnable, and goes back to the run queue.
+    Yield,
+    /// The thread waits to be unparked.
+    Park,
+    /// The thread has exited, and its stack can be freed.
+    Exit,
+    /// The idle thread found a thread to run.
+    Idle,
+}
+
+/// The scheduler state of a CPU, stored in its [PerCpu] block.
+pub(crate) struct CpuScheduler {
+    state: SpinLock<CpuState, DisableInterrupts>,
+    /// The [now_ms] time the current thread was switched to.
+    slice_start: AtomicU64,
+    /// Whether the CPU is running its idle thread.
+    idle: AtomicBool,
+    /// Set when the idle CPU was sent a wakeup it hasn't handled yet, so that it isn't woken
+    /// for every thread queued in the meantime.
+    wake_pending: AtomicBool,
+}
+
+struct CpuState {
+    current: Option<Thread>,
+    idle: Option<Thread>,
+    /// The thread switched away from, to be handled by [finish_switch].
+    prev: Option<(Thread, Reason)>,
+}
+
+impl CpuScheduler {
+    pub(crate) const fn new() -> Self {
+        CpuScheduler {
+            state: SpinLock::disable_interrupts(CpuState {
+                current: None,
+                idle: None,
+                prev: None,
+            }),
+            slice_start: AtomicU64::new(0),
+            idle: AtomicBool::new(false),
+            wake_pending: AtomicBool::new(false),
+        }
+    }
+}
+
+fn this_percpu() -> &'static PerCpu {
+    smp::current().expect("Attempted to schedule threads before smp::init")
+}
+
+fn this_cpu() -> &'static CpuScheduler {
+    &this_percpu().scheduler
+}
+
+/// Turn the code running on the current CPU into a thread, and give the CPU an idle thread.
+pub(super) fn init_cpu() {
+    let idle = Thread::new(Box::new(idle_loop)).expect("Failed to allocate the idle thread");
+    let cpu = this_cpu();
+    let mut state = cpu.state.lock();
+    if state.current.is_some() {
+        return;
+    }
+    state.current = Some(Thread::adopt());
+    state.idle = Some(idle);
+    cpu.slice_start.store(now_ms(), Relaxed);
+}
+
+/// Returns the thread running on the current CPU, if it has been turned into one.
+pub(super) fn current() -> Option<Thread> {
+    smp::current()?.scheduler.state.lock().current.clone()
+}
+
+/// Make a thread runnable, waking an idle CPU to run it.
+pub(super) fn enqueue(thread: Thread) {
+    RUN_QUEUE.lock().push_back(thread);
+    let mut woken = false;
+    smp::for_each_cpu(|cpu| {
+        let scheduler = &cpu.scheduler;
+        if !woken && scheduler.idle.load(Acquire) && !scheduler.wake_pending.swap(true, AcqRel) {
+            cpu.wake();
+            woken = true;
+        }
+    });
+    // Make sure a timer interrupt preempts the running threads in time.
+    if tickless::is_enabled() {
+        tickless::schedule(now_ms() + TIME_SLICE_MS);
+    }
+}
+
+/// Switch to the next thread in the run queue. Yielding with an empty run queue returns
+/// immediately, other reasons switch to the idle thread.
+///
+/// Interrupts must be disabled.
+pub(super) fn switch_away(reason: Reason) {
+    let cpu = this_cpu();
+    let mut state = cpu.state.lock();
+    if state.current.is_none() {
+        return;
+    }
+    let next = RUN_QUEUE.lock().pop_front();
+    let next = match next {
+        Some(next) => next,
+        None if matches!(reason, Reason::Yield | Reason::Idle) => return,
+        None => state.idle.clone().expect("The CPU has no idle thread"),
+    };
+    let prev = state.current.replace(next.clone()).unwrap();
+    let is_idle = |thread: &Thread| {
+        state
+            .idle
+            .as_ref()
+            .is_some_and(|idle| idle.id() == thread.id())
+    };
+    // The idle thread never goes in the run queue.
+    let reason = if is_idle(&prev) { Reason::Idle } else { reason };
+    let is_idle = is_idle(&next);
+    next.0.sched.lock().state = State::Running;
+    if let Some(rsp) = next.kernel_entry() {
+        this_percpu().set_kernel_stack(rsp);
+    }
+    memory::activate_table(next.page_table());
+    cpu.idle.store(is_idle, Release);
+    cpu.slice_start.store(now_ms(), Relaxed);
+
+    let old = prev.0.context.as_ptr();
+    let new = next.0.context.load(Acquire);
+    state.prev = Some((prev, reason));
+    drop(state);
+    drop(next);
+    // SAFETY: The next thread was switched away from, and only put in the run queue by
+    // finish_switch once its stack was no longer in use.
+    unsafe { super::switch::switch(old, new) };
+    finish_switch();
+}
+
+/// Handle the thread the current CPU just switched away from. Called by every thread right after
+/// it is switched to.
+pub(super) fn finish_switch() {
+    let Some((prev, reason)) = this_cpu().state.lock().prev.take() else {
+        return;
+    };
+    match reason {
+        Reason::Yield => {
+            prev.0.sched.lock().state = State::Ready;
+            enqueue(prev);
+        }
+        Reason::Park => {
+            let mut sched = prev.0.sched.lock();
+            if sched.unparked {
+                sched.unparked = false;
+                sched.state = State::Ready;
+                drop(sched);
+                enqueue(prev);
+            } else {
+                sched.state = State::Parked;
+            }
+        }
+        Reason::Exit => drop(prev.0.stack.lock().take()),
+        Reason::Idle => prev.0.sched.lock().state = State::Ready,
+    }
+}
+
+/// Send a reschedule interrupt to the CPU running `thread`, if it is another one.
+pub(super) fn interrupt(thread: &Thread) {
+    smp::for_each_cpu(|cpu| {
+        let running = cpu.scheduler.state.lock().current.as_ref().map(Thread::id);
+        if running == Some(thread.id()) && !cpu.is_current() {
+            apic::send_ipi(cpu.apic_id(), apic::RESCHEDULE_VECTOR);
+        }
+    });
+}
+
+/// Yield to the next runnable thread if the current thread was running on its CPU.
+pub(super) fn yield_now() {
+    interrupts::without_interrupts(|| {
+        if current().is_some() {
+            switch_away(Reason::Yield);
+        }
+    });
+}
+
+fn idle_loop() {
+    let cpu = this_cpu();
+    loop {
+        interrupts::disable();
+        cpu.wake_pending.store(false, Release);
+        if RUN_QUEUE.lock().is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            switch_away(Reason::Idle);
+            interrupts::enable();
+        }
+    }
+}
+
+/// Check whether any CPU has used up its time slice while threads are waiting, preempting the
+/// current thread or asking the other CPU to. Called by the timer interrupt.
+pub fn tick() {
+    if RUN_QUEUE.lock().is_empty() {
+        return;
+    }
+    let now = now_ms();
+    smp::for_each_cpu(|cpu| {
+        let scheduler = &cpu.scheduler;
+        if scheduler.idle.load(Acquire) {
+            cpu.wake();
+        } else if now.saturating_sub(scheduler.slice_start.load(Relaxed)) >= TIME_SLICE_MS
+            && !cpu.is_current()
+        {
+            apic::send_ipi(cpu.apic_id(), apic::RESCHEDULE_VECTOR);
+        }
+    });
+}
+
+/// Preempt the current thread if its time slice is up. Called at the end of the timer
+/// interrupt, after the end of interrupt was signalled.
+pub fn preempt() {
+    let Some(cpu) = smp::current() else {
+        return;
+    };
+    let start = cpu.scheduler.slice_start.load(Relaxed);
+    if now_ms().saturating_sub(start) >= TIME_SLICE_MS {
+        reschedule(cpu);
+    }
+}
+
+/// Switch to the next thread in the run queue. Called by the reschedule interrupt, after the
+/// end of interrupt was signalled.
+pub fn reschedule_interrupt() {
+    if let Some(cpu) = smp::current() {
+        reschedule(cpu);
+    }
+}
+
+fn reschedule(cpu: &'static PerCpu) {
+    if !cpu.scheduler.idle.load(Acquire) && current().is_some() {
+        switch_away(Reason::Yield);
+    }
+}
+
+/// Returns when the next timer interrupt is needed to preempt threads, if any are waiting.
+pub fn preemption_deadline() -> Option<u64> {
+    (!RUN_QUEUE.lock().is_empty()).then(|| now_ms() + TIME_SLICE_MS)
+}
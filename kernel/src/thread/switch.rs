@@ -0,0 +1,85 @@
+//! Switching between the register contexts of threads.
+use alloc::boxed::Box;
+use x86_64::VirtAddr;
+
+/// The entry point of a thread, passed to [thread_main] through `r12` by `thread_start`.
+pub(super) type ThreadMain = Box<dyn FnOnce() + Send>;
+
+/// RFLAGS with only the always-set bit, interrupts are enabled by [thread_main].
+const INITIAL_RFLAGS: u64 = 0x2;
+
+core::arch::global_asm!(
+    ".global thread_switch",
+    "thread_switch:",
+    "pushfq",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "popfq",
+    "ret",
+    "",
+    ".global thread_start",
+    "thread_start:",
+    "mov rdi, r12",
+    "call {main}",
+    "ud2",
+    main = sym thread_main,
+);
+
+unsafe extern "C" {
+    /// Save the callee-saved registers and RFLAGS on the current stack and store the stack
+    /// pointer in `old`, then restore them from the stack at `new` and return into that thread.
+    fn thread_switch(old: *mut u64, new: u64);
+    fn thread_start();
+}
+
+/// Switch from the current thread to the one whose saved stack pointer is `new`, saving the
+/// current one in `old`. Returns once another thread switches back to `old`.
+///
+/// # Safety
+/// Interrupts must be disabled, and `new` must be a context saved by [switch] or prepared by
+/// [init_stack] on a stack that is not in use.
+pub(super) unsafe fn switch(old: *mut u64, new: u64) {
+    unsafe { thread_switch(old, new) }
+}
+
+/// Prepare a stack whose first [switch] runs `main`, returning the stack pointer to switch to.
+///
+/// # Safety
+/// `top` must be the top of a mapped stack that is not in use.
+pub(super) unsafe fn init_stack(top: VirtAddr, main: ThreadMain) -> u64 {
+    let main = Box::into_raw(Box::new(main));
+    // The return address is at the top, so that the stack is aligned when calling thread_main.
+    let frame = [
+        0,                            // r15
+        0,                            // r14
+        0,                            // r13
+        main as u64,                  // r12
+        0,                            // rbx
+        0,                            // rbp
+        INITIAL_RFLAGS,               // rflags
+        thread_start as usize as u64, // return address
+    ];
+    let rsp = top - size_of_val(&frame) as u64;
+    unsafe { rsp.as_mut_ptr::<[u64; 8]>().write(frame) };
+    rsp.as_u64()
+}
+
+extern "C" fn thread_main(main: *mut ThreadMain) -> ! {
+    super::scheduler::finish_switch();
+    x86_64::instructions::interrupts::enable();
+    let main = unsafe { Box::from_raw(main) };
+    main();
+    super::exit()
+}
@@ -0,0 +1,183 @@
+//! User mode.
+//!
+//! User programs run in ring 3 on a kernel [thread], and enter the kernel through the [syscall]
+//! interface and interrupts, both of which switch to the stack of the thread at the point it
+//! entered user mode. The `exit` syscall unwinds that stack back to [run].
+//!
+//! Every program runs as a [process](crate::process) with its own [AddressSpace]. Programs are
+//! either raw machine code started at its first byte, see [run], or ELF executables, see [exec].
+mod demo;
+pub mod elf;
+mod memory;
+pub mod programs;
+mod stack;
+pub mod syscall;
+
+use crate::{
+    memory::vmm::VmmError,
+    process, smp,
+    thread::{self, JoinHandle},
+};
+use alloc::{string::String, vec::Vec};
+use elf::{Elf, ElfError};
+pub use memory::{AddressSpace, USER_REGION_SIZE, USER_REGION_START};
+use x86_64::{VirtAddr, instructions::interrupts, structures::paging::PageTableFlags};
+
+/// The size of the stack of a user program.
+const STACK_SIZE: u64 = 64 * 1024;
+/// The RFLAGS user programs start with, with interrupts enabled.
+const INITIAL_RFLAGS: u64 = 0x202;
+
+core::arch::global_asm!(
+    ".global user_enter",
+    "user_enter:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // Keep the stack aligned for the call below.
+    "sub rsp, 8",
+    "mov r12, rdi",
+    "mov r13, rsi",
+    "cli",
+    "mov rdi, rsp",
+    "call {entered}",
+    "mov rcx, r12",
+    "mov rsp, r13",
+    "mov r11, {rflags}",
+    // Don't leak kernel values to the program.
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "sysretq",
+    "",
+    ".global user_leave",
+    "user_leave:",
+    "mov rsp, rdi",
+    "mov rax, rsi",
+    "add rsp, 8",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    entered = sym entered,
+    rflags = const INITIAL_RFLAGS,
+);
+
+unsafe extern "C" {
+    /// Save the callee-saved registers and jump to `entry` in user mode, returning the exit code
+    /// passed to [user_leave].
+    fn user_enter(entry: u64, stack: u64) -> i64;
+    /// Return from [user_enter] with `code`, given the kernel stack pointer it saved.
+    fn user_leave(rsp: u64, code: i64) -> !;
+}
+
+/// Record the stack pointer `user_enter` saved its registers at as the stack to enter the kernel
+/// on. Called with interrupts disabled.
+extern "C" fn entered(rsp: u64) {
+    let rsp = VirtAddr::new(rsp);
+    thread::current().set_kernel_entry(Some(rsp));
+    smp::current()
+        .expect("Attempted to enter user mode before smp::init")
+        .set_kernel_stack(rsp);
+}
+
+/// Enable syscalls on the current CPU.
+pub fn init() {
+    syscall::init();
+}
+
+const STACK_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// The name of processes started by [run], which have no name of their own.
+const CODE_PROCESS_NAME: &str = "code";
+
+/// Load `code` into a new process and run it in user mode on the current thread, starting at its
+/// first byte, until it exits. Returns the exit code.
+pub fn run(code: &[u8]) -> Result<i64, VmmError> {
+    let mut space = AddressSpace::new()?;
+    let text = space.load(
+        code,
+        code.len() as u64,
+        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+    )?;
+    let stack = space.map(STACK_SIZE, STACK_FLAGS)?;
+    Ok(enter(CODE_PROCESS_NAME, space, text.start, stack.end))
+}
+
+/// [run] `code` on a new thread.
+pub fn spawn(code: &'static [u8]) -> JoinHandle<Result<i64, VmmError>> {
+    thread::spawn(move || run(code))
+}
+
+/// Load the ELF executable `elf` into a new process and run it in user mode on the current thread
+/// until it exits, passing it `argv` and `envp` on its stack. Returns the exit code.
+///
+/// The process is named after `argv[0]`.
+pub fn exec(elf: &[u8], argv: &[&str], envp: &[&str]) -> Result<i64, ElfError> {
+    let elf = Elf::parse(elf)?;
+    let mut space = AddressSpace::new()?;
+    let image = elf.load(&mut space)?;
+    let stack = space.map(STACK_SIZE, STACK_FLAGS)?;
+    let (rsp, initial) = stack::build(stack.end, STACK_SIZE, argv, envp, image.auxv())
+        .ok_or(ElfError::ArgumentsTooLong)?;
+    space.write(rsp, &initial)?;
+    let name = argv.first().copied().unwrap_or_default();
+    Ok(enter(name, space, image.entry, rsp))
+}
+
+/// [exec] `elf` on a new thread.
+pub fn spawn_exec(
+    elf: &'static [u8],
+    argv: Vec<String>,
+    envp: Vec<String>,
+) -> JoinHandle<Result<i64, ElfError>> {
+    thread::spawn(move || {
+        let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+        let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+        exec(elf, &argv, &envp)
+    })
+}
+
+/// Start a process running `space` on the current thread and run it in user mode until the
+/// program exits, then tear it down and reap it.
+fn enter(name: &str, space: AddressSpace, entry: VirtAddr, stack: VirtAddr) -> i64 {
+    let process = process::start(name, space);
+    let code = unsafe { user_enter(entry.as_u64(), stack.as_u64()) };
+    thread::current().set_kernel_entry(None);
+    interrupts::enable();
+    process.exit(code);
+    process::reap(process.pid()).expect("The process was reaped by someone else")
+}
+
+/// Leave user mode, returning `code` from [run].
+pub(crate) fn exit(code: i64) -> ! {
+    interrupts::disable();
+    let rsp = thread::current()
+        .kernel_entry()
+        .expect("Attempted to exit user mode without entering it");
+    unsafe { user_leave(rsp.as_u64(), code) }
+}
+
+/// A small program that prints a greeting and exits with its privilege level, 3.
+pub fn demo() -> &'static [u8] {
+    demo::code()
+}
@@ -0,0 +1,34 @@
+//! The position-independent machine code of the [demo](super::demo) program.
+core::arch::global_asm!(
+    r#".pushsection .rodata.user_demo, "a""#,
+    ".global user_demo_start",
+    "user_demo_start:",
+    // write(1, message, len)
+    "mov $0, %eax",
+    "mov $1, %edi",
+    "lea user_demo_message(%rip), %rsi",
+    "mov $(user_demo_end - user_demo_message), %edx",
+    "syscall",
+    // exit(cs & 3)
+    "mov %cs, %rdi",
+    "and $3, %rdi",
+    "mov $2, %eax",
+    "syscall",
+    "ud2",
+    "user_demo_message:",
+    r#".ascii "Hello from user mode!\n""#,
+    "user_demo_end:",
+    ".popsection",
+    options(att_syntax),
+);
+
+unsafe extern "C" {
+    static user_demo_start: u8;
+    static user_demo_end: u8;
+}
+
+pub(super) fn code() -> &'static [u8] {
+    let start = &raw const user_demo_start;
+    let end = &raw const user_demo_end;
+    unsafe { core::slice::from_raw_parts(start, end.offset_from(start) as usize) }
+}
@@ -0,0 +1,237 @@
+//! Loading of statically linked ELF64 executables.
+//!
+//! Every `PT_LOAD` segment is mapped at its virtual address, which has to lie in the user region,
+//! with the permissions from its flags. Segments must not share pages.
+use super::memory::AddressSpace;
+use crate::memory::vmm::VmmError;
+use core::mem::size_of;
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const VERSION: u8 = 1;
+/// An executable with a fixed load address.
+const TYPE_EXEC: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3e;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// The auxiliary vector keys passed to programs.
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ends before a header or segment it describes.
+    Truncated,
+    /// The file doesn't start with the ELF magic.
+    NotElf,
+    /// The file isn't a little-endian x86-64 executable, or uses an unsupported ELF version.
+    Unsupported,
+    /// A segment is malformed or outside the user region.
+    InvalidSegment,
+    /// The entry point isn't in an executable segment.
+    InvalidEntry,
+    /// The arguments and environment don't fit on the stack.
+    ArgumentsTooLong,
+    /// Mapping a segment or the stack failed.
+    Memory(VmmError),
+}
+
+impl From<VmmError> for ElfError {
+    fn from(err: VmmError) -> Self {
+        ElfError::Memory(err)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct FileHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    fn contains(&self, addr: u64) -> bool {
+        self.vaddr <= addr && addr - self.vaddr < self.memsz
+    }
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Read a `T` at `offset`, which may be unaligned.
+fn read<T: Copy>(bytes: &[u8], offset: u64) -> Result<T, ElfError> {
+    let end = offset
+        .checked_add(size_of::<T>() as u64)
+        .ok_or(ElfError::Truncated)?;
+    let bytes = bytes
+        .get(offset as usize..end as usize)
+        .ok_or(ElfError::Truncated)?;
+    Ok(unsafe { bytes.as_ptr().cast::<T>().read_unaligned() })
+}
+
+/// A validated ELF executable.
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    header: FileHeader,
+}
+
+/// Where an executable was loaded, as needed to start it.
+#[derive(Debug, Clone, Copy)]
+pub struct Image {
+    pub entry: VirtAddr,
+    /// The address of the program headers in memory, if they are part of a segment.
+    pub phdr: Option<VirtAddr>,
+    pub phnum: u16,
+}
+
+impl Image {
+    /// The auxiliary vector describing the image to the program.
+    pub fn auxv(&self) -> impl Iterator<Item = (u64, u64)> {
+        let phdr = self.phdr.map(|phdr| (AT_PHDR, phdr.as_u64()));
+        phdr.into_iter().chain([
+            (AT_PHENT, size_of::<ProgramHeader>() as u64),
+            (AT_PHNUM, self.phnum as u64),
+            (AT_PAGESZ, 4096),
+            (AT_ENTRY, self.entry.as_u64()),
+        ])
+    }
+}
+
+impl<'a> Elf<'a> {
+    /// Validate the file header and program headers.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ElfError> {
+        let header: FileHeader = read(bytes, 0).map_err(|_| ElfError::NotElf)?;
+        let ident = header.ident;
+        if ident[..4] != MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if ident[4] != CLASS_64
+            || ident[5] != LITTLE_ENDIAN
+            || ident[6] != VERSION
+            || header.kind != TYPE_EXEC
+            || header.machine != MACHINE_X86_64
+            || header.phentsize as usize != size_of::<ProgramHeader>()
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let elf = Elf { bytes, header };
+        for segment in elf.program_headers() {
+            let segment = segment?;
+            if segment.kind != PT_LOAD {
+                continue;
+            }
+            let file_end = segment.offset.checked_add(segment.filesz);
+            if segment.filesz > segment.memsz
+                || file_end.is_none_or(|end| end > bytes.len() as u64)
+                || (segment.align > 1
+                    && segment.vaddr % segment.align != segment.offset % segment.align)
+            {
+                return Err(ElfError::InvalidSegment);
+            }
+        }
+        let executable = elf
+            .segments()
+            .any(|segment| segment.flags & PF_X != 0 && segment.contains(header.entry));
+        if !executable {
+            return Err(ElfError::InvalidEntry);
+        }
+        Ok(elf)
+    }
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.header.entry)
+    }
+    /// Returns every program header.
+    pub fn program_headers(&self) -> impl Iterator<Item = Result<ProgramHeader, ElfError>> {
+        let FileHeader { phoff, phnum, .. } = self.header;
+        let size = size_of::<ProgramHeader>() as u64;
+        (0..phnum as u64).map(move |i| read(self.bytes, phoff + i * size))
+    }
+    /// Returns the `PT_LOAD` segments, which were validated by [Self::parse].
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> {
+        self.program_headers()
+            .filter_map(Result::ok)
+            .filter(|segment| segment.kind == PT_LOAD)
+    }
+    /// Returns the address of the program headers once loaded.
+    fn phdr(&self) -> Option<VirtAddr> {
+        let phoff = self.header.phoff;
+        let phdr =
+            self.program_headers()
+                .filter_map(Result::ok)
+                .find_map(|segment| match segment.kind {
+                    PT_PHDR => Some(segment.vaddr),
+                    PT_LOAD
+                        if segment.offset <= phoff && phoff - segment.offset < segment.filesz =>
+                    {
+                        Some(segment.vaddr + (phoff - segment.offset))
+                    }
+                    _ => None,
+                })?;
+        VirtAddr::try_new(phdr).ok()
+    }
+    /// Map every segment into `space`.
+    pub fn load(&self, space: &mut AddressSpace) -> Result<Image, ElfError> {
+        for segment in self.segments() {
+            if segment.memsz == 0 {
+                continue;
+            }
+            let addr = VirtAddr::try_new(segment.vaddr).map_err(|_| ElfError::InvalidSegment)?;
+            let data = &self.bytes[segment.offset as usize..][..segment.filesz as usize];
+            space
+                .load_at(addr, data, segment.memsz, segment.page_flags())
+                .map_err(|err| match err {
+                    VmmError::AlreadyMapped | VmmError::OutOfAddressSpace => {
+                        ElfError::InvalidSegment
+                    }
+                    err => ElfError::Memory(err),
+                })?;
+        }
+        Ok(Image {
+            entry: self.entry(),
+            phdr: self.phdr(),
+            phnum: self.header.phnum,
+        })
+    }
+}
@@ -0,0 +1,224 @@
+//! Memory mapped for user programs.
+//!
+//! User mappings live in their own level 4 entry. Each process gets an [AddressSpace] with its own
+//! level 4 table, which shares the kernel's mappings and owns everything in the user entry. The
+//! mappings and the page tables holding them are freed when it's dropped. Mappings placed by the
+//! kernel are preceded by an unmapped guard page, so that a user stack overflow faults.
+use super::syscall::SyscallError;
+use crate::{
+    memory::{
+        phys_to_virt,
+        vmm::{RangeAllocator, VMM, VirtRange, Vmm, VmmError},
+    },
+    process,
+};
+use alloc::vec::Vec;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{PageTableFlags, PhysFrame},
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The start of the virtual address range user memory is mapped in.
+pub const USER_REGION_START: u64 = 0x_1000_0000_0000;
+/// The size of the user address range, one level 4 entry.
+pub const USER_REGION_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+const REGION: VirtRange = VirtRange {
+    start: VirtAddr::new_truncate(USER_REGION_START),
+    end: VirtAddr::new_truncate(USER_REGION_START + USER_REGION_SIZE),
+};
+
+/// The memory of a user program.
+pub struct AddressSpace {
+    /// The level 4 table.
+    table: PhysFrame,
+    ranges: RangeAllocator,
+    /// The reserved ranges, including their guard pages.
+    mappings: Vec<VirtRange>,
+}
+
+impl AddressSpace {
+    /// Create an empty address space.
+    pub fn new() -> Result<Self, VmmError> {
+        Ok(AddressSpace {
+            table: VMM.lock().create_table(REGION)?,
+            ranges: RangeAllocator::new(REGION),
+            mappings: Vec::new(),
+        })
+    }
+    /// The level 4 table of the address space.
+    pub fn table(&self) -> PhysFrame {
+        self.table
+    }
+    /// Map `data` followed by zeroes, up to `len` bytes, wherever there is room.
+    pub fn load(
+        &mut self,
+        data: &[u8],
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<VirtRange, VmmError> {
+        let len = len.max(data.len() as u64).next_multiple_of(PAGE_SIZE);
+        let range = self
+            .ranges
+            .alloc(len + PAGE_SIZE, PAGE_SIZE)
+            .ok_or(VmmError::OutOfAddressSpace)?;
+        let mapped = VirtRange {
+            start: range.start + PAGE_SIZE,
+            end: range.end,
+        };
+        self.fill(range, mapped, mapped.start, data, flags)?;
+        Ok(mapped)
+    }
+    /// Map `len` zeroed bytes wherever there is room.
+    pub fn map(&mut self, len: u64, flags: PageTableFlags) -> Result<VirtRange, VmmError> {
+        self.load(&[], len, flags)
+    }
+    /// Map the pages covering `len` bytes at `addr`, with `data` copied to `addr` and the rest
+    /// zeroed.
+    pub fn load_at(
+        &mut self,
+        addr: VirtAddr,
+        data: &[u8],
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<VirtRange, VmmError> {
+        let len = len.max(data.len() as u64);
+        let end = addr
+            .as_u64()
+            .checked_add(len)
+            .filter(|&end| end <= REGION.end.as_u64())
+            .ok_or(VmmError::OutOfAddressSpace)?;
+        let range = VirtRange {
+            start: addr.align_down(PAGE_SIZE),
+            end: VirtAddr::new(end).align_up(PAGE_SIZE),
+        };
+        if !REGION.contains(range.start) || !self.ranges.reserve(range) {
+            return Err(VmmError::AlreadyMapped);
+        }
+        self.fill(range, range, addr, data, flags)?;
+        Ok(range)
+    }
+    /// Map the pages of a reserved range, taking ownership of it, zero them and copy `data` to
+    /// `addr`.
+    fn fill(
+        &mut self,
+        range: VirtRange,
+        mapped: VirtRange,
+        addr: VirtAddr,
+        data: &[u8],
+        flags: PageTableFlags,
+    ) -> Result<(), VmmError> {
+        let mut vmm = VMM.lock();
+        let result = vmm.with_table(self.table, |vmm| {
+            vmm.map_range(mapped, flags)?;
+            for_each_chunk(vmm, mapped.start, mapped.len(), |bytes| bytes.fill(0))?;
+            write(vmm, addr, data)
+        });
+        if result.is_err() {
+            vmm.with_table(self.table, |vmm| vmm.unmap_range(mapped));
+            self.ranges.free(range);
+        }
+        drop(vmm);
+        if result.is_ok() {
+            self.mappings.push(range);
+        }
+        result
+    }
+    /// Copy `data` to `addr`, which must be mapped, regardless of whether the address space is
+    /// active or the pages are writable.
+    pub fn write(&self, addr: VirtAddr, data: &[u8]) -> Result<(), VmmError> {
+        VMM.lock()
+            .with_table(self.table, |vmm| write(vmm, addr, data))
+    }
+    /// Returns whether all of `range` is mapped in user memory with `flags`.
+    pub fn is_mapped(&self, range: VirtRange, flags: PageTableFlags) -> bool {
+        if range.start < REGION.start || range.end > REGION.end {
+            return false;
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        VMM.lock().with_table(self.table, |vmm| {
+            let mut page = range.start.align_down(PAGE_SIZE);
+            while page < range.end {
+                if !vmm.flags(page).is_some_and(|mapped| mapped.contains(flags)) {
+                    return false;
+                }
+                page += PAGE_SIZE;
+            }
+            true
+        })
+    }
+}
+
+/// Run `f` on the `len` bytes at `addr`, one page at a time, through the mapping of physical
+/// memory.
+fn for_each_chunk(
+    vmm: &Vmm,
+    addr: VirtAddr,
+    len: u64,
+    mut f: impl FnMut(&mut [u8]),
+) -> Result<(), VmmError> {
+    let mut done = 0;
+    while done < len {
+        let at = addr + done;
+        let chunk = (PAGE_SIZE - at.as_u64() % PAGE_SIZE).min(len - done);
+        let phys = vmm.translate(at).ok_or(VmmError::NotMapped)?;
+        f(unsafe {
+            core::slice::from_raw_parts_mut(phys_to_virt(phys).as_mut_ptr(), chunk as usize)
+        });
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Copy `data` to `addr` through the mapping of physical memory.
+fn write(vmm: &Vmm, addr: VirtAddr, mut data: &[u8]) -> Result<(), VmmError> {
+    for_each_chunk(vmm, addr, data.len() as u64, |bytes| {
+        let (chunk, rest) = data.split_at(bytes.len());
+        bytes.copy_from_slice(chunk);
+        data = rest;
+    })
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let mut vmm = VMM.lock();
+        vmm.with_table(self.table, |vmm| {
+            for &range in &self.mappings {
+                vmm.unmap_range(range);
+            }
+        });
+        // SAFETY: Everything was unmapped, and processes switch to the kernel's table before
+        // dropping their address space.
+        unsafe { vmm.free_table(self.table, REGION) };
+    }
+}
+
+/// Check that `len` bytes at `ptr` are mapped in the memory of the current process with `flags`.
+fn check(ptr: u64, len: u64, flags: PageTableFlags) -> Result<(), SyscallError> {
+    let end = ptr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    let (Ok(start), Ok(end)) = (VirtAddr::try_new(ptr), VirtAddr::try_new(end)) else {
+        return Err(SyscallError::BadAddress);
+    };
+    let range = VirtRange { start, end };
+    let mapped = process::current()
+        .and_then(|process| process.with_space(|space| space.is_mapped(range, flags)))
+        .unwrap_or(false);
+    if !mapped {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(())
+}
+
+/// Borrow a buffer passed to a syscall.
+pub fn user_slice(ptr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    check(ptr, len, PageTableFlags::empty())?;
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+/// Borrow a buffer a syscall writes to.
+pub fn user_slice_mut(ptr: u64, len: u64) -> Result<&'static mut [u8], SyscallError> {
+    check(ptr, len, PageTableFlags::WRITABLE)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
@@ -0,0 +1,18 @@
+//! User programs embedded in the kernel image, until there is a filesystem to load them from.
+//!
+//! They are assembled from the sources in `kernel/programs` by its `build.sh`.
+
+/// The embedded programs by name.
+pub const PROGRAMS: &[(&str, &[u8])] = &[
+    ("hello", include_bytes!("../../programs/hello.elf")),
+    ("sleep", include_bytes!("../../programs/sleep.elf")),
+    ("test", include_bytes!("../../programs/test.elf")),
+];
+
+/// Returns the ELF file of the program called `name`.
+pub fn get(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS
+        .iter()
+        .find(|&&(program, _)| program == name)
+        .map(|&(_, elf)| elf)
+}
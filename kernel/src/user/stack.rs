@@ -0,0 +1,52 @@
+//! The initial stack of a program, as laid out by the System V ABI.
+//!
+//! From the stack pointer up: `argc`, the `argv` pointers followed by a null, the `envp` pointers
+//! followed by a null, the auxiliary vector terminated by `AT_NULL`, and then the strings.
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+const AT_NULL: u64 = 0;
+
+/// Build the initial stack of a program for a stack ending at `end`. Returns the stack pointer to
+/// start the program with and the bytes to write there, or `None` if they don't fit in `size`
+/// bytes.
+pub(super) fn build(
+    end: VirtAddr,
+    size: u64,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: impl IntoIterator<Item = (u64, u64)>,
+) -> Option<(VirtAddr, Vec<u8>)> {
+    let len: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let strings_start = end.as_u64().checked_sub(len as u64)?;
+    let mut strings = Vec::with_capacity(len);
+    let mut push_str = |s: &str| {
+        let addr = strings_start + strings.len() as u64;
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+        addr
+    };
+    let argv: Vec<u64> = argv.iter().map(|arg| push_str(arg)).collect();
+    let envp: Vec<u64> = envp.iter().map(|var| push_str(var)).collect();
+
+    let mut words = Vec::with_capacity(argv.len() + envp.len() + 16);
+    words.push(argv.len() as u64);
+    words.extend(argv);
+    words.push(0);
+    words.extend(envp);
+    words.push(0);
+    for (key, value) in auxv {
+        words.extend([key, value]);
+    }
+    words.extend([AT_NULL, 0]);
+
+    // The stack pointer has to be 16 byte aligned, with argc at the bottom.
+    let rsp = strings_start.checked_sub((words.len() * size_of::<u64>()) as u64)? & !15;
+    if end.as_u64() - rsp > size {
+        return None;
+    }
+    let mut bytes: Vec<u8> = words.iter().flat_map(|word| word.to_ne_bytes()).collect();
+    bytes.resize((strings_start - rsp) as usize, 0);
+    bytes.extend(strings);
+    Some((VirtAddr::new(rsp), bytes))
+}
@@ -0,0 +1,255 @@
+//! The `syscall` interface.
+//!
+//! The syscall number is passed in `rax` and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`,
+//! `r8` and `r9`, like on Linux. The result is returned in `rax`, negative values being a
+//! [SyscallError]. `rcx` and `r11` are clobbered, every other register is preserved.
+//!
+//! | Number | Syscall                       | Returns                     |
+//! |--------|-------------------------------|-----------------------------|
+//! | 0      | `write(fd, buf, len)`         | the number of bytes written |
+//! | 1      | `read(fd, buf, len)`          | the number of bytes read    |
+//! | 2      | `exit(code)`                  | does not return             |
+//! | 3      | `sleep(ms)`                   | 0                           |
+//! | 4      | `getpid()`                    | the ID of the process       |
+//! | 5      | `mmap(addr, len, prot)`       | the address of the mapping  |
+//!
+//! File descriptors index the [handle table](crate::process::Handle) of the process, which starts
+//! out with the keyboard, the VGA console and the serial port as 0, 1 and 2. `mmap` only supports
+//! anonymous mappings placed by the kernel, so `addr` must be 0, and `prot` is a combination of
+//! [PROT_READ], [PROT_WRITE] and [PROT_EXEC].
+use super::memory;
+use crate::{
+    gdt,
+    prelude::*,
+    process::{self, Handle, Process},
+    smp::PerCpu,
+    task::{keyboard::KeypressStream, timer},
+    thread,
+};
+use core::mem::offset_of;
+use futures_util::StreamExt;
+use pc_keyboard::DecodedKey;
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::PageTableFlags,
+};
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// The syscall numbers, which are part of the user ABI and must never change.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    Write = 0,
+    Read = 1,
+    Exit = 2,
+    Sleep = 3,
+    GetPid = 4,
+    Mmap = 5,
+}
+
+impl TryFrom<u64> for Syscall {
+    type Error = SyscallError;
+
+    fn try_from(number: u64) -> Result<Self, SyscallError> {
+        Ok(match number {
+            0 => Syscall::Write,
+            1 => Syscall::Read,
+            2 => Syscall::Exit,
+            3 => Syscall::Sleep,
+            4 => Syscall::GetPid,
+            5 => Syscall::Mmap,
+            _ => return Err(SyscallError::NoSuchSyscall),
+        })
+    }
+}
+
+/// The errors returned by syscalls, using the Linux error numbers.
+#[repr(i64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// The process was killed while the syscall was blocked.
+    Interrupted = -4,
+    BadFileDescriptor = -9,
+    OutOfMemory = -12,
+    /// A buffer isn't entirely mapped in user memory.
+    BadAddress = -14,
+    InvalidArgument = -22,
+    NoSuchSyscall = -38,
+}
+
+/// The user registers saved on the kernel stack by `syscall_entry`.
+#[repr(C)]
+#[derive(Debug)]
+struct SyscallFrame {
+    number: u64,
+    args: [u64; 6],
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+}
+
+core::arch::global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    // Interrupts are masked by SFMASK, so the user GS base can be swapped in briefly to reach
+    // the PerCpu block.
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    "push qword ptr gs:[{user_stack}]",
+    "swapgs",
+    "push rcx",
+    "push r11",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "cli",
+    "add rsp, 8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+    user_stack = const offset_of!(PerCpu, user_stack),
+    kernel_stack = const offset_of!(PerCpu, kernel_stack),
+    dispatch = sym dispatch,
+);
+
+unsafe extern "C" {
+    fn syscall_entry();
+}
+
+/// Enable the `syscall` instruction on the current CPU.
+pub(super) fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("The GDT segments are not laid out for sysret");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|efer| efer.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+extern "C" fn dispatch(frame: &mut SyscallFrame) -> i64 {
+    interrupts::enable();
+    let process = process::current().expect("A syscall was made outside of a process");
+    let [a0, a1, a2, ..] = frame.args;
+    let result = match Syscall::try_from(frame.number) {
+        Ok(Syscall::Write) => write(&process, a0, a1, a2),
+        Ok(Syscall::Read) => read(&process, a0, a1, a2),
+        Ok(Syscall::Exit) => {
+            drop(process);
+            super::exit(a0 as i64)
+        }
+        Ok(Syscall::Sleep) => sleep(&process, a0),
+        Ok(Syscall::GetPid) => Ok(process.pid().as_u64()),
+        Ok(Syscall::Mmap) => mmap(&process, a0, a1, a2),
+        Err(err) => Err(err),
+    };
+    let killed = process.is_killed();
+    drop(process);
+    if killed {
+        super::exit(process::KILLED_EXIT_CODE);
+    }
+    interrupts::disable();
+    match result {
+        Ok(value) => value as i64,
+        Err(err) => err as i64,
+    }
+}
+
+fn write(process: &Process, fd: u64, buf: u64, len: u64) -> Result<u64, SyscallError> {
+    let handle = process.handle(fd);
+    let buf = memory::user_slice(buf, len)?;
+    match handle {
+        Some(Handle::Vga) => {
+            let mut vga = VGA_OUT.lock();
+            buf.iter().for_each(|&byte| vga.write_byte(byte));
+        }
+        Some(Handle::Serial) => {
+            let mut serial = SERIAL1.lock();
+            buf.iter().for_each(|&byte| serial.send(byte));
+        }
+        Some(Handle::Keyboard) | None => return Err(SyscallError::BadFileDescriptor),
+    }
+    Ok(len)
+}
+
+/// Wait for a key that produces a character, and read its UTF-8 encoding if it fits.
+fn read(process: &Process, fd: u64, buf: u64, len: u64) -> Result<u64, SyscallError> {
+    if process.handle(fd) != Some(Handle::Keyboard) {
+        return Err(SyscallError::BadFileDescriptor);
+    }
+    let buf = memory::user_slice_mut(buf, len)?;
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    let mut keypresses = KeypressStream::new();
+    let char = block(process, async {
+        loop {
+            if let Some((_, Some(DecodedKey::Unicode(char)))) = keypresses.next().await {
+                break char;
+            }
+        }
+    })?;
+    if char.len_utf8() > buf.len() {
+        return Err(SyscallError::InvalidArgument);
+    }
+    Ok(char.encode_utf8(buf).len() as u64)
+}
+
+fn sleep(process: &Process, ms: u64) -> Result<u64, SyscallError> {
+    block(process, timer::sleep(ms))?;
+    Ok(0)
+}
+
+/// Block the process on `future`, giving up if it is [killed](process::kill).
+fn block<F: Future>(process: &Process, future: F) -> Result<F::Output, SyscallError> {
+    thread::block_on_unless(future, || process.is_killed()).ok_or(SyscallError::Interrupted)
+}
+
+fn mmap(process: &Process, addr: u64, len: u64, prot: u64) -> Result<u64, SyscallError> {
+    if addr != 0 || len == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let range = process
+        .with_space(|space| space.map(len, flags))
+        .expect("A running process has no address space")
+        .map_err(|_| SyscallError::OutOfMemory)?;
+    Ok(range.start.as_u64())
+}
@@ -0,0 +1,50 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use kernel::acpi::{self, Fadt, HpetTable, Madt, MadtEntry};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[test_case]
+fn tables_are_found() {
+    assert!(acpi::is_available());
+    assert!(acpi::tables().count() > 0);
+    assert!(acpi::find_table(b"FACP").is_some());
+    assert!(acpi::find_table(b"NONE").is_none());
+}
+
+#[test_case]
+fn madt_lists_the_boot_processor() {
+    let madt = Madt::get().unwrap();
+    assert!(
+        madt.entries()
+            .any(|entry| matches!(entry, MadtEntry::LocalApic { .. }))
+    );
+    assert!(
+        madt.entries()
+            .any(|entry| matches!(entry, MadtEntry::IoApic { .. }))
+    );
+}
+
+#[test_case]
+fn fadt_references_the_dsdt() {
+    let fadt = Fadt::get().unwrap();
+    let dsdt = fadt.dsdt_table().unwrap();
+    assert_eq!(&dsdt.signature, b"DSDT");
+    assert_ne!(fadt.pm1a_control_block(), 0);
+}
+
+#[test_case]
+fn hpet_has_a_base_address() {
+    let hpet = HpetTable::get().unwrap();
+    assert!(hpet.base_address().is_some());
+}
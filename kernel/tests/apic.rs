@@ -0,0 +1,30 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use kernel::{acpi::Madt, clock::Instant, interrupts::apic};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[test_case]
+fn apic_is_enabled() {
+    assert!(apic::is_supported());
+    assert!(Madt::get().is_some());
+    assert!(apic::is_enabled());
+}
+
+#[test_case]
+fn timer_interrupts_are_delivered() {
+    let start = Instant::now();
+    while start.elapsed_ms() < 10 {
+        x86_64::instructions::hlt();
+    }
+}
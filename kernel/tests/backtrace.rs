@@ -0,0 +1,60 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+extern crate alloc;
+use alloc::format;
+use bootloader::{BootInfo, entry_point};
+use kernel::backtrace::{Backtrace, symbolize};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[inline(never)]
+fn inner() -> Backtrace {
+    Backtrace::capture()
+}
+
+#[inline(never)]
+fn outer() -> Backtrace {
+    inner()
+}
+
+/// Returns the name of the function containing the return address `addr`.
+fn caller(addr: u64) -> &'static str {
+    symbolize(addr - 1).unwrap().name
+}
+
+#[test_case]
+fn functions_are_symbolized() {
+    let symbol = symbolize(inner as usize as u64 + 1).unwrap();
+    assert_eq!(symbol.name, "backtrace::inner");
+    assert_eq!(symbol.offset, 1);
+}
+
+#[test_case]
+fn captured_frames_are_the_callers() {
+    let backtrace = outer();
+    let frames = backtrace.frames();
+    assert!(frames.len() >= 3);
+    assert_eq!(caller(frames[0]), "backtrace::inner");
+    assert_eq!(caller(frames[1]), "backtrace::outer");
+    assert_eq!(
+        caller(frames[2]),
+        "backtrace::captured_frames_are_the_callers"
+    );
+}
+
+#[test_case]
+fn interrupted_frames_start_at_the_instruction() {
+    let rip = outer as usize as u64;
+    let backtrace = Backtrace::from_frame(rip, 0);
+    assert_eq!(backtrace.frames(), [rip]);
+    assert!(format!("{backtrace}").contains("backtrace::outer+0x0"));
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use core::time::Duration;
+use kernel::clock::{self, ClockSource, Instant};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[test_case]
+fn high_resolution_source_is_selected() {
+    let source = clock::source().unwrap();
+    assert!(!matches!(source, ClockSource::Pit), "{}", source.name());
+}
+
+#[test_case]
+fn instants_are_monotonic() {
+    let mut last = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn sub_millisecond_precision() {
+    let start = Instant::now();
+    let mut now = Instant::now();
+    while now == start {
+        now = Instant::now();
+    }
+    assert!(now.duration_since(start) < Duration::from_millis(1));
+}
+
+#[test_case]
+fn tracks_the_millisecond_clock() {
+    let start = Instant::now();
+    let ms_start = kernel::clock::uptime();
+    while start.elapsed_ms() < 20 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = Instant::now() - start;
+    assert!(elapsed >= Duration::from_millis(20));
+    assert!(elapsed < Duration::from_millis(40));
+    assert!(kernel::clock::uptime() - ms_start >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn duration_arithmetic() {
+    let start = Instant::now();
+    let later = start + Duration::from_micros(1500);
+    assert_eq!(later - start, Duration::from_micros(1500));
+    assert_eq!(later - Duration::from_micros(1500), start);
+    assert_eq!(later.since_epoch_ns() - start.since_epoch_ns(), 1_500_000);
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use kernel::memory::vmm::{COW, VMM, fault_stats};
+use x86_64::{
+    VirtAddr,
+    structures::paging::{PageTableFlags, PhysFrame},
+};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+fn frame(addr: VirtAddr) -> PhysFrame {
+    PhysFrame::containing_address(VMM.lock().translate(addr).unwrap())
+}
+
+#[test_case]
+fn fork_style_sharing() {
+    let mut vmm = VMM.lock();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let parent = vmm.alloc_mapped(2 * 4096, flags).unwrap();
+    let child = vmm.alloc_range(2 * 4096).unwrap();
+    for page in 0..2u64 {
+        unsafe {
+            (parent.start + page * 4096)
+                .as_mut_ptr::<u64>()
+                .write(page + 1)
+        };
+    }
+    vmm.clone_cow(parent, child.start).unwrap();
+    let used = vmm.frame_stats().used_frames;
+    assert!(vmm.flags(parent.start).unwrap().contains(COW));
+    assert!(
+        !vmm.flags(child.start)
+            .unwrap()
+            .contains(PageTableFlags::WRITABLE)
+    );
+    core::mem::drop(vmm);
+
+    let parent_ptr: *mut u64 = parent.start.as_mut_ptr();
+    let child_ptr: *mut u64 = child.start.as_mut_ptr();
+    assert_eq!(frame(parent.start), frame(child.start));
+    assert_eq!(VMM.lock().frames().refcount(frame(parent.start)), 2);
+    unsafe { assert_eq!(child_ptr.read_volatile(), 1) };
+
+    // Writing to the child copies the shared frame, leaving the parent untouched.
+    let before = fault_stats();
+    unsafe { child_ptr.write_volatile(42) };
+    assert_eq!(fault_stats().handled, before.handled + 1);
+    unsafe {
+        assert_eq!(child_ptr.read_volatile(), 42);
+        assert_eq!(parent_ptr.read_volatile(), 1);
+    }
+    assert_ne!(frame(parent.start), frame(child.start));
+    assert_eq!(VMM.lock().frames().refcount(frame(parent.start)), 1);
+
+    // The last sharer of a frame takes it over without copying.
+    unsafe { parent_ptr.write_volatile(7) };
+    let second = parent.start + 4096u64;
+    let shared = frame(second);
+    unsafe { (second + 8u64).as_mut_ptr::<u64>().write_volatile(3) };
+    assert_ne!(frame(second), shared);
+    unsafe {
+        (child.start + 4096u64 + 8u64)
+            .as_mut_ptr::<u64>()
+            .write_volatile(4)
+    };
+    assert_eq!(frame(child.start + 4096u64), shared);
+    unsafe {
+        assert_eq!((second + 8u64).as_ptr::<u64>().read_volatile(), 3);
+        assert_eq!(second.as_ptr::<u64>().read_volatile(), 2);
+        assert_eq!((child.start + 4096u64).as_ptr::<u64>().read_volatile(), 2);
+    }
+
+    let mut vmm = VMM.lock();
+    vmm.free_mapped(child);
+    vmm.free_mapped(parent);
+    assert_eq!(vmm.frame_stats().used_frames, used - 2);
+}
+
+#[test_case]
+fn read_only_pages_are_shared_without_cow() {
+    let mut vmm = VMM.lock();
+    let src = vmm.alloc_mapped(4096, PageTableFlags::PRESENT).unwrap();
+    let dst = vmm.alloc_range(4096).unwrap();
+    vmm.clone_cow(src, dst.start).unwrap();
+    assert!(!vmm.flags(dst.start).unwrap().contains(COW));
+    let used = vmm.frame_stats().used_frames;
+    vmm.free_mapped(src);
+    assert_eq!(vmm.frame_stats().used_frames, used);
+    vmm.free_mapped(dst);
+    assert_eq!(vmm.frame_stats().used_frames, used - 1);
+}
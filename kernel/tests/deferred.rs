@@ -0,0 +1,57 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering::*};
+use kernel::{
+    clock::Instant,
+    interrupts::{self, deferred::defer},
+    test::{UNUSED_IRQ, raise_unused_irq},
+};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+static LAST_ARG: AtomicU64 = AtomicU64::new(0);
+static INTERRUPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn record(arg: u64) {
+    INTERRUPTS_ENABLED.store(x86_64::instructions::interrupts::are_enabled(), Relaxed);
+    LAST_ARG.store(arg, Relaxed);
+}
+
+/// Wait up to 100 ms for `record` to have run with `arg`.
+fn wait_for(arg: u64) -> bool {
+    let start = Instant::now();
+    while LAST_ARG.load(Relaxed) != arg && start.elapsed_ms() < 100 {
+        x86_64::instructions::hlt();
+    }
+    LAST_ARG.load(Relaxed) == arg
+}
+
+#[test_case]
+fn deferred_work_runs_with_interrupts_enabled() {
+    defer(record, 1);
+    assert!(wait_for(1));
+    assert!(INTERRUPTS_ENABLED.load(Relaxed));
+}
+
+fn handler() {
+    defer(record, 2);
+}
+
+#[test_case]
+fn irq_handlers_defer_work() {
+    let id = interrupts::register_irq(UNUSED_IRQ, handler);
+    raise_unused_irq();
+    assert!(wait_for(2));
+    assert!(INTERRUPTS_ENABLED.load(Relaxed));
+    assert!(interrupts::unregister_irq(UNUSED_IRQ, id));
+}
@@ -0,0 +1,56 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use kernel::memory::vmm::{VMM, fault_stats};
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[test_case]
+fn lazy_region_is_mapped_on_first_touch() {
+    let range = VMM
+        .lock()
+        .alloc_lazy(16 * 4096, PageTableFlags::WRITABLE)
+        .unwrap();
+    let before = fault_stats();
+    assert!(VMM.lock().translate(range.start).is_none());
+
+    let ptr: *mut u64 = (range.start + 4096 * 3u64).as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert_eq!(fault_stats().handled, before.handled + 1);
+    assert!(VMM.lock().translate(range.start + 4096 * 3u64).is_some());
+    assert!(VMM.lock().translate(range.start).is_none());
+
+    VMM.lock().free_lazy(range);
+}
+
+#[test_case]
+fn released_region_returns_frames() {
+    let mut vmm = VMM.lock();
+    let range = vmm.alloc_lazy(4 * 4096, PageTableFlags::WRITABLE).unwrap();
+    core::mem::drop(vmm);
+    for page in 0..4u64 {
+        unsafe {
+            (range.start + page * 4096)
+                .as_mut_ptr::<u8>()
+                .write_volatile(1)
+        };
+    }
+    let mut vmm = VMM.lock();
+    let used = vmm.frame_stats().used_frames;
+    vmm.free_lazy(range);
+    assert_eq!(vmm.frame_stats().used_frames, used - 4);
+}
@@ -0,0 +1,99 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+extern crate alloc;
+use alloc::vec::Vec;
+use bootloader::{BootInfo, entry_point};
+use kernel::user::{
+    self,
+    elf::{Elf, ElfError},
+    programs,
+};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+fn test_program() -> &'static [u8] {
+    programs::get("test").expect("The test program is embedded")
+}
+
+#[test_case]
+fn hello_exits_with_0() {
+    let hello = programs::get("hello").unwrap();
+    assert_eq!(user::exec(hello, &["hello", "world"], &[]), Ok(0));
+}
+
+#[test_case]
+fn argv_is_passed() {
+    let argv = ["test", "a", "b", "c"];
+    assert_eq!(user::exec(test_program(), &argv, &[]), Ok(4));
+}
+
+#[test_case]
+fn envp_is_passed() {
+    let envp = ["HOME=/", "TERM=vga"];
+    assert_eq!(user::exec(test_program(), &["test", "e"], &envp), Ok(2));
+}
+
+#[test_case]
+fn auxv_is_passed() {
+    assert_eq!(user::exec(test_program(), &["test", "p"], &[]), Ok(4096));
+}
+
+#[test_case]
+fn data_and_bss_are_writable() {
+    assert_eq!(user::exec(test_program(), &["test", "d"], &[]), Ok(44));
+}
+
+#[test_case]
+fn programs_run_on_threads() {
+    let argv = ["test", "a"].map(Into::into).to_vec();
+    let handle = user::spawn_exec(test_program(), argv, Vec::new());
+    assert_eq!(handle.join(), Ok(2));
+}
+
+#[test_case]
+fn segments_are_mapped_at_their_addresses() {
+    let elf = Elf::parse(test_program()).unwrap();
+    assert!(elf.segments().count() >= 2);
+    for segment in elf.segments() {
+        assert!(segment.vaddr >= user::USER_REGION_START);
+    }
+}
+
+#[test_case]
+fn invalid_headers_are_rejected() {
+    assert_eq!(Elf::parse(b"").err(), Some(ElfError::NotElf));
+    assert_eq!(Elf::parse(&[0; 64]).err(), Some(ElfError::NotElf));
+
+    let mut elf = test_program().to_vec();
+    // 32 bit class.
+    elf[4] = 1;
+    assert_eq!(Elf::parse(&elf).err(), Some(ElfError::Unsupported));
+    elf[4] = 2;
+    // A shared object rather than an executable.
+    elf[16] = 3;
+    assert_eq!(Elf::parse(&elf).err(), Some(ElfError::Unsupported));
+    elf[16] = 2;
+    // The entry point outside of any segment.
+    elf[24..32].copy_from_slice(&0x1234_u64.to_le_bytes());
+    assert_eq!(Elf::parse(&elf).err(), Some(ElfError::InvalidEntry));
+}
+
+#[test_case]
+fn truncated_files_are_rejected() {
+    let elf = test_program();
+    // The program headers are cut off.
+    let loaded = user::exec(&elf[..200], &["test"], &[]);
+    assert_eq!(loaded, Err(ElfError::Truncated));
+    // The code segment is cut off.
+    let loaded = user::exec(&elf[..0x1000], &["test"], &[]);
+    assert_eq!(loaded, Err(ElfError::InvalidSegment));
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+extern crate alloc;
+use alloc::format;
+use bootloader::{BootInfo, entry_point};
+use kernel::{
+    interrupts::exceptions::{self, ErrorCode},
+    user, user_program,
+};
+use x86_64::structures::idt::PageFaultErrorCode;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+user_program!(invalid_opcode: "ud2");
+user_program!(divide_by_zero: "xor %ecx, %ecx", "div %ecx");
+// A privileged instruction.
+user_program!(halt: "hlt");
+user_program!(read_kernel_memory: "mov 0x200000, %rax");
+
+#[test_case]
+fn invalid_opcodes_end_the_program() {
+    assert_eq!(user::run(invalid_opcode()), Ok(128 + 4));
+}
+
+#[test_case]
+fn divide_errors_end_the_program() {
+    assert_eq!(user::run(divide_by_zero()), Ok(128 + 8));
+}
+
+#[test_case]
+fn general_protection_faults_end_the_program() {
+    assert_eq!(user::run(halt()), Ok(128 + 11));
+}
+
+#[test_case]
+fn page_faults_end_the_program() {
+    assert_eq!(user::run(read_kernel_memory()), Ok(128 + 11));
+}
+
+#[test_case]
+fn breakpoints_continue() {
+    x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn selector_error_codes_are_decoded() {
+    // Index 5 of the GDT.
+    let ErrorCode::Selector(selector) = ErrorCode::decode(13, 0x28) else {
+        panic!("Expected a selector error code");
+    };
+    assert_eq!(selector.index(), 5);
+    assert_eq!(format!("{}", ErrorCode::decode(13, 0x28)), "GDT index 5");
+    assert_eq!(
+        format!("{}", ErrorCode::decode(11, 0x1b)),
+        "IDT index 3, external"
+    );
+    assert_eq!(format!("{}", ErrorCode::decode(13, 0)), "no selector");
+}
+
+#[test_case]
+fn page_fault_error_codes_are_decoded() {
+    let code = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    assert_eq!(ErrorCode::decode(14, 0b11), ErrorCode::PageFault(code));
+}
+
+#[test_case]
+fn exceptions_without_error_codes_are_not_decoded() {
+    assert_eq!(ErrorCode::decode(6, 0), ErrorCode::None);
+    assert_eq!(exceptions::name(6), ("#UD", "Invalid Opcode"));
+}
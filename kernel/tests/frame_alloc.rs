@@ -0,0 +1,56 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use kernel::memory::vmm::VMM;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[test_case]
+fn allocate_and_free() {
+    let mut vmm = VMM.lock();
+    let frames = vmm.frames();
+    let before = frames.stats();
+    let a = frames.allocate_frame().unwrap();
+    let b = frames.allocate_frame().unwrap();
+    assert_ne!(a, b);
+    assert_eq!(frames.stats().free_frames, before.free_frames - 2);
+    unsafe {
+        frames.deallocate_frame(a);
+        frames.deallocate_frame(b);
+    }
+    assert_eq!(frames.stats().free_frames, before.free_frames);
+}
+
+#[test_case]
+fn freed_frames_are_reused() {
+    let mut vmm = VMM.lock();
+    let frames = vmm.frames();
+    let a = frames.allocate_frame().unwrap();
+    unsafe { frames.deallocate_frame(a) };
+    let b = frames.allocate_frame().unwrap();
+    assert_eq!(a, b);
+    unsafe { frames.deallocate_frame(b) };
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    let mut vmm = VMM.lock();
+    let frames = vmm.frames();
+    let before = frames.stats();
+    let range = frames.allocate_contiguous(16, 8).unwrap();
+    assert_eq!(range.len(), 16);
+    assert_eq!(range.start.start_address().as_u64() % (8 * 4096), 0);
+    assert_eq!(frames.stats().used_frames, before.used_frames + 16);
+    unsafe { frames.deallocate_contiguous(range) };
+    assert_eq!(frames.stats().used_frames, before.used_frames);
+}
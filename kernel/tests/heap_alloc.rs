@@ -7,7 +7,12 @@ extern crate alloc;
 use alloc::vec::Vec;
 use alloc::{boxed::Box, vec};
 use bootloader::{BootInfo, entry_point};
-use kernel::memory::global_alloc::HEAP_SIZE;
+use kernel::memory::{
+    global_alloc::{ALLOCATOR, HEAP_MAX_SIZE, HEAP_SIZE, HEAP_START, HEAP_STEP, set_heap_limit},
+    slab_alloc::SIZE_CLASSES,
+    vmm::VMM,
+};
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
 
 entry_point!(main);
 fn main(boot_info: &'static BootInfo) -> ! {
@@ -55,6 +60,9 @@ fn many_boxes_long_lived() {
 
 #[test_case]
 fn merge_small_allocs_to_large() {
+    // Growing the heap would serve the large allocation without merging the small ones.
+    let total = ALLOCATOR.0.lock().stats().total;
+    set_heap_limit(total);
     let small = [0u64; 2];
     let mut allocs = Vec::new();
     for _ in (0..HEAP_SIZE).step_by(16 * 2) {
@@ -62,5 +70,73 @@ fn merge_small_allocs_to_large() {
         allocs.push(x);
     }
     core::mem::drop(allocs);
-    let _large = vec![0u8; HEAP_SIZE - 1024];
+    let large = vec![0u8; HEAP_SIZE - 1024];
+    set_heap_limit(HEAP_MAX_SIZE);
+    core::mem::drop(large);
+}
+
+#[test_case]
+fn grow_past_initial_size() {
+    let large = vec![1u8; HEAP_SIZE * 4];
+    assert!(ALLOCATOR.0.lock().stats().total > HEAP_SIZE * 4);
+    assert_eq!(
+        large.iter().map(|&b| b as usize).sum::<usize>(),
+        HEAP_SIZE * 4
+    );
+    core::mem::drop(large);
+    assert!(ALLOCATOR.0.lock().stats().total < HEAP_SIZE * 2);
+}
+
+#[test_case]
+fn heap_is_mapped_with_huge_pages() {
+    let large = vec![1u8; HEAP_STEP * 2];
+    let grown = VirtAddr::new((HEAP_START + HEAP_SIZE) as u64);
+    let vmm = VMM.lock();
+    for addr in [VirtAddr::new(HEAP_START as u64), grown] {
+        let flags = vmm.flags(addr).unwrap();
+        assert!(flags.contains(PageTableFlags::HUGE_PAGE));
+    }
+    core::mem::drop(vmm);
+    core::mem::drop(large);
+}
+
+#[test_case]
+fn small_allocations_use_size_classes() {
+    let before = ALLOCATOR.0.lock().stats().size_classes[0];
+    let small = Box::new(1u8);
+    let after = ALLOCATOR.0.lock().stats().size_classes[0];
+    assert_eq!(after.block_size, 8);
+    assert_eq!(after.allocated, before.allocated + 1);
+    core::mem::drop(small);
+    let freed = ALLOCATOR.0.lock().stats().size_classes[0];
+    assert_eq!(freed.allocated, before.allocated);
+}
+
+#[test_case]
+fn empty_slabs_are_released() {
+    let before = ALLOCATOR.0.lock().stats().size_classes[0];
+    let boxes: Vec<Box<u8>> = (0..4096).map(|i| Box::new(i as u8)).collect();
+    assert!(ALLOCATOR.0.lock().stats().size_classes[0].slabs > before.slabs + 1);
+    core::mem::drop(boxes);
+    let after = ALLOCATOR.0.lock().stats().size_classes[0];
+    assert!(after.slabs <= before.slabs + 1);
+    assert_eq!(after.allocated, before.allocated);
+}
+
+/// Larger than the biggest size class, so that it is served by the free list.
+const UNCLASSED: usize = SIZE_CLASSES[SIZE_CLASSES.len() - 1] * 2;
+
+#[test_case]
+fn realloc_in_place() {
+    let mut vec: Vec<u8> = Vec::with_capacity(UNCLASSED * 3);
+    vec.extend((0..UNCLASSED).map(|i| i as u8));
+    let ptr = vec.as_ptr();
+    // Shrinking returns the tail to the free list, so the memory right after the allocation is
+    // free to grow back into, whatever the rest of the heap looks like.
+    vec.shrink_to_fit();
+    assert_eq!(vec.as_ptr(), ptr);
+    vec.reserve_exact(UNCLASSED * 2);
+    assert_eq!(vec.as_ptr(), ptr);
+    assert!(vec.capacity() >= UNCLASSED * 3);
+    assert!(vec.iter().enumerate().all(|(i, &b)| b == i as u8));
 }
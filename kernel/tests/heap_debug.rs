@@ -0,0 +1,113 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+//! The checks of the `heap_debug` feature. Run with `cargo test --features heap_debug`.
+extern crate alloc;
+use bootloader::{BootInfo, entry_point};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[cfg(feature = "heap_debug")]
+mod checks {
+    use alloc::alloc::{alloc, dealloc};
+    use core::alloc::Layout;
+    use kernel::memory::{freelist_alloc::HeapError, global_alloc::check_allocation};
+
+    const LAYOUT: Layout = match Layout::from_size_align(64, 64) {
+        Ok(layout) => layout,
+        Err(_) => panic!(),
+    };
+
+    /// Run `f` on a fresh allocation of [LAYOUT], which is freed afterwards.
+    fn with_allocation(f: impl FnOnce(*mut u8)) {
+        let ptr = unsafe { alloc(LAYOUT) };
+        assert!(!ptr.is_null());
+        f(ptr);
+        assert_eq!(check_allocation(ptr, LAYOUT), Ok(()));
+        unsafe { dealloc(ptr, LAYOUT) };
+    }
+
+    /// Overwrite the byte at `at` while running `f`.
+    fn with_corrupted(at: *mut u8, f: impl FnOnce()) {
+        let canary = unsafe { at.read_volatile() };
+        unsafe { at.write_volatile(!canary) };
+        f();
+        unsafe { at.write_volatile(canary) };
+    }
+
+    #[test_case]
+    fn live_allocations_pass() {
+        with_allocation(|_| ());
+    }
+
+    #[test_case]
+    fn double_free() {
+        let ptr = unsafe { alloc(LAYOUT) };
+        unsafe { dealloc(ptr, LAYOUT) };
+        let addr = ptr as usize;
+        assert_eq!(
+            check_allocation(ptr, LAYOUT),
+            Err(HeapError::DoubleFree { addr })
+        );
+    }
+
+    #[test_case]
+    fn overflow() {
+        with_allocation(|ptr| {
+            let at = ptr.wrapping_add(LAYOUT.size());
+            with_corrupted(at, || {
+                let (addr, at) = (ptr as usize, at as usize);
+                assert_eq!(
+                    check_allocation(ptr, LAYOUT),
+                    Err(HeapError::Overflow { addr, at })
+                );
+            });
+        });
+    }
+
+    #[test_case]
+    fn underflow() {
+        with_allocation(|ptr| {
+            let at = ptr.wrapping_sub(1);
+            with_corrupted(at, || {
+                let (addr, at) = (ptr as usize, at as usize);
+                assert_eq!(
+                    check_allocation(ptr, LAYOUT),
+                    Err(HeapError::Underflow { addr, at })
+                );
+            });
+        });
+    }
+
+    #[test_case]
+    fn layout_mismatch() {
+        with_allocation(|ptr| {
+            let addr = ptr as usize;
+            let mismatch = |freed_size, freed_align| HeapError::LayoutMismatch {
+                addr,
+                size: 64,
+                align: 64,
+                freed_size,
+                freed_align,
+            };
+            let smaller = Layout::from_size_align(32, 64).unwrap();
+            assert_eq!(check_allocation(ptr, smaller), Err(mismatch(32, 64)));
+            // Smaller by less than the padding the allocator rounds sizes up with.
+            let slightly_smaller = Layout::from_size_align(60, 64).unwrap();
+            assert_eq!(
+                check_allocation(ptr, slightly_smaller),
+                Err(mismatch(60, 64))
+            );
+            let less_aligned = Layout::from_size_align(64, 32).unwrap();
+            assert_eq!(check_allocation(ptr, less_aligned), Err(mismatch(64, 32)));
+        });
+    }
+}
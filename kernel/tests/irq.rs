@@ -0,0 +1,77 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use core::sync::atomic::{AtomicU64, Ordering::*};
+use kernel::{
+    clock::Instant,
+    interrupts::{self, IrqStats, TIMER_IRQ},
+    test::{UNUSED_IRQ, raise_unused_irq},
+};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+static FIRST: AtomicU64 = AtomicU64::new(0);
+static SECOND: AtomicU64 = AtomicU64::new(0);
+
+fn first() {
+    FIRST.fetch_add(1, Relaxed);
+}
+
+fn second() {
+    SECOND.fetch_add(1, Relaxed);
+}
+
+fn stats(irq: u8) -> IrqStats {
+    interrupts::irq_stats().nth(irq as usize).unwrap()
+}
+
+#[test_case]
+fn timer_interrupts_are_counted() {
+    let count = stats(TIMER_IRQ).count;
+    let start = Instant::now();
+    while start.elapsed_ms() < 20 {
+        x86_64::instructions::hlt();
+    }
+    assert!(stats(TIMER_IRQ).count > count);
+    // In tickless mode the local APIC timer fires on the line's vector instead of the PIT.
+    assert!(!stats(TIMER_IRQ).masked);
+}
+
+#[test_case]
+fn registering_unmasks_the_line() {
+    assert!(stats(UNUSED_IRQ).masked);
+    let id = interrupts::register_irq(UNUSED_IRQ, first);
+    let stats_after = stats(UNUSED_IRQ);
+    assert!(!stats_after.masked);
+    assert_eq!(stats_after.handlers, 1);
+    assert!(interrupts::unregister_irq(UNUSED_IRQ, id));
+    assert!(stats(UNUSED_IRQ).masked);
+    assert!(!interrupts::unregister_irq(UNUSED_IRQ, id));
+}
+
+#[test_case]
+fn shared_lines_run_every_handler() {
+    let first_id = interrupts::register_irq(UNUSED_IRQ, first);
+    let second_id = interrupts::register_irq(UNUSED_IRQ, second);
+    let count = stats(UNUSED_IRQ).count;
+    let (first_runs, second_runs) = (FIRST.load(Relaxed), SECOND.load(Relaxed));
+    raise_unused_irq();
+    assert_eq!(stats(UNUSED_IRQ).count, count + 1);
+    assert_eq!(FIRST.load(Relaxed), first_runs + 1);
+    assert_eq!(SECOND.load(Relaxed), second_runs + 1);
+
+    assert!(interrupts::unregister_irq(UNUSED_IRQ, first_id));
+    raise_unused_irq();
+    assert_eq!(FIRST.load(Relaxed), first_runs + 1);
+    assert_eq!(SECOND.load(Relaxed), second_runs + 2);
+    assert!(interrupts::unregister_irq(UNUSED_IRQ, second_id));
+}
@@ -0,0 +1,53 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use kernel::{
+    clock::Instant,
+    interrupts::{self, KEYBOARD_IRQ, TIMER_IRQ, apic},
+    smp,
+};
+use x86_64::instructions::interrupts::without_interrupts;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    interrupts::keep_legacy_pic();
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[test_case]
+fn apic_is_not_enabled() {
+    assert!(apic::is_supported());
+    assert!(!apic::is_enabled());
+    assert_eq!(smp::cpu_count(), 1);
+}
+
+#[test_case]
+fn timer_interrupts_are_delivered() {
+    let count = interrupts::irq_stats()
+        .nth(TIMER_IRQ as usize)
+        .unwrap()
+        .count;
+    let start = Instant::now();
+    while start.elapsed_ms() < 10 {
+        x86_64::instructions::hlt();
+    }
+    assert!(
+        interrupts::irq_stats()
+            .nth(TIMER_IRQ as usize)
+            .unwrap()
+            .count
+            > count
+    );
+}
+
+#[test_case]
+fn unmasked_lines_stay_open_on_the_pic() {
+    let [master, _] = without_interrupts(|| unsafe { interrupts::PICS.lock().read_masks() });
+    assert_eq!(master & (1 << TIMER_IRQ | 1 << KEYBOARD_IRQ), 0);
+}
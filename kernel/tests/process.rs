@@ -0,0 +1,142 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+extern crate alloc;
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{BootInfo, entry_point};
+use kernel::{
+    clock::Instant,
+    memory::vmm::VMM,
+    process::{self, KILLED_EXIT_CODE, Pid, Process, State},
+    thread::{self, ThreadId},
+    user::{self, programs},
+    user_program,
+};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+// Loop forever without making syscalls.
+user_program!(forever: "1:", "jmp 1b");
+// Wait for a key, which the tests never press.
+user_program!(
+    read_key:
+    "sub $8, %rsp",
+    "mov $1, %eax",
+    "xor %edi, %edi",
+    "mov %rsp, %rsi",
+    "mov $8, %edx",
+    "syscall",
+    "mov %rax, %rdi",
+    "mov $2, %eax",
+    "syscall",
+);
+
+/// Run the sleep program with `args` on a new thread.
+fn sleep(args: &[&str]) -> thread::JoinHandle<Result<i64, user::elf::ElfError>> {
+    let elf = programs::get("sleep").unwrap();
+    let argv = ["sleep"]
+        .iter()
+        .chain(args)
+        .map(|&arg| arg.into())
+        .collect();
+    user::spawn_exec(elf, argv, Vec::new())
+}
+
+/// Wait for the process run by `thread` to start.
+fn process_of(thread: ThreadId) -> Arc<Process> {
+    loop {
+        let process = process::list()
+            .into_iter()
+            .find(|process| process.thread().id() == thread);
+        match process {
+            Some(process) => return process,
+            None => thread::sleep(1),
+        }
+    }
+}
+
+#[test_case]
+fn running_processes_are_listed() {
+    let handle = sleep(&["1"]);
+    let process = process_of(handle.thread().id());
+    assert_eq!(process.name(), "sleep");
+    assert_eq!(process.state(), State::Running);
+    assert_eq!(process.parent(), None);
+    assert_eq!(handle.join(), Ok(0));
+    assert!(process::get(process.pid()).is_none());
+}
+
+#[test_case]
+fn killed_processes_exit_after_a_syscall() {
+    let handle = sleep(&[]);
+    let process = process_of(handle.thread().id());
+    assert!(process::kill(process.pid()));
+    assert_eq!(handle.join(), Ok(KILLED_EXIT_CODE));
+    assert!(!process::kill(process.pid()));
+}
+
+#[test_case]
+fn killed_processes_stop_sleeping() {
+    let handle = sleep(&[]);
+    let process = process_of(handle.thread().id());
+    // Let it block in the sleep syscall.
+    thread::sleep(10);
+    let start = Instant::now();
+    assert!(process::kill(process.pid()));
+    assert_eq!(handle.join(), Ok(KILLED_EXIT_CODE));
+    // The program sleeps a second at a time.
+    assert!(start.elapsed_ms() < 500);
+}
+
+#[test_case]
+fn killed_processes_stop_reading() {
+    let handle = user::spawn(read_key());
+    let process = process_of(handle.thread().id());
+    thread::sleep(10);
+    assert!(process::kill(process.pid()));
+    assert_eq!(handle.join(), Ok(KILLED_EXIT_CODE));
+}
+
+#[test_case]
+fn killed_processes_exit_without_syscalls() {
+    let handle = user::spawn(forever());
+    let process = process_of(handle.thread().id());
+    assert!(process::kill(process.pid()));
+    assert_eq!(handle.join(), Ok(KILLED_EXIT_CODE));
+}
+
+#[test_case]
+fn unknown_processes_cant_be_killed() {
+    assert!(!process::kill(Pid::from_u64(u64::MAX).unwrap()));
+}
+
+#[test_case]
+fn programs_at_the_same_address_run_concurrently() {
+    let elf = programs::get("test").unwrap();
+    let handles: Vec<_> = (0..4)
+        .map(|_| user::spawn_exec(elf, ["test", "d"].map(Into::into).to_vec(), Vec::new()))
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join(), Ok(44));
+    }
+}
+
+#[test_case]
+fn exited_processes_free_their_memory() {
+    let elf = programs::get("test").unwrap();
+    // The first run may grow the heap.
+    assert_eq!(user::exec(elf, &["test", "d"], &[]), Ok(44));
+    let used = VMM.lock().frame_stats().used_frames;
+    for _ in 0..8 {
+        assert_eq!(user::exec(elf, &["test", "d"], &[]), Ok(44));
+    }
+    assert_eq!(VMM.lock().frame_stats().used_frames, used);
+}
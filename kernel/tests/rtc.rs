@@ -0,0 +1,65 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use core::{
+    sync::atomic::{AtomicU64, Ordering::*},
+    time::Duration,
+};
+use kernel::clock::{DateTime, Instant, WallClock, rtc};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[test_case]
+fn boot_time_is_plausible() {
+    let now = WallClock::now().unwrap();
+    assert!(now.year >= 2024, "{now}");
+    assert!((1..=12).contains(&now.month), "{now}");
+    assert!((1..=31).contains(&now.day), "{now}");
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60, "{now}");
+}
+
+#[test_case]
+fn wall_clock_matches_the_rtc() {
+    let rtc = rtc::read_datetime().to_unix();
+    let wall = WallClock::unix_time().unwrap();
+    assert!(rtc.abs_diff(wall) <= Duration::from_secs(2));
+}
+
+#[test_case]
+fn unix_time_round_trips() {
+    let date = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 37,
+        second: 42,
+        nanosecond: 5,
+    };
+    assert_eq!(date.to_unix(), Duration::new(1_709_213_862, 5));
+    assert_eq!(DateTime::from_unix(date.to_unix()), date);
+    assert_eq!(DateTime::from_unix(Duration::ZERO).year, 1970);
+}
+
+static PERIODIC: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn periodic_interrupt_fires() {
+    // 1024 Hz
+    rtc::set_periodic(6, Some(|| _ = PERIODIC.fetch_add(1, Relaxed)));
+    let start = Instant::now();
+    while start.elapsed_ms() < 50 {
+        x86_64::instructions::hlt();
+    }
+    rtc::set_periodic(6, None);
+    assert!(PERIODIC.load(Relaxed) >= 10);
+}
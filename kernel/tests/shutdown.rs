@@ -0,0 +1,19 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use kernel::prelude::*;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("shutdown::shutdown...\t");
+
+    kernel::init(boot_info);
+    kernel::enable_test();
+
+    // Exits QEMU with the success code in test mode
+    serial_println!("[ok]");
+    kernel::power::shutdown()
+}
@@ -0,0 +1,156 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+extern crate alloc;
+use bootloader::{BootInfo, entry_point};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering::*};
+use kernel::{
+    clock::Instant,
+    memory::vmm::VMM,
+    smp,
+    task::{Task, executor::spawn_shared, timer::sleep},
+};
+use spinlock::{DisableInterrupts, SpinLock};
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+/// Wait for `done` to reach `count`, failing after a second.
+fn wait_for(done: &AtomicUsize, count: usize) {
+    let start = Instant::now();
+    while done.load(Acquire) < count {
+        assert!(start.elapsed_ms() < 1000, "Timed out waiting for tasks");
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn application_processors_are_started() {
+    // The tests are run with `-smp 4`.
+    assert_eq!(smp::cpu_count(), 4);
+    let cpus = smp::cpus();
+    for (index, cpu) in cpus.iter().enumerate() {
+        assert_eq!(cpu.index(), index);
+    }
+    let current = smp::current().unwrap();
+    assert_eq!(current.index(), 0);
+    assert!(current.is_current());
+    assert!(!cpus[1].is_current());
+}
+
+#[test_case]
+fn tasks_run_on_every_core() {
+    static CORES: AtomicU64 = AtomicU64::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    const TASKS: usize = 12;
+    for _ in 0..TASKS {
+        spawn_shared(Task::new(async {
+            let index = smp::current().unwrap().index();
+            CORES.fetch_or(1 << index, Relaxed);
+            // Keep the core busy, so that the other tasks are picked up by other cores.
+            let start = Instant::now();
+            while start.elapsed_ms() < 20 {
+                core::hint::spin_loop();
+            }
+            DONE.fetch_add(1, Release);
+        }));
+    }
+    wait_for(&DONE, TASKS);
+    // Executor threads migrate between cores, so which cores ran the tasks is not guaranteed, but
+    // the BSP is busy running the test, so at least one AP must have.
+    assert_ne!(CORES.load(Relaxed) & !1, 0);
+}
+
+#[test_case]
+fn tasks_are_woken_across_cores() {
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    for _ in 0..6 {
+        spawn_shared(Task::new(async {
+            // Timer wakers are fired by the timer interrupt, which may be handled by another
+            // core.
+            sleep(5).await;
+            DONE.fetch_add(1, Release);
+        }));
+    }
+    wait_for(&DONE, 6);
+}
+
+#[test_case]
+fn spinlock_contention() {
+    static COUNTER: SpinLock<u64, DisableInterrupts> = SpinLock::disable_interrupts(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    const INCREMENTS: u64 = 100_000;
+    for _ in 0..3 {
+        spawn_shared(Task::new(async {
+            for _ in 0..INCREMENTS {
+                *COUNTER.lock() += 1;
+            }
+            DONE.fetch_add(1, Release);
+        }));
+    }
+    for _ in 0..INCREMENTS {
+        *COUNTER.lock() += 1;
+    }
+    wait_for(&DONE, 3);
+    assert_eq!(*COUNTER.lock(), 4 * INCREMENTS);
+}
+
+#[test_case]
+fn heap_allocation_across_cores() {
+    use alloc::vec::Vec;
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    for _ in 0..3 {
+        spawn_shared(Task::new(async {
+            let mut vecs = Vec::new();
+            for i in 0..1000usize {
+                vecs.push(alloc::vec![i; i % 64]);
+            }
+            for (i, vec) in vecs.iter().enumerate() {
+                assert!(vec.iter().all(|&value| value == i));
+            }
+            DONE.fetch_add(1, Release);
+        }));
+    }
+    wait_for(&DONE, 3);
+}
+
+#[test_case]
+fn unmapped_frames_are_freed_after_tlb_shootdown() {
+    let mut vmm = VMM.lock();
+    let before = vmm.frame_stats();
+    let range = vmm
+        .alloc_mapped(4 * 4096, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        .unwrap();
+    vmm.free_mapped(range);
+    // Retired frames are only reclaimed by the VMM, which is still locked.
+    assert!(vmm.frame_stats().retired_frames >= 4);
+    drop(vmm);
+    smp::wait_for_tlbs(smp::next_tlb_generation() - 1);
+    let mut vmm = VMM.lock();
+    // Every retired frame is in one of two batches.
+    vmm.frames().reclaim();
+    vmm.frames().reclaim();
+    assert!(vmm.frame_stats().free_frames >= before.free_frames);
+}
+
+#[test_case]
+fn protect_waits_for_tlb_shootdown() {
+    let mut vmm = VMM.lock();
+    let range = vmm
+        .alloc_mapped(4096, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        .unwrap();
+    vmm.protect(range, PageTableFlags::PRESENT).unwrap();
+    let generation = smp::next_tlb_generation() - 1;
+    drop(vmm);
+    // No CPU may still write through a writable mapping once the lock is released.
+    assert!(smp::tlbs_flushed(generation));
+    VMM.lock().free_mapped(range);
+}
@@ -4,12 +4,17 @@
 #![reexport_test_harness_main = "test_main"]
 #![no_main]
 use bootloader::{BootInfo, entry_point};
+use core::sync::atomic::{AtomicBool, Ordering::*};
 use kernel::{
+    memory::stack::KernelStack,
     prelude::*,
     qemu::{QemuExitCode, exit_qemu},
 };
 use spinlock::LazyStatic;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
 
 entry_point!(main);
 fn main(boot_info: &'static BootInfo) -> ! {
@@ -18,6 +23,7 @@ fn main(boot_info: &'static BootInfo) -> ! {
     kernel::init(boot_info);
     kernel::enable_test();
     init_test_idt();
+    _ = RUNTIME_STACK.insert_if_uninit(KernelStack::new(4).unwrap());
 
     // trigger a stack overflow
     stack_overflow();
@@ -25,6 +31,11 @@ fn main(boot_info: &'static BootInfo) -> ! {
     panic!("Execution continued after stack overflow");
 }
 
+/// A stack allocated at runtime, overflowed once the boot stack was.
+static RUNTIME_STACK: LazyStatic<KernelStack> =
+    LazyStatic::new(|| panic!("The runtime stack is allocated in main"));
+static BOOT_STACK_OVERFLOWED: AtomicBool = AtomicBool::new(false);
+
 static TEST_IDT: LazyStatic<InterruptDescriptorTable> = LazyStatic::new(|| {
     let mut idt = InterruptDescriptorTable::new();
     unsafe {
@@ -43,12 +54,38 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    if !BOOT_STACK_OVERFLOWED.swap(true, Relaxed) {
+        serial_println!("[ok]");
+        serial_print!("stack_overflow::runtime_stack_overflow...\t");
+        overflow_runtime_stack();
+    }
+    let guard = RUNTIME_STACK.force().guard_page();
+    let addr = Cr2::read().unwrap();
+    assert!(
+        guard.contains(addr),
+        "Faulted at {addr:?}, outside of the guard page {guard:?}"
+    );
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
 }
 
+/// Switch to the runtime-allocated stack and overflow it. The double fault it causes runs on a
+/// fresh IST stack, so this can be called from the double fault handler.
+fn overflow_runtime_stack() -> ! {
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "call {overflow}",
+            "ud2",
+            top = in(reg) RUNTIME_STACK.force().top().as_u64(),
+            overflow = sym stack_overflow,
+            options(noreturn)
+        );
+    }
+}
+
 #[allow(unconditional_recursion)]
-fn stack_overflow() {
+extern "C" fn stack_overflow() {
     stack_overflow();
     let mut val = 0;
     let mut val = volatile::VolatileRef::from_mut_ref(&mut val);
@@ -0,0 +1,139 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+extern crate alloc;
+use alloc::vec::Vec;
+use bootloader::{BootInfo, entry_point};
+use core::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering::*},
+    time::Duration,
+};
+use kernel::{
+    clock::Instant,
+    smp,
+    task::{Task, executor::Executor, timer},
+    thread,
+};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+#[test_case]
+fn join_returns_the_result() {
+    let handle = thread::spawn(|| (1..=10u64).product::<u64>());
+    assert_eq!(handle.join(), 3628800);
+}
+
+#[test_case]
+fn many_threads() {
+    let handles: Vec<_> = (0..32usize).map(|i| thread::spawn(move || i * 2)).collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join(), i * 2);
+    }
+}
+
+#[test_case]
+fn spinning_threads_are_preempted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static SPINS: [AtomicUsize; 8] = [const { AtomicUsize::new(0) }; 8];
+    // More threads that never yield than there are CPUs, so they have to be preempted.
+    let handles: Vec<_> = (0..SPINS.len())
+        .map(|i| {
+            thread::spawn(move || {
+                while !STOP.load(Relaxed) {
+                    SPINS[i].fetch_add(1, Relaxed);
+                    core::hint::spin_loop();
+                }
+            })
+        })
+        .collect();
+    assert!(SPINS.len() > smp::cpu_count());
+    thread::sleep(200);
+    STOP.store(true, Relaxed);
+    for handle in handles {
+        handle.join();
+    }
+    for spins in &SPINS {
+        assert!(spins.load(Relaxed) > 0);
+    }
+}
+
+#[test_case]
+fn yield_now_lets_others_run() {
+    static TURNS: AtomicUsize = AtomicUsize::new(0);
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                for _ in 0..100 {
+                    TURNS.fetch_add(1, Relaxed);
+                    thread::yield_now();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(TURNS.load(Relaxed), 400);
+}
+
+#[test_case]
+fn sleep_blocks_for_the_duration() {
+    let start = Instant::now();
+    thread::sleep(30);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(29), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(200), "{elapsed:?}");
+}
+
+#[test_case]
+fn park_and_unpark() {
+    static READY: AtomicBool = AtomicBool::new(false);
+    let main = thread::current();
+    let handle = thread::spawn(move || {
+        thread::sleep(10);
+        READY.store(true, Release);
+        main.unpark();
+    });
+    while !READY.load(Acquire) {
+        thread::park();
+    }
+    handle.join();
+    // An unpark before parking makes the next park return immediately.
+    thread::current().unpark();
+    thread::park();
+}
+
+#[test_case]
+fn executor_runs_alongside_threads() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let spinners: Vec<_> = (0..smp::cpu_count() * 2)
+        .map(|_| {
+            thread::spawn(|| {
+                while !STOP.load(Relaxed) {
+                    core::hint::spin_loop();
+                }
+            })
+        })
+        .collect();
+    let start = Instant::now();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        for _ in 0..5 {
+            timer::sleep(5).await;
+        }
+    }));
+    executor.run();
+    assert!(start.elapsed_ms() < 500);
+    STOP.store(true, Relaxed);
+    for spinner in spinners {
+        spinner.join();
+    }
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+extern crate alloc;
+use bootloader::{BootInfo, entry_point};
+use core::time::Duration;
+use kernel::{
+    clock::{self, Instant, tickless},
+    interrupts::{self, TIMER_IRQ},
+    task::{
+        Task,
+        executor::Executor,
+        timer::{Interval, sleep},
+    },
+};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+fn run(future: impl Future<Output = ()> + Send + 'static) {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(future));
+    executor.run();
+}
+
+#[test_case]
+fn tickless_mode_is_enabled() {
+    assert!(tickless::is_enabled());
+}
+
+#[test_case]
+fn pit_stays_silenced_when_its_line_is_unmasked() {
+    let stats = || interrupts::irq_stats().nth(TIMER_IRQ as usize).unwrap();
+    assert!(stats().silenced);
+    interrupts::mask_irq(TIMER_IRQ);
+    interrupts::unmask_irq(TIMER_IRQ);
+    let (start, fired) = (Instant::now(), tickless::fired());
+    let count = stats().count;
+    while start.elapsed_ms() < 20 {
+        core::hint::spin_loop();
+    }
+    // Only one-shot timer interrupts arrive on the line, not the PIT's periodic ones.
+    assert!(stats().count - count <= tickless::fired() - fired);
+}
+
+#[test_case]
+fn millisecond_clock_keeps_advancing() {
+    let (start, start_ms) = (Instant::now(), clock::now_ms());
+    while start.elapsed_ms() < 20 {
+        core::hint::spin_loop();
+    }
+    assert!(clock::now_ms() - start_ms >= 19);
+}
+
+#[test_case]
+fn sleep_wakes_up_once() {
+    let start = Instant::now();
+    let fired = tickless::fired();
+    run(async { sleep(50).await });
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(49), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(80), "{elapsed:?}");
+    // A periodic tick would have fired 50 times.
+    assert!(tickless::fired() - fired < 10);
+}
+
+#[test_case]
+fn interval_keeps_its_period() {
+    let start = Instant::now();
+    run(async {
+        let mut interval = Interval::new(10);
+        interval.tick().await;
+        for _ in 0..5 {
+            interval.tick().await;
+        }
+    });
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(49), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(80), "{elapsed:?}");
+}
@@ -0,0 +1,146 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+extern crate alloc;
+use alloc::vec::Vec;
+use bootloader::{BootInfo, entry_point};
+use kernel::{
+    clock::Instant,
+    smp,
+    user::{self, syscall::SyscallError},
+    user_program,
+};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+// Exit with the result of getpid.
+user_program!(getpid: "mov $4, %eax", "syscall", "mov %rax, %rdi", "mov $2, %eax", "syscall");
+
+// Exit with the result of writing from a kernel address.
+user_program!(
+    write_kernel_memory:
+    "mov $0, %eax",
+    "mov $1, %edi",
+    "mov $0x200000, %rsi",
+    "mov $4, %edx",
+    "syscall",
+    "mov %rax, %rdi",
+    "mov $2, %eax",
+    "syscall",
+);
+
+// Exit with the result of an unknown syscall.
+user_program!(
+    unknown_syscall:
+    "mov $99, %eax",
+    "syscall",
+    "mov %rax, %rdi",
+    "mov $2, %eax",
+    "syscall",
+);
+
+// Map two pages, store to both and exit with the sum of the values read back.
+user_program!(
+    mmap:
+    "mov $5, %eax",
+    "xor %edi, %edi",
+    "mov $8192, %esi",
+    "mov $3, %edx",
+    "syscall",
+    "test %rax, %rax",
+    "js 1f",
+    "movq $20, (%rax)",
+    "movq $22, 4096(%rax)",
+    "mov (%rax), %rdi",
+    "add 4096(%rax), %rdi",
+    "mov $2, %eax",
+    "syscall",
+    "1:",
+    "mov %rax, %rdi",
+    "mov $2, %eax",
+    "syscall",
+);
+
+// Sleep for 30ms, then exit with 0.
+user_program!(
+    sleep:
+    "mov $3, %eax",
+    "mov $30, %edi",
+    "syscall",
+    "mov $2, %eax",
+    "xor %edi, %edi",
+    "syscall",
+);
+
+// Count down from 20 million without making syscalls, then exit with 7.
+user_program!(
+    spin:
+    "mov $20000000, %ecx",
+    "1:",
+    "dec %rcx",
+    "jnz 1b",
+    "mov $2, %eax",
+    "mov $7, %edi",
+    "syscall",
+);
+
+#[test_case]
+fn demo_runs_in_ring_3() {
+    assert_eq!(user::run(user::demo()), Ok(3));
+}
+
+#[test_case]
+fn demo_runs_on_a_thread() {
+    assert_eq!(user::spawn(user::demo()).join(), Ok(3));
+}
+
+#[test_case]
+fn getpid_returns_a_new_process_id() {
+    let first = user::run(getpid()).unwrap();
+    let second = user::spawn(getpid()).join().unwrap();
+    assert!(first > 0);
+    assert!(second > first);
+}
+
+#[test_case]
+fn kernel_memory_is_rejected() {
+    let code = SyscallError::BadAddress as i64;
+    assert_eq!(user::run(write_kernel_memory()), Ok(code));
+}
+
+#[test_case]
+fn unknown_syscalls_fail() {
+    let code = SyscallError::NoSuchSyscall as i64;
+    assert_eq!(user::run(unknown_syscall()), Ok(code));
+}
+
+#[test_case]
+fn mmap_maps_writable_memory() {
+    assert_eq!(user::run(mmap()), Ok(42));
+}
+
+#[test_case]
+fn sleep_blocks_the_program() {
+    let start = Instant::now();
+    assert_eq!(user::run(sleep()), Ok(0));
+    assert!(start.elapsed_ms() >= 29);
+}
+
+#[test_case]
+fn user_programs_are_preempted() {
+    // More programs than CPUs, which never enter the kernel until they exit.
+    let handles: Vec<_> = (0..smp::cpu_count() * 2)
+        .map(|_| user::spawn(spin()))
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join(), Ok(7));
+    }
+}
@@ -0,0 +1,133 @@
+#![no_std]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![no_main]
+use bootloader::{BootInfo, entry_point};
+use kernel::memory::{phys_to_virt, vmm::VMM};
+use x86_64::{PhysAddr, structures::paging::PageTableFlags};
+
+entry_point!(main);
+fn main(boot_info: &'static BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::enable_test();
+    test_main();
+    unreachable!()
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+#[test_case]
+fn map_and_unmap_returns_frames() {
+    let mut vmm = VMM.lock();
+    let before = vmm.frame_stats();
+    let range = vmm.alloc_mapped(4 * 4096, FLAGS).unwrap();
+    assert!(vmm.frame_stats().used_frames >= before.used_frames + 4);
+    let ptr: *mut u64 = range.start.as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    let phys = vmm.translate(range.start).unwrap();
+    assert_eq!(
+        unsafe { phys_to_virt(phys).as_ptr::<u64>().read_volatile() },
+        0xdead_beef
+    );
+    vmm.free_mapped(range);
+    assert!(vmm.translate(range.start).is_none());
+    assert!(vmm.frame_stats().used_frames < before.used_frames + 4);
+}
+
+#[test_case]
+fn protect_changes_flags() {
+    let mut vmm = VMM.lock();
+    let range = vmm.alloc_mapped(4096, FLAGS).unwrap();
+    vmm.protect(range, PageTableFlags::PRESENT).unwrap();
+    let flags = vmm.flags(range.start).unwrap();
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    vmm.free_mapped(range);
+}
+
+#[test_case]
+fn mmio_mapping_aliases_physical_memory() {
+    let mut vmm = VMM.lock();
+    let before = vmm.frame_stats();
+    let vga = PhysAddr::new(0xb8000);
+    let virt = unsafe { vmm.map_mmio(vga, 4000).unwrap() };
+    assert_eq!(vmm.translate(virt), Some(vga));
+    let mapped = unsafe { virt.as_ptr::<u16>().read_volatile() };
+    let direct = unsafe { phys_to_virt(vga).as_ptr::<u16>().read_volatile() };
+    assert_eq!(mapped, direct);
+    vmm.unmap_mmio(virt, 4000);
+    // The VGA buffer is not owned by the mapping, so it must not be handed to the frame allocator
+    assert!(vmm.frame_stats().free_frames <= before.free_frames);
+}
+
+#[test_case]
+fn unaligned_mmio_mappings_are_released() {
+    let mut vmm = VMM.lock();
+    let reg = PhysAddr::new(0xb8010);
+    let first = unsafe { vmm.map_mmio(reg, 4) }.unwrap();
+    assert_eq!(vmm.translate(first), Some(reg));
+    vmm.unmap_mmio(first, 4);
+    assert_eq!(vmm.translate(first), None);
+    // The released range is handed out again
+    let second = unsafe { vmm.map_mmio(reg, 4) }.unwrap();
+    assert_eq!(second, first);
+    vmm.unmap_mmio(second, 4);
+}
+
+#[test_case]
+fn large_mappings_use_huge_pages() {
+    let mut vmm = VMM.lock();
+    let range = vmm.alloc_mapped(4 * 1024 * 1024, FLAGS).unwrap();
+    let used = vmm.frame_stats().used_frames;
+    assert!(range.start.is_aligned(2 * 1024 * 1024u64));
+    let flags = vmm.flags(range.start).unwrap();
+    assert!(flags.contains(PageTableFlags::HUGE_PAGE));
+    let phys = vmm.translate(range.start).unwrap();
+    assert_eq!(
+        vmm.translate(range.start + 0x1234u64),
+        Some(phys + 0x1234u64)
+    );
+    vmm.free_mapped(range);
+    assert!(vmm.translate(range.start).is_none());
+    assert_eq!(vmm.frame_stats().used_frames, used - 1024);
+}
+
+#[test_case]
+fn partial_unmap_splits_huge_pages() {
+    let mut vmm = VMM.lock();
+    let range = vmm.alloc_mapped(2 * 1024 * 1024, FLAGS).unwrap();
+    assert!(
+        vmm.flags(range.start)
+            .unwrap()
+            .contains(PageTableFlags::HUGE_PAGE)
+    );
+    let second = range.start + 4096u64;
+    let phys = vmm.translate(second).unwrap();
+    let used = vmm.frame_stats().used_frames;
+
+    vmm.unmap_range(kernel::memory::vmm::VirtRange::new(range.start, 4096));
+    assert!(vmm.translate(range.start).is_none());
+    assert_eq!(vmm.translate(second), Some(phys));
+    assert!(
+        !vmm.flags(second)
+            .unwrap()
+            .contains(PageTableFlags::HUGE_PAGE)
+    );
+    // One frame is released, and one is used for the new page table.
+    assert_eq!(vmm.frame_stats().used_frames, used);
+
+    vmm.protect(
+        kernel::memory::vmm::VirtRange::new(second, 4096),
+        PageTableFlags::PRESENT,
+    )
+    .unwrap();
+    assert!(
+        !vmm.flags(second)
+            .unwrap()
+            .contains(PageTableFlags::WRITABLE)
+    );
+    vmm.free_mapped(range);
+}